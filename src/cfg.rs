@@ -10,10 +10,16 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
-#[derive(Debug, Default)]
+use std::fmt::Display;
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Standard {
+    /// The language as published for Windows 3
+    WIN3_0,
+    /// The language together with the extensions of this interpreter
     #[default]
-    WIN3,
+    Extended,
 }
 
 impl TryFrom<&str> for Standard {
@@ -21,14 +27,123 @@ impl TryFrom<&str> for Standard {
 
     fn try_from(value: &str) -> Result<Self, Self::Error> {
         match value {
-            "win3" => Ok(Self::WIN3),
+            "win3.0" | "win3" => Ok(Self::WIN3_0),
+            "extended" => Ok(Self::Extended),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Debug, Default)]
+impl Display for Standard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::WIN3_0 => write!(f, "win3.0"),
+            Self::Extended => write!(f, "extended"),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Gtk,
+    Terminal,
+}
+
+impl TryFrom<&str> for Backend {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "gtk" => Ok(Self::Gtk),
+            "terminal" => Ok(Self::Terminal),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct Config {
+    /// Enforce the limits of Windows 3, and reject programs relying on undefined behavior
     pub pedantic: bool,
+    /// Version of the language that programs must keep to
     pub standard: Standard,
+    /// Most pending keyboard, mouse, and menu events kept each, before the oldest are dropped
+    pub queue_capacity: usize,
+    /// Preserve the aspect ratio of bitmaps drawn with `DrawSizedBitmap`
+    pub keep_aspect: bool,
+    /// File of events fed to the program instead of live input
+    pub replay: Option<String>,
+    /// Print every variable once the program ends
+    pub dump_vars: bool,
+    /// Pass the command given to `Run` to `sh -c`, instead of executing it directly
+    pub run_shell: bool,
+    /// Ask before running each command given to `Run`
+    pub confirm_run: bool,
+    /// Font used for text until the program calls `UseFont`
+    pub font_name: String,
+    /// Size of the initial font, and of fonts chosen with a height of 0
+    pub font_size: f64,
+    /// GIF file the window is recorded into, written once the program ends
+    pub record: Option<String>,
+    /// Most frames per second captured while recording
+    pub record_fps: Option<u16>,
+    /// Where the program is shown
+    pub backend: Backend,
+    /// Initial background color, until the program calls `UseBackground`
+    pub background_rgb: (u8, u8, u8),
+    /// Interpret coordinates as signed 16-bit integers
+    pub signed_coords: bool,
+    /// Largest width and height of the canvas, in pixels
+    pub max_canvas_size: i32,
+    /// Allow whitespace before labels starting a line
+    pub indented_labels: bool,
+    /// Replace `{name}` placeholders in `DrawText` and `MessageBox` with variable values
+    pub interpolate: bool,
+    /// Advance the time seen by the program only while it waits, without sleeping
+    pub fake_clock: bool,
+    /// Draw a placeholder in place of bitmaps that fail to load, instead of ending the program
+    pub image_placeholder: bool,
+    /// Directory searched for bitmaps by their filename, before the bundled Windows 3 bitmaps
+    pub resource_dir: Option<String>,
+    /// Most times per second that a timed `WaitInput` may return
+    pub max_fps: Option<u16>,
+    /// PNG file the canvas is saved to once the program ends
+    pub snapshot: Option<String>,
+    /// File each executed command is written to, as a line of JSON
+    pub trace_json: Option<String>,
+    /// Print how often each command was executed once the program ends
+    pub profile: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            pedantic: false,
+            standard: Standard::default(),
+            queue_capacity: 256,
+            keep_aspect: false,
+            replay: None,
+            dump_vars: false,
+            run_shell: false,
+            confirm_run: false,
+            font_name: "Sans".to_string(),
+            font_size: 18.,
+            record: None,
+            record_fps: None,
+            backend: Backend::default(),
+            background_rgb: (255, 255, 255),
+            signed_coords: false,
+            max_canvas_size: 8192,
+            indented_labels: false,
+            interpolate: false,
+            fake_clock: false,
+            image_placeholder: false,
+            resource_dir: None,
+            max_fps: None,
+            snapshot: None,
+            trace_json: None,
+            profile: false,
+        }
+    }
 }
@@ -0,0 +1,101 @@
+// Copyright (C) 2023  Wojciech Graj
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+use std::thread;
+use std::time;
+
+/// Source of the time seen by a program, measured from when the clock was created
+pub trait Clock {
+    fn elapsed(&self) -> time::Duration;
+    fn sleep(&mut self, duration: time::Duration);
+}
+
+/// Wall clock
+pub struct SystemClock {
+    start: time::Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: time::Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn elapsed(&self) -> time::Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Clock that only moves when advanced, so sleeping returns immediately
+#[derive(Default)]
+pub struct FakeClock {
+    elapsed: time::Duration,
+}
+
+impl FakeClock {
+    pub fn advance(&mut self, duration: time::Duration) {
+        self.elapsed += duration;
+    }
+}
+
+impl Clock for FakeClock {
+    fn elapsed(&self) -> time::Duration {
+        self.elapsed
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        self.advance(duration);
+    }
+}
+
+/// Milliseconds elapsed on `clock`, wrapping around every 65536
+pub fn ticks(clock: &dyn Clock) -> u16 {
+    (clock.elapsed().as_millis() % 65536) as u16
+}
+
+/// Timer ticking periodically on a clock
+pub struct Timer {
+    interval: time::Duration,
+    next_tick: time::Duration,
+}
+
+impl Timer {
+    pub fn new(interval: time::Duration, clock: &dyn Clock) -> Self {
+        Self {
+            interval,
+            next_tick: clock.elapsed() + interval,
+        }
+    }
+
+    /// Clock's time when the timer next ticks
+    pub fn next_tick(&self) -> time::Duration {
+        self.next_tick
+    }
+
+    /// Whether the timer is due, in which case the next tick is scheduled an interval from now
+    pub fn tick(&mut self, clock: &dyn Clock) -> bool {
+        let now = clock.elapsed();
+        if now >= self.next_tick {
+            self.next_tick = now + self.interval;
+            true
+        } else {
+            false
+        }
+    }
+}
@@ -0,0 +1,2010 @@
+use std::cell::Ref;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::f64::consts::PI;
+use std::f64::consts::TAU;
+
+use gtk::cairo;
+use gtk::gdk;
+use gtk::gdk::prelude::*;
+use gtk::gdk_pixbuf;
+use gtk::glib;
+use thiserror::Error;
+
+use crate::cfg;
+use crate::ir;
+use crate::resource;
+
+/// Width and height of the placeholder drawn by `DrawBitmap`, in the current coordinates
+const PLACEHOLDER_SIZE: f64 = 32.;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to create cairo surface")]
+    SurfaceCreateError,
+    #[error("Glib error: {}", .0)]
+    GlibError(#[from] glib::Error),
+    #[error("Failed to create Pixbuf from image")]
+    PixbufLoadError,
+    #[error("Malformed data URI, expected 'data:image/TYPE;base64,DATA'")]
+    DataUriError,
+    #[error("RestoreState without a matching SaveState")]
+    StateStackEmptyError,
+    #[error("Failed to allocate memory")]
+    AllocationError,
+    #[error("Canvas is empty")]
+    EmptyCanvasError,
+}
+
+pub mod cairo_util {
+    use gtk::cairo;
+
+    pub fn new_surface_rgb(
+        width: i32,
+        height: i32,
+        r: f64,
+        g: f64,
+        b: f64,
+    ) -> Result<(cairo::ImageSurface, cairo::Context), cairo::Error> {
+        let surface = cairo::ImageSurface::create(cairo::Format::Rgb24, width, height)?;
+        let cr = cairo::Context::new(&surface)?;
+        cr.set_source_rgb(r, g, b);
+        cr.paint()?;
+        Ok((surface, cr))
+    }
+
+    /// Reads the color of a pixel from the data of a `Rgb24` or `ARgb32` surface.
+    ///
+    /// Both formats store each pixel as a native-endian 32-bit integer, which on little-endian
+    /// machines gives the bytes B, G, R, A. `ARgb32` colors are premultiplied by alpha, so they are
+    /// divided by it here to allow comparing them with unpremultiplied colors.
+    pub fn pixel_bgr(format: cairo::Format, pixel: &[u8]) -> [u8; 3] {
+        let bgr = [pixel[0], pixel[1], pixel[2]];
+        match (format, pixel[3]) {
+            (cairo::Format::ARgb32, 0) => [0, 0, 0],
+            (cairo::Format::ARgb32, alpha) => bgr.map(|c| {
+                ((u16::from(c) * 255 + u16::from(alpha) / 2) / u16::from(alpha)).min(255) as u8
+            }),
+            _ => bgr,
+        }
+    }
+
+    pub fn draw_pattern_diagonal_up(cr: &cairo::Context) {
+        cr.move_to(0.5, 8.);
+        cr.line_to(8., 0.5);
+    }
+
+    pub fn draw_pattern_diagonal_down(cr: &cairo::Context) {
+        cr.move_to(0., 0.5);
+        cr.line_to(7.5, 8.);
+    }
+
+    pub fn draw_pattern_horizontal(cr: &cairo::Context) {
+        cr.move_to(0., 0.);
+        cr.line_to(8., 0.);
+    }
+
+    pub fn draw_pattern_vertical(cr: &cairo::Context) {
+        cr.move_to(0., 0.);
+        cr.line_to(0., 8.);
+    }
+}
+
+macro_rules! cairo_context_getter_and_invalidator {
+    ($var: ident, $member: ident, $var_inval:ident, $cr: expr) => {
+        pub fn $var(&self) -> Ref<cairo::Context> {
+            {
+                let borrowed = self.$member.borrow();
+                if let Some(_) = *borrowed {
+                    return Ref::map(borrowed, |cr| cr.as_ref().unwrap());
+                }
+            }
+            {
+                let mut borrowed = self.$member.borrow_mut();
+                let cr = cairo::Context::new(&self.surface).unwrap();
+                cr.set_antialias(cairo::Antialias::None);
+                *borrowed = Some(cr);
+            }
+            let borrowed = self.$member.borrow();
+            $cr(self, borrowed.as_ref().unwrap());
+            Ref::map(borrowed, |cr| cr.as_ref().unwrap())
+        }
+
+        pub fn $var_inval(&self) {
+            let mut borrowed = self.$member.borrow_mut();
+            *borrowed = None;
+        }
+    };
+}
+
+macro_rules! scale_vars {
+    ($draw_ctx:expr, ($($x:ident),*)) => {
+        $(
+            let $x = $draw_ctx.scaled($x);
+        )*
+    };
+}
+
+/// Settings saved by `SaveState`
+struct DrawState {
+    text_face: cairo::FontFace,
+    text_size: Option<f64>,
+    text_width: Option<f64>,
+    text_underline: ir::FontUnderline,
+    text_rgb: (f64, f64, f64),
+    pen_type: ir::PenType,
+    pen_width: f64,
+    pen_rgb: (f64, f64, f64),
+    pen_cap: ir::LineCap,
+    pen_join: ir::LineJoin,
+    background_transparency: ir::BackgroundTransparency,
+    background_rgb: (f64, f64, f64),
+    brush_type: ir::BrushType,
+    brush_rgb: (f64, f64, f64),
+    scale: f64,
+}
+
+pub struct DrawCtx {
+    pub surface: cairo::ImageSurface,
+    cr_text_: RefCell<Option<cairo::Context>>,
+    cr_pen_: RefCell<Option<cairo::Context>>,
+    cr_background_: RefCell<Option<cairo::Context>>,
+    cr_brush_: RefCell<Option<cairo::Context>>,
+
+    pub text_face: cairo::FontFace,
+    /// Font size set by a `UseFont` call with a nonzero height
+    pub text_size: Option<f64>,
+    pub text_width: Option<f64>,
+    pub text_underline: crate::ir::FontUnderline,
+    pub text_rgb: (f64, f64, f64),
+    /// Font size used until a `UseFont` call with a nonzero height
+    pub default_font_size: f64,
+    /// Fonts already warned about being substituted
+    substituted_fonts: HashSet<String>,
+
+    pub pen_type: ir::PenType,
+    pub pen_width: f64,
+    pub pen_rgb: (f64, f64, f64),
+    pub pen_cap: ir::LineCap,
+    pub pen_join: ir::LineJoin,
+
+    pub background_transparency: ir::BackgroundTransparency,
+    pub background_rgb: (f64, f64, f64),
+
+    pub brush_type: ir::BrushType,
+    pub brush_rgb: (f64, f64, f64),
+
+    pub scale: f64,
+    /// Whether coordinates are interpreted as signed
+    signed_coords: bool,
+    /// Largest width and height of the surface
+    max_size: i32,
+
+    /// Position at which `Print` draws its next line
+    pub cursor: (f64, f64),
+
+    /// Minimum number of characters `DrawNumber` draws, padding with `number_pad`
+    number_width: usize,
+    number_pad: char,
+    /// Whether `DrawNumber` draws numbers in hexadecimal
+    pub number_hex: bool,
+
+    /// Color drawn as transparent in bitmaps
+    transparent_rgb: Option<(u8, u8, u8)>,
+    /// Whether bitmaps failing to load are drawn as placeholders instead of failing
+    image_placeholder: bool,
+    /// Images that bitmaps are looked up in before the filesystem
+    resources: Box<dyn resource::ResourceProvider>,
+
+    /// Scratch space of `DrawFlood`, kept between calls to avoid reallocating it each time
+    flood_mask: RefCell<Option<cairo::ImageSurface>>,
+    flood_queue: RefCell<Vec<(usize, usize)>>,
+
+    saved_states: Vec<DrawState>,
+}
+
+impl DrawCtx {
+    pub fn new(
+        config: &cfg::Config,
+        resources: Box<dyn resource::ResourceProvider>,
+    ) -> Result<Self, cairo::Error> {
+        Ok(DrawCtx {
+            surface: cairo::ImageSurface::create(cairo::Format::ARgb32, 0, 0)?,
+            cr_text_: RefCell::new(None),
+            cr_pen_: RefCell::new(None),
+            cr_background_: RefCell::new(None),
+            cr_brush_: RefCell::new(None),
+
+            text_face: cairo::FontFace::toy_create(
+                &config.font_name,
+                cairo::FontSlant::Normal,
+                cairo::FontWeight::Normal,
+            )?,
+            text_size: None,
+            text_width: None,
+            text_underline: ir::FontUnderline::NoUnderline,
+            text_rgb: (0., 0., 0.),
+            default_font_size: config.font_size,
+            substituted_fonts: HashSet::new(),
+
+            pen_type: ir::PenType::Solid,
+            pen_width: 1.,
+            pen_rgb: (0., 0., 0.),
+            pen_cap: ir::LineCap::Butt,
+            pen_join: ir::LineJoin::Miter,
+
+            background_transparency: ir::BackgroundTransparency::Opaque,
+            background_rgb: (
+                f64::from(config.background_rgb.0) / 255.,
+                f64::from(config.background_rgb.1) / 255.,
+                f64::from(config.background_rgb.2) / 255.,
+            ),
+
+            brush_type: ir::BrushType::Null,
+            brush_rgb: (0., 0., 0.),
+
+            scale: 1.,
+            signed_coords: config.signed_coords,
+            max_size: config.max_canvas_size,
+
+            cursor: (0., 0.),
+
+            number_width: 0,
+            number_pad: ' ',
+            number_hex: false,
+
+            transparent_rgb: None,
+            image_placeholder: config.image_placeholder,
+            resources,
+
+            flood_mask: RefCell::new(None),
+            flood_queue: RefCell::new(Vec::new()),
+
+            saved_states: Vec::new(),
+        })
+    }
+
+    cairo_context_getter_and_invalidator!(
+        cr_text,
+        cr_text_,
+        cr_text_inval,
+        |draw_ctx: &DrawCtx, cr: &cairo::Context| {
+            let (r, g, b) = draw_ctx.text_rgb;
+            cr.set_font_face(&draw_ctx.text_face);
+            cr.set_source_rgb(r, g, b);
+            if let Some(size) = draw_ctx.text_size {
+                cr.set_font_size(size);
+            } else {
+                cr.set_font_size(draw_ctx.default_font_size);
+            }
+        }
+    );
+
+    cairo_context_getter_and_invalidator!(
+        cr_pen,
+        cr_pen_,
+        cr_pen_inval,
+        |draw_ctx: &DrawCtx, cr: &cairo::Context| {
+            let (r, g, b) = draw_ctx.pen_rgb;
+            let dashes: &[f64] = match draw_ctx.pen_type {
+                ir::PenType::Solid => &[],
+                ir::PenType::Null => &[0., 1.],
+                ir::PenType::Dash => &[24., 8.],
+                ir::PenType::Dot => &[4.],
+                ir::PenType::DashDot => &[12., 6., 3., 6.],
+                ir::PenType::DashDotDot => &[12., 3., 3., 3., 3., 3.],
+            };
+            // Dashes grow with the pen, so thick dashed lines keep their proportions
+            let dash_scale = draw_ctx.pen_width.max(1.);
+            let dashes: Vec<f64> = dashes.iter().map(|len| len * dash_scale).collect();
+            cr.set_dash(&dashes, 0.);
+            cr.set_line_width(draw_ctx.pen_width);
+            draw_ctx.set_line_style(cr);
+            cr.set_source_rgb(r, g, b);
+        }
+    );
+
+    cairo_context_getter_and_invalidator!(
+        cr_background,
+        cr_background_,
+        cr_background_inval,
+        |draw_ctx: &DrawCtx, cr: &cairo::Context| {
+            let (r, g, b) = draw_ctx.background_rgb;
+            cr.set_line_width(draw_ctx.pen_width);
+            draw_ctx.set_line_style(cr);
+            cr.set_source_rgb(r, g, b);
+        }
+    );
+
+    cairo_context_getter_and_invalidator!(
+        cr_brush,
+        cr_brush_,
+        cr_brush_inval,
+        |draw_ctx: &DrawCtx, cr: &cairo::Context| {
+            let (r, g, b) = draw_ctx.brush_rgb;
+            let pattern = cairo::SurfacePattern::create(match draw_ctx.brush_type {
+                ir::BrushType::Solid => cairo_util::new_surface_rgb(1, 1, r, g, b).unwrap().0,
+                ir::BrushType::DiagonalUp => draw_ctx
+                    .create_hatch_surface(|cr| {
+                        cairo_util::draw_pattern_diagonal_up(cr);
+                        cr.rectangle(0., 0., 0.5, 0.5);
+                    })
+                    .unwrap(),
+                ir::BrushType::DiagonalDown => draw_ctx
+                    .create_hatch_surface(|cr| {
+                        cairo_util::draw_pattern_diagonal_down(cr);
+                        cr.rectangle(8., 0., 0.5, 0.5);
+                    })
+                    .unwrap(),
+                ir::BrushType::DiagonalCross => draw_ctx
+                    .create_hatch_surface(|cr| {
+                        cairo_util::draw_pattern_diagonal_up(cr);
+                        cairo_util::draw_pattern_diagonal_down(cr);
+                    })
+                    .unwrap(),
+                ir::BrushType::Horizontal => draw_ctx
+                    .create_hatch_surface(cairo_util::draw_pattern_horizontal)
+                    .unwrap(),
+                ir::BrushType::Vertical => draw_ctx
+                    .create_hatch_surface(cairo_util::draw_pattern_vertical)
+                    .unwrap(),
+                ir::BrushType::Cross => draw_ctx
+                    .create_hatch_surface(|cr| {
+                        cairo_util::draw_pattern_horizontal(cr);
+                        cairo_util::draw_pattern_vertical(cr);
+                    })
+                    .unwrap(),
+                ir::BrushType::Null => {
+                    let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 1, 1).unwrap();
+                    let cr = cairo::Context::new(&surface).unwrap();
+                    cr.set_source_rgba(0., 0., 0., 0.);
+                    cr.paint().ok();
+                    surface
+                }
+            });
+            pattern.set_extend(cairo::Extend::Repeat);
+            cr.set_source(pattern).ok();
+        }
+    );
+
+    fn set_line_style(&self, cr: &cairo::Context) {
+        cr.set_line_cap(match self.pen_cap {
+            ir::LineCap::Butt => cairo::LineCap::Butt,
+            ir::LineCap::Round => cairo::LineCap::Round,
+            ir::LineCap::Square => cairo::LineCap::Square,
+        });
+        cr.set_line_join(match self.pen_join {
+            ir::LineJoin::Miter => cairo::LineJoin::Miter,
+            ir::LineJoin::Round => cairo::LineJoin::Round,
+            ir::LineJoin::Bevel => cairo::LineJoin::Bevel,
+        });
+    }
+
+    /// Creates an 8x8 hatch brush tile, drawing the hatch lines with `draw`.
+    /// The gaps between lines are `background_rgb` in opaque mode, and show the underlying
+    /// pixels in transparent mode.
+    fn create_hatch_surface(
+        &self,
+        draw: impl FnOnce(&cairo::Context),
+    ) -> Result<cairo::ImageSurface, cairo::Error> {
+        let surface = cairo::ImageSurface::create(cairo::Format::ARgb32, 8, 8)?;
+        let cr = cairo::Context::new(&surface)?;
+        cr.set_antialias(cairo::Antialias::None);
+        if let ir::BackgroundTransparency::Opaque = self.background_transparency {
+            let (r, g, b) = self.background_rgb;
+            cr.set_source_rgb(r, g, b);
+            cr.paint()?;
+        }
+        let (r, g, b) = self.brush_rgb;
+        cr.set_source_rgb(r, g, b);
+        draw(&cr);
+        cr.stroke()?;
+        drop(cr);
+        Ok(surface)
+    }
+
+    pub fn resize(&mut self, width: i32, height: i32) -> Result<(), cairo::Error> {
+        self.surface = {
+            let (surface, cr) = cairo_util::new_surface_rgb(
+                width.min(self.max_size),
+                height.min(self.max_size),
+                self.background_rgb.0,
+                self.background_rgb.1,
+                self.background_rgb.2,
+            )?;
+            cr.set_source_surface(&self.surface, 0., 0.)?;
+            cr.paint()?;
+            surface
+        };
+        *self.cr_text_.borrow_mut() = None;
+        *self.cr_pen_.borrow_mut() = None;
+        *self.cr_background_.borrow_mut() = None;
+        *self.cr_brush_.borrow_mut() = None;
+        Ok(())
+    }
+
+    pub fn set_scale(&mut self, scale: f64) {
+        if scale != self.scale {
+            self.scale = scale;
+            self.cr_pen_inval();
+            self.cr_background_inval();
+        }
+    }
+
+    pub fn scaled(&self, x: u16) -> f64 {
+        if self.signed_coords {
+            f64::from(x as i16) * self.scale
+        } else {
+            f64::from(x) * self.scale
+        }
+    }
+
+    /// Computes the font size at which the current font face has a height of `height`
+    pub fn font_size_for_height(&self, height: f64) -> Result<f64, cairo::Error> {
+        let mut options = cairo::FontOptions::new()?;
+        options.set_hint_metrics(cairo::HintMetrics::Off);
+        let unit_font = cairo::ScaledFont::new(
+            &self.text_face,
+            &cairo::Matrix::identity(),
+            &cairo::Matrix::identity(),
+            &options,
+        )?;
+        Ok(height / unit_font.extents().height())
+    }
+
+    pub fn measure_text(&self, text: &str) -> Result<f64, cairo::Error> {
+        Ok(if let Some(width) = self.text_width {
+            width * (text.chars().count() as f64)
+        } else {
+            self.cr_text().text_extents(text)?.width()
+        })
+    }
+
+    /// Draws text positioned as by `DrawText`
+    pub fn show_text(&self, x: f64, y: f64, text: &str) -> Result<(), cairo::Error> {
+        let font_extents = self.cr_text().font_extents()?;
+        let y = y + font_extents.height();
+
+        let width = self.measure_text(text)?;
+
+        if let ir::BackgroundTransparency::Opaque = self.background_transparency {
+            self.cr_background().rectangle(
+                x,
+                y - font_extents.ascent(),
+                width,
+                font_extents.height(),
+            );
+            self.cr_background().fill()?;
+        }
+
+        if let ir::FontUnderline::Underline = self.text_underline {
+            self.cr_text().move_to(x, y + font_extents.descent());
+            self.cr_text().rel_line_to(width, 0.);
+            self.cr_text().stroke()?;
+        }
+
+        if let Some(width) = self.text_width {
+            let mut x = x;
+            let orig_matrix = self.cr_text().font_matrix();
+            for c in text.chars() {
+                let s = c.to_string();
+                let c = s.as_str();
+                let text_width = self.cr_text().text_extents(c)?.width();
+                if text_width > 0. {
+                    let mut matrix = orig_matrix;
+                    matrix.set_xx(orig_matrix.xx() * width / text_width);
+                    self.cr_text().set_font_matrix(matrix);
+
+                    self.cr_text().move_to(x, y);
+                    self.cr_text().show_text(c)?;
+                }
+                x += width;
+                self.cr_text().set_font_matrix(orig_matrix);
+            }
+        } else {
+            self.cr_text().move_to(x, y);
+            self.cr_text().show_text(text)?;
+        }
+        Ok(())
+    }
+
+    /// Splits text into lines no wider than `width` at spaces. Words wider than `width` are kept
+    /// whole on their own line.
+    pub fn wrap_text(&self, text: &str, width: f64) -> Result<Vec<String>, cairo::Error> {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for word in text.split(' ') {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", line, word)
+            };
+            if line.is_empty() || self.measure_text(&candidate)? <= width {
+                line = candidate;
+            } else {
+                lines.push(line);
+                line = word.to_string();
+            }
+        }
+        lines.push(line);
+        Ok(lines)
+    }
+
+    pub fn line_exec(&self, brush: bool, op: impl Fn(Ref<cairo::Context>)) {
+        if brush {
+            match self.brush_type {
+                ir::BrushType::Solid
+                | ir::BrushType::DiagonalUp
+                | ir::BrushType::DiagonalDown
+                | ir::BrushType::DiagonalCross
+                | ir::BrushType::Horizontal
+                | ir::BrushType::Vertical
+                | ir::BrushType::Cross => {
+                    op(self.cr_brush());
+                }
+                ir::BrushType::Null => {}
+            }
+        }
+        if self.pen_type == ir::PenType::Null {
+            return;
+        }
+        match self.background_transparency {
+            ir::BackgroundTransparency::Opaque => {
+                op(self.cr_background());
+            }
+            ir::BackgroundTransparency::Transparent => {}
+        }
+        match self.pen_type {
+            ir::PenType::Solid
+            | ir::PenType::Dash
+            | ir::PenType::Dot
+            | ir::PenType::DashDot
+            | ir::PenType::DashDotDot => {
+                op(self.cr_pen());
+            }
+            ir::PenType::Null => {}
+        }
+    }
+
+    pub fn arc_path(
+        &self,
+        cx: f64,
+        cy: f64,
+        sclx: f64,
+        scly: f64,
+        theta1: f64,
+        theta2: f64,
+        mv: bool,
+        brush: bool,
+    ) {
+        const DTHETA: f64 = -0.1;
+        // A full turn, with a margin for rounding
+        const MAX_STEPS: usize = (TAU / -DTHETA) as usize + 2;
+
+        let startx = cx + sclx * theta1.cos();
+        let starty = cy + scly * theta1.sin();
+        let endx = cx + sclx * theta2.cos();
+        let endy = cy + scly * theta2.sin();
+
+        if mv {
+            self.line_exec(brush, |ctx| {
+                ctx.move_to(startx, starty);
+            });
+        }
+        let mut theta = if theta1 > theta2 {
+            theta1
+        } else {
+            theta1 + TAU
+        };
+        let mut steps = 0;
+        // A step landing just short of the end would add a sliver of a segment before it, which
+        // miter joins can turn into a spike
+        while theta - theta2 > -DTHETA * 1e-3 {
+            if steps == MAX_STEPS {
+                if cfg!(debug_assertions) {
+                    eprintln!(
+                        "Warning: Arc from {} to {} exceeded {} steps",
+                        theta1, theta2, MAX_STEPS
+                    );
+                }
+                break;
+            }
+            self.line_exec(brush, |ctx| {
+                ctx.line_to(cx + sclx * theta.cos(), cy + scly * theta.sin());
+            });
+            theta += DTHETA;
+            steps += 1;
+        }
+        self.line_exec(brush, |ctx| {
+            ctx.line_to(endx, endy);
+        });
+    }
+
+    pub fn arc_path_rect_bound(
+        &self,
+        x1: f64,
+        y1: f64,
+        x2: f64,
+        y2: f64,
+        x3: f64,
+        y3: f64,
+        x4: f64,
+        y4: f64,
+        brush: bool,
+    ) {
+        let sclx = (x2 - x1) / 2.;
+        let scly = (y2 - y1) / 2.;
+        let cx = (x2 + x1) / 2.;
+        let cy = (y2 + y1) / 2.;
+        // A zero-size rectangle degenerates the ellipse into a line or point, along which the
+        // angles are measured without scaling, as dividing by zero would make them NaN
+        let nonzero = |scl: f64| if scl == 0. { 1. } else { scl };
+        let theta1 = ((y3 - cy) / nonzero(scly)).atan2((x3 - cx) / nonzero(sclx));
+        let theta2 = ((y4 - cy) / nonzero(scly)).atan2((x4 - cx) / nonzero(sclx));
+        self.arc_path(cx, cy, sclx, scly, theta1, theta2, true, brush)
+    }
+
+    pub fn draw(&self) -> Result<(), cairo::Error> {
+        // The fill follows the outline's alignment, so that it stays within the outline
+        if self.pen_type == ir::PenType::Null {
+            self.cr_brush().fill()?;
+        } else {
+            self.aligned(&self.cr_brush(), cairo::Context::fill)?;
+        }
+        self.stroke()
+    }
+
+    pub fn stroke(&self) -> Result<(), cairo::Error> {
+        // A null pen draws no outline, so line_exec built no paths to stroke
+        if self.pen_type == ir::PenType::Null {
+            return Ok(());
+        }
+        self.aligned(&self.cr_background(), cairo::Context::stroke)?;
+        self.aligned(&self.cr_pen(), cairo::Context::stroke)
+    }
+
+    /// Applies `op` to the path of `cr`. Pixel coordinates lie on the boundaries between pixels,
+    /// so an odd pen width would be centered on one and blur across two rows, unless shifted to
+    /// the middle of a pixel.
+    fn aligned(
+        &self,
+        cr: &cairo::Context,
+        op: impl FnOnce(&cairo::Context) -> Result<(), cairo::Error>,
+    ) -> Result<(), cairo::Error> {
+        if self.scale != 1. || self.pen_width % 2. != 1. {
+            return op(cr);
+        }
+        let path = cr.copy_path()?;
+        cr.new_path();
+        cr.save()?;
+        cr.translate(0.5, 0.5);
+        cr.append_path(&path);
+        let result = op(cr);
+        cr.restore()?;
+        result
+    }
+
+    pub fn draw_arc(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2, x3, y3, x4, y4));
+
+        self.arc_path_rect_bound(x1, y1, x2, y2, x3, y3, x4, y4, false);
+        self.stroke()?;
+        Ok(())
+    }
+
+    pub fn draw_background(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.cr_background().paint()?;
+        self.cursor = (0., 0.);
+        Ok(())
+    }
+
+    pub fn draw_bitmap(
+        &self,
+        x: u16,
+        y: u16,
+        filename: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x, y));
+
+        let Some(pixbuf) = self.load_bitmap(filename, None)? else {
+            let size = PLACEHOLDER_SIZE * self.scale;
+            self.draw_placeholder(x, y, x + size, y + size)?;
+            return Ok(());
+        };
+        let pixbuf = self.color_key(pixbuf);
+
+        let surface = pixbuf
+            .create_surface(1, None::<&gdk::Window>)
+            .ok_or_else(|| Error::SurfaceCreateError)?;
+
+        let cr = cairo::Context::new(self.surface.as_ref())?;
+        cr.set_source_surface(&surface, x, y)?;
+        cr.paint()?;
+        Ok(())
+    }
+
+    pub fn draw_chord(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2, x3, y3, x4, y4));
+
+        // Closing the path, rather than drawing a line back to its start, joins the outline there
+        self.arc_path_rect_bound(x1, y1, x2, y2, x3, y3, x4, y4, true);
+        self.line_exec(true, |ctx| {
+            ctx.close_path();
+        });
+
+        self.draw()?;
+        Ok(())
+    }
+
+    pub fn draw_ellipse(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2));
+
+        self.arc_path(
+            (x2 + x1) / 2.,
+            (y2 + y1) / 2.,
+            (x2 - x1) / 2.,
+            (y2 - y1) / 2.,
+            TAU,
+            0.0,
+            true,
+            true,
+        );
+        self.line_exec(true, |ctx| {
+            ctx.close_path();
+        });
+        self.draw()?;
+        Ok(())
+    }
+
+    pub fn draw_flood(
+        &self,
+        x: u16,
+        y: u16,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x, y));
+
+        let tgt = [b as u8, g as u8, r as u8];
+
+        let width = self.surface.width() as usize;
+        let height = self.surface.height() as usize;
+        let format = self.surface.format();
+        if x < 0. || y < 0. || x as usize >= width || y as usize >= height {
+            return Ok(());
+        }
+
+        let mut flood_mask = self.flood_mask.borrow_mut();
+        let mask_surface = match &mut *flood_mask {
+            Some(mask_surface)
+                if mask_surface.width() == width as i32
+                    && mask_surface.height() == height as i32 =>
+            {
+                mask_surface
+            }
+            flood_mask => flood_mask.insert(
+                cairo::ImageSurface::create(cairo::Format::A8, width as i32, height as i32)
+                    .map_err(|_| Error::AllocationError)?,
+            ),
+        };
+        let mask_stride = mask_surface.stride() as usize;
+
+        {
+            let mut mask = mask_surface.data()?;
+            mask.fill(0);
+            let mut q = self.flood_queue.borrow_mut();
+            q.clear();
+            q.push((x as usize, y as usize));
+
+            // This is inefficient, but implementing a more efficient flood-fill is a hassle
+            self.surface.with_data(|data| {
+                while let Some((x, y)) = q.pop() {
+                    let i = x + y * width;
+                    let mask_i = x + y * mask_stride;
+                    if mask[mask_i] == 0
+                        && cairo_util::pixel_bgr(format, &data[(i * 4)..(i * 4 + 4)]) != tgt
+                    {
+                        mask[mask_i] = 255;
+                        if x > 0 {
+                            q.push((x - 1, y));
+                        }
+                        if x < width - 1 {
+                            q.push((x + 1, y));
+                        }
+                        if y > 0 {
+                            q.push((x, y - 1));
+                        }
+                        if y < height - 1 {
+                            q.push((x, y + 1));
+                        }
+                    }
+                }
+            })?;
+        }
+
+        self.cr_brush().mask_surface(&*mask_surface, 0., 0.)?;
+        Ok(())
+    }
+
+    pub fn draw_line(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2));
+
+        self.line_exec(false, |ctx| {
+            ctx.move_to(x1, y1);
+            ctx.line_to(x2, y2);
+        });
+        self.stroke()?;
+        Ok(())
+    }
+
+    pub fn draw_pie(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2, x3, y3, x4, y4));
+
+        self.arc_path_rect_bound(x1, y1, x2, y2, x3, y3, x4, y4, true);
+        self.line_exec(true, |ctx| {
+            ctx.line_to((x2 + x1) / 2., (y2 + y1) / 2.);
+            ctx.close_path();
+        });
+        self.draw()?;
+        Ok(())
+    }
+
+    pub fn draw_rectangle(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2));
+
+        self.line_exec(true, |ctx| {
+            ctx.move_to(x1, y1);
+            ctx.line_to(x2, y1);
+            ctx.line_to(x2, y2);
+            ctx.line_to(x1, y2);
+            ctx.line_to(x1, y1);
+        });
+        self.draw()?;
+        Ok(())
+    }
+
+    pub fn draw_round_rectangle(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2, x3, y3));
+
+        // The path runs from the top-left corner, so the corners are put in that order
+        let (x1, x2) = (x1.min(x2), x1.max(x2));
+        let (y1, y2) = (y1.min(y2), y1.max(y2));
+        // Corners wider or taller than the rectangle would overlap, so they meet at its middle
+        let x3 = (x3 / 2.).min((x2 - x1) / 2.);
+        let y3 = (y3 / 2.).min((y2 - y1) / 2.);
+
+        self.arc_path(x1 + x3, y1 + y3, x3, y3, PI * 1.5, PI, false, true);
+        self.line_exec(true, |ctx| {
+            ctx.line_to(x1, y2 - y3);
+        });
+        self.arc_path(x1 + x3, y2 - y3, x3, y3, PI, PI * 0.5, false, true);
+        self.line_exec(true, |ctx| {
+            ctx.line_to(x2 - x3, y2);
+        });
+        self.arc_path(x2 - x3, y2 - y3, x3, y3, PI * 0.5, 0., false, true);
+        self.line_exec(true, |ctx| {
+            ctx.line_to(x2, y1 + y3);
+        });
+        self.arc_path(x2 - x3, y1 + y3, x3, y3, 0., PI * -0.5, false, true);
+        self.line_exec(true, |ctx| {
+            ctx.line_to(x1 + x3, y1);
+        });
+
+        self.draw()?;
+        Ok(())
+    }
+
+    pub fn draw_sized_bitmap(
+        &self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        filename: &str,
+        keep_aspect: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x1, y1, x2, y2));
+
+        let (width, height) = ((x2 - x1).abs(), (y2 - y1).abs());
+        // Scaling blends colors, so the transparent color must be keyed out beforehand
+        let size = if keep_aspect || self.transparent_rgb.is_some() {
+            None
+        } else {
+            Some((width as i32, height as i32))
+        };
+        let Some(pixbuf) = self.load_bitmap(filename, size)? else {
+            self.draw_placeholder(x1, y1, x2, y2)?;
+            return Ok(());
+        };
+        let (pixbuf, left, top) = if keep_aspect {
+            // Fit the image within the rectangle, centering it along the axis with space to spare
+            let pixbuf = self.color_key(pixbuf);
+            let ratio =
+                (width / f64::from(pixbuf.width())).min(height / f64::from(pixbuf.height()));
+            let pixbuf = pixbuf
+                .scale_simple(
+                    ((f64::from(pixbuf.width()) * ratio) as i32).max(1),
+                    ((f64::from(pixbuf.height()) * ratio) as i32).max(1),
+                    gdk_pixbuf::InterpType::Bilinear,
+                )
+                .ok_or_else(|| Error::PixbufLoadError)?;
+            let left = x1.min(x2) + (width - f64::from(pixbuf.width())) / 2.;
+            let top = y1.min(y2) + (height - f64::from(pixbuf.height())) / 2.;
+            (pixbuf, left, top)
+        } else if self.transparent_rgb.is_some() {
+            let pixbuf = self
+                .color_key(pixbuf)
+                .scale_simple(
+                    (width as i32).max(1),
+                    (height as i32).max(1),
+                    gdk_pixbuf::InterpType::Bilinear,
+                )
+                .ok_or_else(|| Error::PixbufLoadError)?;
+            (pixbuf, x1.min(x2), y1.min(y2))
+        } else {
+            (pixbuf, x1.min(x2), y1.min(y2))
+        };
+
+        let surface = pixbuf
+            .create_surface(1, None::<&gdk::Window>)
+            .ok_or_else(|| Error::SurfaceCreateError)?;
+
+        let cr = cairo::Context::new(self.surface.as_ref())?;
+        cr.scale(
+            if x1 < x2 { 1. } else { -1. },
+            if y1 < y2 { 1. } else { -1. },
+        );
+        // A flipped axis is mirrored about the origin, so the image must start at -(size + offset)
+        cr.translate(
+            if x1 < x2 {
+                left
+            } else {
+                f64::from(-pixbuf.width()) - left
+            },
+            if y1 < y2 {
+                top
+            } else {
+                f64::from(-pixbuf.height()) - top
+            },
+        );
+        cr.set_source_surface(&surface, 0., 0.)?;
+        cr.paint()?;
+        Ok(())
+    }
+
+    /// Saves the canvas to a PNG file
+    pub fn save_png(&self, filename: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let width = self.surface.width();
+        let height = self.surface.height();
+        if width == 0 || height == 0 {
+            return Err(Error::EmptyCanvasError.into());
+        }
+
+        let stride = self.surface.stride() as usize;
+        let format = self.surface.format();
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        self.surface.with_data(|data| {
+            for y in 0..(height as usize) {
+                for x in 0..(width as usize) {
+                    let i = y * stride + x * 4;
+                    let [b, g, r] = cairo_util::pixel_bgr(format, &data[i..(i + 4)]);
+                    rgb.extend([r, g, b]);
+                }
+            }
+        })?;
+
+        gdk_pixbuf::Pixbuf::from_mut_slice(
+            rgb,
+            gdk_pixbuf::Colorspace::Rgb,
+            false,
+            8,
+            width,
+            height,
+            width * 3,
+        )
+        .savev(filename, "png", &[])?;
+        Ok(())
+    }
+
+    /// Loads a bitmap, or gives `None` if it fails to load and placeholders are enabled
+    fn load_bitmap(
+        &self,
+        filename: &str,
+        size: Option<(i32, i32)>,
+    ) -> Result<Option<gdk_pixbuf::Pixbuf>, Error> {
+        match pixbuf_from_filename(filename, size, self.resources.as_ref()) {
+            Ok(pixbuf) => Ok(Some(pixbuf)),
+            Err(_) if self.image_placeholder => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Draws a gray box crossed from corner to corner in place of a missing bitmap
+    fn draw_placeholder(&self, x1: f64, y1: f64, x2: f64, y2: f64) -> Result<(), cairo::Error> {
+        let cr = cairo::Context::new(self.surface.as_ref())?;
+        cr.set_source_rgb(0.5, 0.5, 0.5);
+        cr.set_line_width(self.scale.max(1.));
+        cr.rectangle(x1.min(x2), y1.min(y2), (x2 - x1).abs(), (y2 - y1).abs());
+        cr.move_to(x1, y1);
+        cr.line_to(x2, y2);
+        cr.move_to(x1, y2);
+        cr.line_to(x2, y1);
+        cr.stroke()
+    }
+
+    pub fn draw_text(&self, x: u16, y: u16, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        scale_vars!(self, (x, y));
+
+        self.show_text(x, y, text)?;
+        Ok(())
+    }
+
+    pub fn locate(&mut self, x: u16, y: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.cursor = (self.scaled(x), self.scaled(y));
+        Ok(())
+    }
+
+    pub fn print(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let line_height = self.cr_text().font_extents()?.height();
+        let wrap_width = f64::from(self.surface.width()) - self.cursor.0;
+        for line in self.wrap_text(text, wrap_width)? {
+            let (x, y) = self.cursor;
+            self.show_text(x, y, &line)?;
+            self.cursor.1 += line_height;
+        }
+        Ok(())
+    }
+
+    pub fn restore_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let state = self.saved_states.pop().ok_or(Error::StateStackEmptyError)?;
+        self.text_face = state.text_face;
+        self.text_size = state.text_size;
+        self.text_width = state.text_width;
+        self.text_underline = state.text_underline;
+        self.text_rgb = state.text_rgb;
+        self.pen_type = state.pen_type;
+        self.pen_width = state.pen_width;
+        self.pen_rgb = state.pen_rgb;
+        self.pen_cap = state.pen_cap;
+        self.pen_join = state.pen_join;
+        self.background_transparency = state.background_transparency;
+        self.background_rgb = state.background_rgb;
+        self.brush_type = state.brush_type;
+        self.brush_rgb = state.brush_rgb;
+        self.scale = state.scale;
+        self.cr_text_inval();
+        self.cr_pen_inval();
+        self.cr_background_inval();
+        self.cr_brush_inval();
+        Ok(())
+    }
+
+    pub fn save_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.saved_states.push(DrawState {
+            text_face: self.text_face.clone(),
+            text_size: self.text_size,
+            text_width: self.text_width,
+            text_underline: self.text_underline,
+            text_rgb: self.text_rgb,
+            pen_type: self.pen_type,
+            pen_width: self.pen_width,
+            pen_rgb: self.pen_rgb,
+            pen_cap: self.pen_cap,
+            pen_join: self.pen_join,
+            background_transparency: self.background_transparency,
+            background_rgb: self.background_rgb,
+            brush_type: self.brush_type,
+            brush_rgb: self.brush_rgb,
+            scale: self.scale,
+        });
+        Ok(())
+    }
+
+    pub fn set_transparent_color(&mut self, rgb: Option<(u16, u16, u16)>) {
+        self.transparent_rgb = rgb.map(|(r, g, b)| (r as u8, g as u8, b as u8));
+    }
+
+    /// Makes the pixels of `pixbuf` matching the transparent color fully transparent
+    fn color_key(&self, pixbuf: gdk_pixbuf::Pixbuf) -> gdk_pixbuf::Pixbuf {
+        match self.transparent_rgb {
+            Some((r, g, b)) => pixbuf.add_alpha(true, r, g, b),
+            None => pixbuf,
+        }
+    }
+
+    pub fn set_number_format(&mut self, width: u16, pad: char) {
+        self.number_width = width.into();
+        self.number_pad = pad;
+    }
+
+    pub fn format_number(&self, n: u16) -> String {
+        let digits = if self.number_hex {
+            format!("{:X}", n)
+        } else {
+            n.to_string()
+        };
+        let mut text = String::new();
+        for _ in digits.len()..self.number_width {
+            text.push(self.number_pad);
+        }
+        text + &digits
+    }
+
+    pub fn set_pen_style(
+        &mut self,
+        cap: ir::LineCap,
+        join: ir::LineJoin,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if (cap, join) != (self.pen_cap, self.pen_join) {
+            self.pen_cap = cap;
+            self.pen_join = join;
+            self.cr_pen_inval();
+            self.cr_background_inval();
+        }
+        Ok(())
+    }
+
+    pub fn text_width(&self, text: &str) -> Result<u16, Box<dyn std::error::Error>> {
+        Ok((self.measure_text(text)? / self.scale).round() as u16)
+    }
+
+    pub fn use_background(
+        &mut self,
+        option: ir::BackgroundTransparency,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rgb = (
+            f64::from(r) / 255.,
+            f64::from(g) / 255.,
+            f64::from(b) / 255.,
+        );
+        // Rebuilding contexts is costly, so scripts repeating the same settings keep them
+        if (option, rgb) != (self.background_transparency, self.background_rgb) {
+            self.background_transparency = option;
+            self.background_rgb = rgb;
+            self.cr_background_inval();
+            self.cr_brush_inval();
+        }
+        Ok(())
+    }
+
+    pub fn use_brush(
+        &mut self,
+        option: ir::BrushType,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let rgb = (
+            f64::from(r) / 255.,
+            f64::from(g) / 255.,
+            f64::from(b) / 255.,
+        );
+        if (option, rgb) != (self.brush_type, self.brush_rgb) {
+            self.brush_type = option;
+            self.brush_rgb = rgb;
+            self.cr_brush_inval();
+        }
+        Ok(())
+    }
+
+    /// Warns when substituting a font for the first time if `pedantic`
+    pub fn use_font(
+        &mut self,
+        name: &str,
+        width: u16,
+        height: u16,
+        bold: ir::FontWeight,
+        italic: ir::FontSlant,
+        underline: ir::FontUnderline,
+        r: u16,
+        g: u16,
+        b: u16,
+        pedantic: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.text_underline = underline;
+
+        let substitute = font_conv(name);
+        if pedantic && substitute != name && self.substituted_fonts.insert(name.to_string()) {
+            eprintln!(
+                "Warning: Substituting font '{}' with '{}'",
+                name, substitute
+            );
+        }
+
+        let font_face = cairo::FontFace::toy_create(
+            substitute,
+            match italic {
+                ir::FontSlant::Italic => cairo::FontSlant::Italic,
+                ir::FontSlant::NoItalic => cairo::FontSlant::Normal,
+            },
+            match bold {
+                ir::FontWeight::Bold => cairo::FontWeight::Bold,
+                ir::FontWeight::NoBold => cairo::FontWeight::Normal,
+                // Toy fonts only come in two weights, so others round to the closer one
+                ir::FontWeight::Weight(weight) if weight >= 600 => cairo::FontWeight::Bold,
+                ir::FontWeight::Weight(_) => cairo::FontWeight::Normal,
+            },
+        )?;
+
+        self.text_face = font_face;
+        self.text_rgb = (
+            f64::from(r) / 255.,
+            f64::from(g) / 255.,
+            f64::from(b) / 255.,
+        );
+
+        self.text_width = if width == 0 {
+            None
+        } else {
+            Some(self.scaled(width))
+        };
+
+        self.text_size = if height == 0 {
+            None
+        } else {
+            Some(self.font_size_for_height(self.scaled(height))?)
+        };
+
+        self.cr_text_inval();
+        Ok(())
+    }
+
+    pub fn use_pen(
+        &mut self,
+        option: ir::PenType,
+        width: u16,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Pen width follows the coordinate system, like the coordinates themselves
+        let width = f64::from(width) * self.scale;
+        let rgb = (
+            f64::from(r) / 255.,
+            f64::from(g) / 255.,
+            f64::from(b) / 255.,
+        );
+        if (option, width, rgb) != (self.pen_type, self.pen_width, self.pen_rgb) {
+            self.pen_type = option;
+            self.pen_width = width;
+            self.pen_rgb = rgb;
+            self.cr_pen_inval();
+            self.cr_background_inval();
+        }
+        Ok(())
+    }
+}
+
+pub fn pixbuf_from_bytes(
+    bytes: &[u8],
+    size: Option<(i32, i32)>,
+) -> Result<gdk::gdk_pixbuf::Pixbuf, Error> {
+    let loader = gdk_pixbuf::PixbufLoader::new();
+    if let Some((width, height)) = size {
+        loader.set_size(width, height);
+    }
+    loader.write(bytes)?;
+    loader.close()?;
+    loader.pixbuf().ok_or_else(|| Error::PixbufLoadError)
+}
+
+/// Decodes the image embedded in a `data:image/TYPE;base64,DATA` URI
+fn bytes_from_data_uri(uri: &str) -> Result<Vec<u8>, Error> {
+    let data = uri
+        .strip_prefix("data:image/")
+        .and_then(|uri| uri.split_once(','))
+        .and_then(|(media_type, data)| media_type.ends_with(";base64").then_some(data))
+        .ok_or_else(|| Error::DataUriError)?;
+    // glib decodes leniently, skipping anything outside the alphabet
+    let payload = data.trim_end_matches('=');
+    if data.is_empty()
+        || data.len() % 4 != 0
+        || data.len() - payload.len() > 2
+        || !payload
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '+' || c == '/')
+    {
+        return Err(Error::DataUriError);
+    }
+    Ok(glib::base64_decode(data))
+}
+
+/// Loads an image from a data URI, a resource of `resources`, or a file, in that order
+pub fn pixbuf_from_filename(
+    filename: &str,
+    size: Option<(i32, i32)>,
+    resources: &dyn resource::ResourceProvider,
+) -> Result<gdk::gdk_pixbuf::Pixbuf, Error> {
+    if filename.starts_with("data:") {
+        return pixbuf_from_bytes(&bytes_from_data_uri(filename)?, size);
+    }
+    if let Some(bytes) = resources.resource(filename) {
+        return pixbuf_from_bytes(&bytes, size);
+    }
+    Ok(if let Some((width, height)) = size {
+        gdk_pixbuf::Pixbuf::from_file_at_scale(filename, width, height, false)
+    } else {
+        gdk_pixbuf::Pixbuf::from_file(filename)
+    }?)
+}
+
+/// Maps fonts bundled with Windows 3 to generic families available everywhere
+pub fn font_conv(name: &str) -> &str {
+    match name.to_lowercase().as_str() {
+        "ms sans serif" | "system" | "helv" | "helvetica" | "arial" | "small fonts" => "Sans",
+        "ms serif" | "tms rmn" | "times new roman" | "roman" => "Serif",
+        "courier" | "courier new" | "terminal" | "fixedsys" | "modern" => "Monospace",
+        _ => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sys_terminal;
+
+    /// Renders `src` on a canvas the size of `golden`, comparing it with `golden`, in which each
+    /// pixel is `r`, `g`, or `b` if red, green, or blue, and otherwise `#` if dark, `+` if in
+    /// between, and `.` if light
+    fn assert_golden(src: &str, golden: &[&str]) {
+        let rgba = sys_terminal::render(src, golden[0].len() as i32, golden.len() as i32);
+        assert_pixels(src, &rgba, golden);
+    }
+
+    /// Like `assert_golden`, with options from `config`
+    fn assert_golden_with(src: &str, config: cfg::Config, golden: &[&str]) {
+        let (width, height) = (golden[0].len() as i32, golden.len() as i32);
+        let rgba = sys_terminal::render_with(src, width, height, config);
+        assert_pixels(src, &rgba, golden);
+    }
+
+    fn assert_pixels(src: &str, rgba: &[u8], golden: &[&str]) {
+        let rows: Vec<String> = rgba
+            .chunks(golden[0].len() * 4)
+            .map(|row| row.chunks(4).map(pixel_char).collect())
+            .collect();
+        assert!(rows == golden, "{}:\n{}", src, rows.join("\n"));
+    }
+
+    fn pixel_char(pixel: &[u8]) -> char {
+        match *pixel {
+            [170..=255, 0..=84, 0..=84, _] => 'r',
+            [0..=84, 170..=255, 0..=84, _] => 'g',
+            [0..=84, 0..=84, 170..=255, _] => 'b',
+            [r, g, b, _] => match (u16::from(r) + u16::from(g) + u16::from(b)) / 3 {
+                0..=84 => '#',
+                85..=169 => '+',
+                _ => '.',
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Writes an image drawn like a golden to a PNG file called `name` in the temporary
+    /// directory, returning its path
+    fn write_image(name: &str, rows: &[&str]) -> String {
+        let rgb: Vec<u8> = rows
+            .iter()
+            .flat_map(|row| row.chars())
+            .flat_map(|c| match c {
+                'r' => [255, 0, 0],
+                'g' => [0, 255, 0],
+                'b' => [0, 0, 255],
+                '#' => [0, 0, 0],
+                _ => [255, 255, 255],
+            })
+            .collect();
+        let (width, height) = (rows[0].len() as i32, rows.len() as i32);
+        let path = std::env::temp_dir().join(format!("oriel-{}-{}.png", std::process::id(), name));
+        let path = path.to_str().unwrap().to_string();
+        gdk_pixbuf::Pixbuf::from_mut_slice(
+            rgb,
+            gdk_pixbuf::Colorspace::Rgb,
+            false,
+            8,
+            width,
+            height,
+            width * 3,
+        )
+        .savev(&path, "png", &[])
+        .unwrap();
+        path
+    }
+
+    #[test]
+    fn line() {
+        assert_golden(
+            "DrawLine(1,1,14,6)",
+            &[
+                "................",
+                ".##.............",
+                "...##...........",
+                ".....###........",
+                "........###.....",
+                "..........###...",
+                ".............#..",
+                "................",
+            ],
+        );
+    }
+
+    #[test]
+    fn rectangle() {
+        assert_golden(
+            "DrawRectangle(1,1,10,6)",
+            &[
+                "............",
+                ".##########.",
+                ".#........#.",
+                ".#........#.",
+                ".#........#.",
+                ".#........#.",
+                ".##########.",
+                "............",
+            ],
+        );
+    }
+
+    #[test]
+    fn ellipse() {
+        assert_golden(
+            "DrawEllipse(1,1,14,10)",
+            &[
+                "................",
+                ".....######.....",
+                "...##......##...",
+                "..#..........#..",
+                ".#............#.",
+                ".#............#.",
+                ".#............#.",
+                ".#............#.",
+                "..#..........#..",
+                "...##......##...",
+                ".....######.....",
+                "................",
+            ],
+        );
+    }
+
+    #[test]
+    fn arc() {
+        assert_golden(
+            "DrawArc(1,1,14,10,14,1,1,1)",
+            &[
+                "................",
+                ".....######.....",
+                "...##......##...",
+                "................",
+                "................",
+                "................",
+            ],
+        );
+    }
+
+    #[test]
+    fn text() {
+        assert_golden(
+            "UseFont(\"Sans\",0,12,NOBOLD,NOITALIC,NOUNDERLINE,0,0,0)\nDrawText(1,0,\"Hi\")",
+            &[
+                "..............",
+                "..............",
+                "..............",
+                "..............",
+                "..+....+..#...",
+                "..#....#......",
+                "..#....#..+...",
+                "..#++++#..#...",
+                "..#++++#..#...",
+                "..#....#..#...",
+                "..#....#..#...",
+                "..#....#..#...",
+                "..............",
+                "..............",
+                "..............",
+                "..............",
+            ],
+        );
+    }
+
+    #[test]
+    fn thin_line_covers_one_row() {
+        assert_golden(
+            "DrawLine(1,2,10,2)",
+            &[
+                "............",
+                "............",
+                ".#########..",
+                "............",
+                "............",
+            ],
+        );
+    }
+
+    #[test]
+    fn sized_bitmap_keeps_aspect() {
+        let config = || cfg::Config {
+            keep_aspect: true,
+            ..Default::default()
+        };
+        let portrait = write_image("portrait", &["rr", "rr", "rr", "rr"]);
+        assert_golden_with(
+            &format!("DrawSizedBitmap(0,0,8,8,\"{}\")", portrait),
+            config(),
+            &[
+                "..rrrr..", "..rrrr..", "..rrrr..", "..rrrr..", "..rrrr..", "..rrrr..", "..rrrr..",
+                "..rrrr..",
+            ],
+        );
+        let landscape = write_image("landscape", &["gggg", "gggg"]);
+        assert_golden_with(
+            &format!("DrawSizedBitmap(0,0,8,8,\"{}\")", landscape),
+            config(),
+            &[
+                "........", "........", "gggggggg", "gggggggg", "gggggggg", "gggggggg", "........",
+                "........",
+            ],
+        );
+        // Without the option, the image is stretched to fill the rectangle
+        assert_golden(
+            &format!("DrawSizedBitmap(0,0,8,4,\"{}\")", portrait),
+            &["rrrrrrrr", "rrrrrrrr", "rrrrrrrr", "rrrrrrrr"],
+        );
+        std::fs::remove_file(portrait).unwrap();
+        std::fs::remove_file(landscape).unwrap();
+    }
+
+    #[test]
+    fn sized_bitmap_flipped() {
+        let image = write_image("flipped", &["rg", "b#"]);
+        for ((x1, y1, x2, y2), golden) in [
+            ((1, 1, 3, 3), [".rg.", ".b#."]),
+            ((3, 1, 1, 3), [".gr.", ".#b."]),
+            ((1, 3, 3, 1), [".b#.", ".rg."]),
+            ((3, 3, 1, 1), [".#b.", ".gr."]),
+        ] {
+            assert_golden(
+                &format!("DrawSizedBitmap({},{},{},{},\"{}\")", x1, y1, x2, y2, image),
+                &["....", golden[0], golden[1], "...."],
+            );
+        }
+        std::fs::remove_file(image).unwrap();
+    }
+
+    #[test]
+    fn pixel_bgr_unpremultiplies() {
+        let format = cairo::Format::ARgb32;
+        assert_eq!(
+            cairo_util::pixel_bgr(format, &[0, 64, 128, 128]),
+            [0, 128, 255]
+        );
+        assert_eq!(cairo_util::pixel_bgr(format, &[0, 0, 0, 0]), [0, 0, 0]);
+        assert_eq!(
+            cairo_util::pixel_bgr(cairo::Format::Rgb24, &[1, 2, 3, 0]),
+            [1, 2, 3]
+        );
+    }
+
+    #[test]
+    fn flood_over_transparent_hatch() {
+        assert_golden(
+            "UsePen(SOLID,1,255,0,0)
+            DrawRectangle(1,1,10,8)
+            UseBackground(TRANSPARENT,0,0,0)
+            UseBrush(CROSS,0,255,0)
+            UsePen(NULL,1,0,0,0)
+            DrawRectangle(2,2,9,7)
+            UseBrush(SOLID,0,0,255)
+            DrawFlood(5,5,255,0,0)",
+            &[
+                "............",
+                ".rrrrrrrrrr.",
+                ".rbbbbbbbbr.",
+                ".rbbbbbbbbr.",
+                ".rbbbbbbbbr.",
+                ".rbbbbbbbbr.",
+                ".rbbbbbbbbr.",
+                ".rbbbbbbbbr.",
+                ".rrrrrrrrrr.",
+                "............",
+            ],
+        );
+    }
+
+    #[test]
+    fn consecutive_floods_match_separate_canvases() {
+        let setup = "UsePen(SOLID,1,0,0,0)\nDrawRectangle(1,1,7,7)\nDrawRectangle(9,1,15,7)";
+        let flood_a = "UseBrush(SOLID,255,0,0)\nDrawFlood(4,4,0,0,0)";
+        let flood_b = "UseBrush(SOLID,0,255,0)\nDrawFlood(12,4,0,0,0)";
+        let render = |floods: &[&str]| {
+            let src = std::iter::once(setup)
+                .chain(floods.iter().copied())
+                .collect::<Vec<_>>()
+                .join("\n");
+            sys_terminal::render(&src, 17, 9)
+        };
+        let (none, a, b) = (render(&[]), render(&[flood_a]), render(&[flood_b]));
+        assert!(a != none && b != none);
+
+        // Each flood stays within its rectangle, so together they change the pixels that each
+        // changes on a canvas of its own, and nothing else
+        let expected: Vec<u8> = none
+            .chunks(4)
+            .zip(a.chunks(4).zip(b.chunks(4)))
+            .flat_map(|(none, (a, b))| if a != none { a } else { b })
+            .copied()
+            .collect();
+        assert!(render(&[flood_a, flood_b]) == expected);
+    }
+
+    /// Times successive floods of a whole canvas. Run with
+    /// `cargo test --release flood_benchmark -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn flood_benchmark() {
+        const FLOODS: u32 = 200;
+        let src = format!(
+            "For i=1 To {}\nUseBrush(SOLID,255,0,0)\nDrawFlood(0,0,0,0,0)\nUseBrush(SOLID,0,0,255)\nDrawFlood(0,0,0,0,0)\nNext i",
+            FLOODS / 2
+        );
+        let start = std::time::Instant::now();
+        sys_terminal::render(&src, 640, 480);
+        let elapsed = start.elapsed();
+        println!(
+            "{} floods of 640x480 in {:?}, {:?} each",
+            FLOODS,
+            elapsed,
+            elapsed / FLOODS
+        );
+    }
+
+    #[test]
+    fn fixed_width_text_background() {
+        // Text in the color of the background leaves only the background visible
+        assert_golden(
+            "UseBackground(OPAQUE,0,0,0)
+            UseFont(\"Sans\",3,12,NOBOLD,NOITALIC,NOUNDERLINE,0,0,0)
+            DrawText(1,0,\"aé\")",
+            // Two characters 3 pixels wide, although "é" takes two bytes
+            &[
+                "............",
+                "............",
+                ".######.....",
+                ".######.....",
+            ],
+        );
+    }
+
+    #[test]
+    fn font_height() {
+        for height in [10, 16, 25, 40] {
+            // The opaque background spans the height of the font
+            let src = format!(
+                "UseBackground(OPAQUE,0,0,0)
+                UseFont(\"Sans\",0,{},NOBOLD,NOITALIC,NOUNDERLINE,0,0,0)
+                DrawText(0,0,\"x\")",
+                height
+            );
+            let rgba = sys_terminal::render(&src, 4, 64);
+            let rows = rgba.chunks(4 * 4).filter(|row| row[0] == 0).count();
+            assert!(
+                rows.abs_diff(height) <= 1,
+                "{} rows for height {}",
+                rows,
+                height
+            );
+        }
+    }
+
+    #[test]
+    fn degenerate_arcs() {
+        // A rectangle without height flattens the ellipse into a line
+        for (src, line) in [
+            ("DrawEllipse(1,3,10,3)", "..########.."),
+            ("DrawArc(1,3,10,3,10,3,1,3)", ".#########.."),
+            ("DrawPie(1,3,10,3,10,3,1,3)", ".#########.."),
+            ("DrawChord(1,3,10,3,10,3,1,3)", ".#########.."),
+        ] {
+            let blank = "............";
+            assert_golden(src, &[blank, blank, blank, line, blank, blank]);
+        }
+        assert_golden(
+            "DrawEllipse(5,1,5,6)",
+            &[
+                "............",
+                ".....#......",
+                ".....#......",
+                ".....#......",
+                ".....#......",
+                ".....#......",
+                "............",
+            ],
+        );
+        // One without width or height leaves nothing to draw
+        for src in [
+            "DrawArc(5,3,5,3,1,1,9,9)",
+            "DrawPie(5,3,5,3,1,1,9,9)",
+            "DrawChord(5,3,5,3,1,1,9,9)",
+        ] {
+            assert_golden(src, &["......", "......", "......", "......", "......"]);
+        }
+    }
+
+    #[test]
+    fn dashes_scale_with_pen() {
+        let blank = "........................................";
+        let thin = "####....####....####....####....####....";
+        let thick = "########........########........########";
+        assert_golden(
+            "UsePen(DOT,1,0,0,0)\nDrawLine(0,4,40,4)",
+            &[blank, blank, blank, blank, thin, blank],
+        );
+        // Twice the width gives dots and gaps twice as long
+        assert_golden(
+            "UsePen(DOT,2,0,0,0)\nDrawLine(0,4,40,4)",
+            &[blank, blank, blank, thick, thick, blank],
+        );
+    }
+
+    #[test]
+    fn hatch_gaps() {
+        let src = |mode| {
+            format!(
+                "UsePen(NULL,1,0,0,0)
+                UseBrush(SOLID,255,0,0)
+                DrawRectangle(0,0,12,12)
+                UseBackground({},0,0,255)
+                UseBrush(CROSS,0,255,0)
+                DrawRectangle(2,2,10,10)",
+                mode
+            )
+        };
+        // Gaps show the background color
+        assert_golden(
+            &src("OPAQUE"),
+            &[
+                "rrrrrrrrrrrr",
+                "rrrrrrrrrrrr",
+                "rrbbbbbbgbrr",
+                "rrbbbbbbgbrr",
+                "rrbbbbbbgbrr",
+                "rrbbbbbbgbrr",
+                "rrbbbbbbgbrr",
+                "rrbbbbbbgbrr",
+                "rrggggggggrr",
+                "rrbbbbbbgbrr",
+                "rrrrrrrrrrrr",
+                "rrrrrrrrrrrr",
+            ],
+        );
+        // Gaps show what was drawn before
+        assert_golden(
+            &src("TRANSPARENT"),
+            &[
+                "rrrrrrrrrrrr",
+                "rrrrrrrrrrrr",
+                "rrrrrrrrgrrr",
+                "rrrrrrrrgrrr",
+                "rrrrrrrrgrrr",
+                "rrrrrrrrgrrr",
+                "rrrrrrrrgrrr",
+                "rrrrrrrrgrrr",
+                "rrggggggggrr",
+                "rrrrrrrrgrrr",
+                "rrrrrrrrrrrr",
+                "rrrrrrrrrrrr",
+            ],
+        );
+    }
+
+    #[test]
+    fn round_rectangle_oversized_corners() {
+        // Corners are clamped to meet in the middle, leaving an ellipse, whichever way round the
+        // corners of the rectangle are given
+        for src in [
+            "DrawRoundRectangle(1,1,18,8,100,100)",
+            "DrawRoundRectangle(18,8,1,1,100,100)",
+        ] {
+            assert_golden(
+                src,
+                &[
+                    "....................",
+                    "......########......",
+                    "...###........###...",
+                    "..#..............#..",
+                    ".#................#.",
+                    ".#................#.",
+                    "..#..............#..",
+                    "...###........###...",
+                    "......########......",
+                    "....................",
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn round_rectangle_outline_is_closed() {
+        assert_golden(
+            "DrawRoundRectangle(1,1,18,8,8,8)",
+            &[
+                "....................",
+                "...##############...",
+                "..##............##..",
+                ".#................#.",
+                ".#................#.",
+                ".#................#.",
+                ".#................#.",
+                "..##............##..",
+                "...##############...",
+                "....................",
+            ],
+        );
+        // A gap where an edge meets a corner would let the flood leak out of the rectangle
+        let rgba = sys_terminal::render(
+            "UsePen(SOLID,1,255,0,0)
+            DrawRoundRectangle(2,2,37,27,16,12)
+            UseBrush(SOLID,0,0,255)
+            DrawFlood(20,15,255,0,0)",
+            40,
+            30,
+        );
+        let pixel = |x: usize, y: usize| pixel_char(&rgba[(y * 40 + x) * 4..][..4]);
+        for (x, y) in [
+            (0, 0),
+            (39, 0),
+            (0, 29),
+            (39, 29),
+            (3, 3),
+            (36, 3),
+            (3, 26),
+            (36, 26),
+        ] {
+            assert_eq!(pixel(x, y), '.', "({}, {})", x, y);
+        }
+        for (x, y) in [(20, 15), (5, 15), (34, 15), (20, 4), (20, 25)] {
+            assert_eq!(pixel(x, y), 'b', "({}, {})", x, y);
+        }
+    }
+
+    #[test]
+    fn nearly_full_pie_is_closed() {
+        // The arc runs almost all the way round before both edges return to the center, leaving
+        // only a narrow wedge outside the fill
+        let src = "UsePen(SOLID,1,255,0,0)
+            UseBrush(SOLID,0,0,255)
+            DrawPie(1,1,22,22,22,10,22,13)";
+        let golden = [
+            "........................",
+            ".........rrrrrr.........",
+            "......rrrbbbbbbrrr......",
+            ".....rrbbbbbbbbbbrr.....",
+            "....rbbbbbbbbbbbbbbr....",
+            "...rbbbbbbbbbbbbbbbbr...",
+            "..rrbbbbbbbbbbbbbbbbrr..",
+            "..rbbbbbbbbbbbbbbbbbbr..",
+            "..rbbbbbbbbbbbbbbbbbbr..",
+            ".rbbbbbbbbbbbbbbbbbbbbr.",
+            ".rbbbbbbbbbbbbbbbbbrrrr.",
+            ".rbbbbbbbbbbrrrrrrr.....",
+            ".rbbbbbbbbbbrrrrrrr.....",
+            ".rbbbbbbbbbbbbbbbbbrrrr.",
+            ".rbbbbbbbbbbbbbbbbbbbbr.",
+            "..rbbbbbbbbbbbbbbbbbbr..",
+            "..rbbbbbbbbbbbbbbbbbbr..",
+            "..rrbbbbbbbbbbbbbbbbrr..",
+            "...rbbbbbbbbbbbbbbbbr...",
+            "....rbbbbbbbbbbbbbbr....",
+            ".....rrbbbbbbbbbbrr.....",
+            "......rrrbbbbbbrrr......",
+            ".........rrrrrr.........",
+            "........................",
+        ];
+        assert_golden(src, &golden);
+
+        // Every fill pixel lies between outline pixels, both across its row and down its column
+        let rows: Vec<Vec<u8>> = golden.iter().map(|row| row.bytes().collect()).collect();
+        let columns: Vec<Vec<u8>> = (0..golden[0].len())
+            .map(|x| rows.iter().map(|row| row[x]).collect())
+            .collect();
+        for line in rows.iter().chain(&columns) {
+            for (i, _) in line.iter().enumerate().filter(|(_, &pixel)| pixel == b'b') {
+                assert!(
+                    line[..i].contains(&b'r') && line[i + 1..].contains(&b'r'),
+                    "fill outside the outline:\n{}",
+                    golden.join("\n")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn missing_and_corrupt_bitmaps_draw_placeholders() {
+        let config = || cfg::Config {
+            image_placeholder: true,
+            ..Default::default()
+        };
+        for filename in ["oriel-missing.bmp", "data:image/png;base64,AAAA"] {
+            // The program goes on past the bitmap, drawing the red line
+            let src = format!(
+                "DrawSizedBitmap(1,1,7,7,\"{}\")\nUsePen(SOLID,1,255,0,0)\nDrawLine(0,8,9,8)",
+                filename
+            );
+            assert_golden_with(
+                &src,
+                config(),
+                &[
+                    ".........",
+                    ".++..++..",
+                    ".++..++..",
+                    "...++....",
+                    "...++....",
+                    ".++..++..",
+                    ".++..++..",
+                    ".........",
+                    "rrrrrrrrr",
+                ],
+            );
+        }
+    }
+}
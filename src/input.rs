@@ -0,0 +1,253 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet, VecDeque},
+    rc::Rc,
+};
+
+use crate::{ir, vm};
+
+pub struct MouseRegion<'a> {
+    pub x1: f64,
+    pub y1: f64,
+    pub x2: f64,
+    pub y2: f64,
+    pub callbacks: &'a ir::MouseCallbacks<'a>,
+}
+
+impl<'a> MouseRegion<'a> {
+    /// Region between two opposite corners in canvas coordinates, given in any order. Corners are
+    /// ordered only once scaled, so that negative coordinates in signed mode compare correctly.
+    pub fn new(
+        (x1, y1): (f64, f64),
+        (x2, y2): (f64, f64),
+        callbacks: &'a ir::MouseCallbacks<'a>,
+    ) -> Self {
+        Self {
+            x1: x1.min(x2),
+            y1: y1.min(y2),
+            x2: x1.max(x2),
+            y2: y1.max(y2),
+            callbacks,
+        }
+    }
+
+    /// All four edges are inclusive, so a click exactly on a border counts as inside the region.
+    fn contains(&self, x: f64, y: f64) -> bool {
+        self.x1 <= x && self.y1 <= y && self.x2 >= x && self.y2 >= y
+    }
+}
+
+/// Events are stored in bounded queues. Once a queue holds `capacity` events, the oldest ones are
+/// dropped and lost to make room for new ones.
+#[derive(Default)]
+pub struct InputQueue {
+    keyboard: VecDeque<vm::Key>,
+    mouse: VecDeque<(f64, f64)>,
+    menu: VecDeque<usize>,
+    pub timer: bool,
+    pub closed: bool,
+    capacity: usize,
+}
+
+fn push_capped<T>(queue: &mut VecDeque<T>, capacity: usize, items: impl IntoIterator<Item = T>) {
+    for item in items {
+        if queue.len() >= capacity {
+            queue.pop_front();
+        }
+        queue.push_back(item);
+    }
+}
+
+impl InputQueue {
+    fn clear(&mut self) {
+        self.keyboard.clear();
+        self.mouse.clear();
+        self.menu.clear();
+        self.timer = false;
+    }
+
+    pub fn push_keyboard(&mut self, keys: impl IntoIterator<Item = vm::Key>) {
+        push_capped(&mut self.keyboard, self.capacity, keys);
+    }
+
+    pub fn push_mouse(&mut self, coords: (f64, f64)) {
+        push_capped(&mut self.mouse, self.capacity, [coords]);
+    }
+
+    pub fn push_menu(&mut self, key: usize) {
+        push_capped(&mut self.menu, self.capacity, [key]);
+    }
+}
+
+#[derive(Default)]
+pub struct InputCtx<'a> {
+    pub keyboard: HashMap<vm::Key, ir::Identifier<'a>>,
+    pub mouse: Vec<MouseRegion<'a>>,
+    /// Handler of clicks outside every region in `mouse`
+    pub click: Option<&'a ir::MouseCallbacks<'a>>,
+    pub menu: HashMap<usize, ir::Identifier<'a>>,
+    /// Keys of the disabled menu items, whose input is ignored
+    pub menu_disabled: HashSet<usize>,
+    pub timer: Option<ir::Identifier<'a>>,
+    /// Whether keys without a label continue with the next command, for `SetWaitMode(ANYKEY)`
+    pub any_key: bool,
+    pub queue: Rc<RefCell<InputQueue>>,
+}
+
+impl<'a> InputCtx<'a> {
+    pub fn new(queue_capacity: usize) -> Self {
+        Self {
+            queue: Rc::new(RefCell::new(InputQueue {
+                capacity: queue_capacity,
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    /// Keys of the menu items jumping to `label`
+    pub fn menu_keys(&self, label: ir::Identifier<'a>) -> Vec<usize> {
+        self.menu
+            .iter()
+            .filter(|(_, item_label)| item_label.0.eq_ignore_ascii_case(label.0))
+            .map(|(&key, _)| key)
+            .collect()
+    }
+
+    pub fn clear_queue(&self) {
+        self.queue.borrow_mut().clear();
+    }
+
+    /// Processes the queue like `process_queue`, then empties it so that no input is handled twice.
+    pub fn take_input(&self, scale: f64) -> Option<vm::Input<'a>> {
+        let input = self.process_queue(scale);
+        self.clear_queue();
+        input
+    }
+
+    pub fn process_queue(&self, scale: f64) -> Option<vm::Input<'a>> {
+        {
+            let queue = self.queue.borrow();
+            if queue.closed {
+                return Some(vm::Input::End);
+            }
+            for key in &queue.keyboard {
+                if let Some(&label) = self.keyboard.get(key) {
+                    return Some(vm::Input::Goto(label));
+                }
+            }
+            if self.any_key && !queue.keyboard.is_empty() {
+                return Some(vm::Input::Continue);
+            }
+            for mouse in &queue.mouse {
+                let callbacks = self
+                    .mouse
+                    .iter()
+                    .find(|region| region.contains(mouse.0, mouse.1))
+                    .map(|region| region.callbacks)
+                    .or(self.click);
+                if let Some(callbacks) = callbacks {
+                    return Some(vm::Input::Mouse {
+                        callbacks,
+                        x: (mouse.0 / scale) as u16,
+                        y: (mouse.1 / scale) as u16,
+                    });
+                }
+            }
+            for menu in queue
+                .menu
+                .iter()
+                .filter(|menu| !self.menu_disabled.contains(menu))
+            {
+                if let Some(&label) = self.menu.get(menu) {
+                    return Some(vm::Input::Goto(label));
+                }
+            }
+            if queue.timer {
+                if let Some(label) = self.timer {
+                    return Some(vm::Input::Timer(label));
+                }
+            }
+        }
+        self.clear_queue();
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CALLBACKS: ir::MouseCallbacks = ir::MouseCallbacks {
+        label: ir::Identifier("label"),
+        x: ir::Identifier("x"),
+        y: ir::Identifier("y"),
+    };
+
+    #[test]
+    fn region_edges_are_inclusive() {
+        let region = MouseRegion::new((10., 20.), (30., 40.), &CALLBACKS);
+        assert!(region.contains(10., 30.));
+        assert!(region.contains(30., 30.));
+        assert!(region.contains(20., 20.));
+        assert!(region.contains(20., 40.));
+        assert!(region.contains(10., 20.));
+        assert!(region.contains(30., 40.));
+        assert!(!region.contains(9.5, 30.));
+        assert!(!region.contains(30.5, 30.));
+        assert!(!region.contains(20., 19.5));
+        assert!(!region.contains(20., 40.5));
+    }
+
+    #[test]
+    fn region_with_reversed_corners() {
+        let region = MouseRegion::new((30., 40.), (10., 20.), &CALLBACKS);
+        assert_eq!(
+            (region.x1, region.y1, region.x2, region.y2),
+            (10., 20., 30., 40.)
+        );
+
+        let mut ctx = InputCtx::new(8);
+        ctx.mouse
+            .push(MouseRegion::new((30., 20.), (10., 40.), &CALLBACKS));
+        ctx.queue.borrow_mut().push_mouse((15., 25.));
+        assert!(matches!(
+            ctx.take_input(1.),
+            Some(vm::Input::Mouse { x: 15, y: 25, .. })
+        ));
+    }
+
+    #[test]
+    fn region_with_negative_corner() {
+        // In signed mode, x=65526 is scaled to -10
+        let x1 = f64::from(65526u16 as i16);
+        let region = MouseRegion::new((x1, 0.), (100., 50.), &CALLBACKS);
+        assert!(region.contains(-10., 25.));
+        assert!(region.contains(0., 25.));
+        assert!(region.contains(100., 25.));
+        assert!(!region.contains(101., 25.));
+    }
+
+    #[test]
+    fn ctrl_key_and_plain_key_have_own_labels() {
+        let mut ctx = InputCtx::new(8);
+        for (ctrl, label) in [(true, "ctrl"), (false, "plain")] {
+            ctx.keyboard.insert(
+                vm::Key::Physical(ir::PhysicalKey::new('C', ctrl)),
+                ir::Identifier(label),
+            );
+        }
+        let press = |chr, ctrl| {
+            let key = vm::Key::Physical(ir::PhysicalKey::new(chr, ctrl));
+            ctx.queue.borrow_mut().push_keyboard([key]);
+            match ctx.take_input(1.) {
+                Some(vm::Input::Goto(label)) => Some(label.0),
+                _ => None,
+            }
+        };
+        assert_eq!(press('c', true), Some("ctrl"));
+        assert_eq!(press('C', true), Some("ctrl"));
+        assert_eq!(press('C', false), Some("plain"));
+        assert_eq!(press('c', false), None);
+    }
+}
@@ -28,6 +28,8 @@ pub enum MathOperator {
     Subtract,
     Multiply,
     Divide,
+    Max,
+    Min,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -60,7 +62,7 @@ pub enum BackgroundTransparency {
     Transparent,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum BrushType {
     Solid,
     DiagonalUp,
@@ -78,13 +80,23 @@ pub enum Coordinates {
     Metric,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum CursorShape {
+    #[default]
+    Arrow,
+    Cross,
+    Hand,
+    Null,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum WaitMode {
     Null,
     Focus,
+    AnyKey,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PenType {
     Solid,
     Null,
@@ -94,10 +106,26 @@ pub enum PenType {
     DashDotDot,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineCap {
+    Butt,
+    Round,
+    Square,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineJoin {
+    Miter,
+    Round,
+    Bevel,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum FontWeight {
     Bold,
     NoBold,
+    /// From 100 to 900, where 400 is normal and 700 is bold
+    Weight(u16),
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -141,6 +169,12 @@ pub enum VirtualKey {
     F(u8),
     NumLock,
     ScrollLock,
+    ShiftL,
+    ShiftR,
+    CtrlL,
+    CtrlR,
+    AltL,
+    AltR,
     ColonOrSemiColon,
     PlusOrEqual,
     LessOrComma,
@@ -250,6 +284,12 @@ impl TryFrom<u16> for VirtualKey {
             127 => VirtualKey::F(16),
             144 => VirtualKey::NumLock,
             145 => VirtualKey::ScrollLock,
+            160 => VirtualKey::ShiftL,
+            161 => VirtualKey::ShiftR,
+            162 => VirtualKey::CtrlL,
+            163 => VirtualKey::CtrlR,
+            164 => VirtualKey::AltL,
+            165 => VirtualKey::AltR,
             186 => VirtualKey::ColonOrSemiColon,
             187 => VirtualKey::PlusOrEqual,
             188 => VirtualKey::LessOrComma,
@@ -272,6 +312,17 @@ pub struct PhysicalKey {
     pub ctrl: bool,
 }
 
+impl PhysicalKey {
+    /// Letters typed with Ctrl held are case-insensitive, so `"^C"` matches Ctrl+C
+    /// regardless of Shift or Caps Lock
+    pub fn new(chr: char, ctrl: bool) -> Self {
+        PhysicalKey {
+            chr: if ctrl { chr.to_ascii_uppercase() } else { chr },
+            ctrl,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Key<'a> {
     Virtual(Integer<'a>),
@@ -288,6 +339,18 @@ pub struct MenuCategory<'a> {
 pub struct MenuItem<'a> {
     pub name: &'a str,
     pub label: Option<Identifier<'a>>,
+    /// Whether the item shows a checkmark, if it is checkable
+    pub checked: Option<bool>,
+    pub accelerator: Option<Accelerator<'a>>,
+}
+
+/// Key combination choosing a menu item
+#[derive(Debug, Clone, Copy)]
+pub struct Accelerator<'a> {
+    pub key: &'a str,
+    pub ctrl: bool,
+    pub shift: bool,
+    pub alt: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -310,6 +373,8 @@ pub struct MouseRegion<'a> {
     pub x2: Integer<'a>,
     pub y2: Integer<'a>,
     pub callbacks: MouseCallbacks<'a>,
+    /// Shape of the cursor while hovering over the region
+    pub cursor: Option<CursorShape>,
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
@@ -321,19 +386,28 @@ pub enum Integer<'a> {
     Variable(Identifier<'a>),
 }
 
-#[derive(Debug, Clone, Copy)]
+/// String variables are distinguished from integer ones by the `$` ending their name
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum Str<'a> {
+    Literal(&'a str),
+    Variable(Identifier<'a>),
+}
+
+#[derive(Debug, Clone)]
 pub enum SetValue<'a> {
     Value(Integer<'a>),
     Expression {
-        i1: Integer<'a>,
+        i1: Box<SetValue<'a>>,
         op: MathOperator,
-        i2: Integer<'a>,
+        i2: Box<SetValue<'a>>,
     },
+    Abs(Box<SetValue<'a>>),
 }
 
 #[derive(Debug)]
 pub enum Command<'a> {
     Beep,
+    ClearTransparentColor,
     DrawArc {
         x1: Integer<'a>,
         y1: Integer<'a>,
@@ -348,7 +422,7 @@ pub enum Command<'a> {
     DrawBitmap {
         x: Integer<'a>,
         y: Integer<'a>,
-        filename: &'a str,
+        filename: Str<'a>,
     },
     DrawChord {
         x1: Integer<'a>,
@@ -413,41 +487,124 @@ pub enum Command<'a> {
         y1: Integer<'a>,
         x2: Integer<'a>,
         y2: Integer<'a>,
-        filename: &'a str,
+        filename: Str<'a>,
     },
     DrawText {
         x: Integer<'a>,
         y: Integer<'a>,
-        text: &'a str,
+        text: Str<'a>,
     },
     End,
+    Exit {
+        code: Integer<'a>,
+    },
+    GetEnv {
+        name: Str<'a>,
+        result: Identifier<'a>,
+    },
+    GetTicks {
+        result: Identifier<'a>,
+    },
+    GetTime {
+        hour: Identifier<'a>,
+        minute: Identifier<'a>,
+        second: Identifier<'a>,
+    },
     Gosub(Identifier<'a>),
     Return,
     Goto(Identifier<'a>),
     If {
-        i1: Integer<'a>,
+        i1: SetValue<'a>,
         op: LogicalOperator,
-        i2: Integer<'a>,
+        i2: SetValue<'a>,
         goto_false: usize,
     },
+    Locate {
+        x: Integer<'a>,
+        y: Integer<'a>,
+    },
+    MessageBeep(MessageBoxIcon),
     MessageBox {
         typ: MessageBoxType,
         default_button: Integer<'a>,
         icon: MessageBoxIcon,
-        text: &'a str,
-        caption: &'a str,
+        text: Str<'a>,
+        caption: Str<'a>,
         button_pushed: Identifier<'a>,
     },
-    Run(&'a str),
+    OnClick(Option<MouseCallbacks<'a>>),
+    OutputLn(Str<'a>),
+    PollInput,
+    Print(Str<'a>),
+    ProgressEnd,
+    ProgressSet {
+        value: Integer<'a>,
+    },
+    ProgressStart {
+        max: Integer<'a>,
+    },
+    RestoreState,
+    Run(Str<'a>),
+    SaveState,
     Set {
         var: Identifier<'a>,
         val: SetValue<'a>,
     },
+    SetCanvasSize {
+        width: Integer<'a>,
+        height: Integer<'a>,
+    },
+    SetCursor(CursorShape),
     SetKeyboard(HashMap<Key<'a>, Identifier<'a>>),
     SetMenu(Vec<MenuCategory<'a>>),
+    SetMenuCheck {
+        label: Identifier<'a>,
+        on: Integer<'a>,
+    },
+    SetMenuEnabled {
+        label: Identifier<'a>,
+        enabled: Integer<'a>,
+    },
     SetMouse(Vec<MouseRegion<'a>>),
+    SetNumberBase(Integer<'a>),
+    SetNumberFormat {
+        width: Integer<'a>,
+        pad: Str<'a>,
+    },
+    SetPenStyle {
+        cap: LineCap,
+        join: LineJoin,
+    },
+    SetStatus(Str<'a>),
+    SetStr {
+        var: Identifier<'a>,
+        val: Str<'a>,
+    },
+    SetTimer {
+        milliseconds: Integer<'a>,
+        label: Option<Identifier<'a>>,
+    },
+    SetTransparentColor {
+        r: Integer<'a>,
+        g: Integer<'a>,
+        b: Integer<'a>,
+    },
     SetWaitMode(WaitMode),
     SetWindow(SetWindowOption),
+    Str {
+        value: Integer<'a>,
+        result: Identifier<'a>,
+    },
+    StrLen {
+        text: Str<'a>,
+        result: Identifier<'a>,
+    },
+    SubStr {
+        text: Str<'a>,
+        start: Integer<'a>,
+        count: Integer<'a>,
+        result: Identifier<'a>,
+    },
     UseBackground {
         option: BackgroundTransparency,
         r: Integer<'a>,
@@ -460,7 +617,11 @@ pub enum Command<'a> {
         g: Integer<'a>,
         b: Integer<'a>,
     },
-    UseCaption(&'a str),
+    TextWidth {
+        text: Str<'a>,
+        result: Identifier<'a>,
+    },
+    UseCaption(Str<'a>),
     UseCoordinates(Coordinates),
     UseFont {
         name: &'a str,
@@ -480,11 +641,92 @@ pub enum Command<'a> {
         g: Integer<'a>,
         b: Integer<'a>,
     },
+    Val {
+        text: Str<'a>,
+        result: Identifier<'a>,
+    },
     WaitInput(Option<Integer<'a>>),
 }
 
+impl Command<'_> {
+    /// Name of the command as written in programs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Beep => "Beep",
+            Command::ClearTransparentColor => "ClearTransparentColor",
+            Command::DrawArc { .. } => "DrawArc",
+            Command::DrawBackground => "DrawBackground",
+            Command::DrawBitmap { .. } => "DrawBitmap",
+            Command::DrawChord { .. } => "DrawChord",
+            Command::DrawEllipse { .. } => "DrawEllipse",
+            Command::DrawFlood { .. } => "DrawFlood",
+            Command::DrawLine { .. } => "DrawLine",
+            Command::DrawNumber { .. } => "DrawNumber",
+            Command::DrawPie { .. } => "DrawPie",
+            Command::DrawRectangle { .. } => "DrawRectangle",
+            Command::DrawRoundRectangle { .. } => "DrawRoundRectangle",
+            Command::DrawSizedBitmap { .. } => "DrawSizedBitmap",
+            Command::DrawText { .. } => "DrawText",
+            Command::End => "End",
+            Command::Exit { .. } => "Exit",
+            Command::GetEnv { .. } => "GetEnv",
+            Command::GetTicks { .. } => "GetTicks",
+            Command::GetTime { .. } => "GetTime",
+            Command::Gosub(..) => "Gosub",
+            Command::Return => "Return",
+            Command::Goto(..) => "Goto",
+            Command::If { .. } => "If",
+            Command::Locate { .. } => "Locate",
+            Command::MessageBeep(..) => "MessageBeep",
+            Command::MessageBox { .. } => "MessageBox",
+            Command::OnClick(..) => "OnClick",
+            Command::OutputLn(..) => "OutputLn",
+            Command::PollInput => "PollInput",
+            Command::Print(..) => "Print",
+            Command::ProgressEnd => "ProgressEnd",
+            Command::ProgressSet { .. } => "ProgressSet",
+            Command::ProgressStart { .. } => "ProgressStart",
+            Command::RestoreState => "RestoreState",
+            Command::Run(..) => "Run",
+            Command::SaveState => "SaveState",
+            Command::Set { .. } => "Set",
+            Command::SetCanvasSize { .. } => "SetCanvasSize",
+            Command::SetCursor(..) => "SetCursor",
+            Command::SetKeyboard(..) => "SetKeyboard",
+            Command::SetMenu(..) => "SetMenu",
+            Command::SetMenuCheck { .. } => "SetMenuCheck",
+            Command::SetMenuEnabled { .. } => "SetMenuEnabled",
+            Command::SetMouse(..) => "SetMouse",
+            Command::SetNumberBase(..) => "SetNumberBase",
+            Command::SetNumberFormat { .. } => "SetNumberFormat",
+            Command::SetPenStyle { .. } => "SetPenStyle",
+            Command::SetStatus(..) => "SetStatus",
+            Command::SetStr { .. } => "SetStr",
+            Command::SetTimer { .. } => "SetTimer",
+            Command::SetTransparentColor { .. } => "SetTransparentColor",
+            Command::SetWaitMode(..) => "SetWaitMode",
+            Command::SetWindow(..) => "SetWindow",
+            Command::Str { .. } => "Str",
+            Command::StrLen { .. } => "StrLen",
+            Command::SubStr { .. } => "SubStr",
+            Command::UseBackground { .. } => "UseBackground",
+            Command::UseBrush { .. } => "UseBrush",
+            Command::TextWidth { .. } => "TextWidth",
+            Command::UseCaption(..) => "UseCaption",
+            Command::UseCoordinates(..) => "UseCoordinates",
+            Command::UseFont { .. } => "UseFont",
+            Command::UsePen { .. } => "UsePen",
+            Command::Val { .. } => "Val",
+            Command::WaitInput(..) => "WaitInput",
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Program<'a> {
     pub commands: Vec<Command<'a>>,
-    pub labels: HashMap<Identifier<'a>, usize>,
+    /// Labels are case-insensitive, so they are stored in lowercase
+    pub labels: HashMap<String, usize>,
+    /// Source (line, column) of each command
+    pub locations: Vec<(usize, usize)>,
 }
@@ -10,12 +10,37 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
-use std::{env, fs::read_to_string};
+// Without a backend, the VM is built but never run
+#![cfg_attr(not(feature = "gtk"), allow(dead_code))]
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env,
+    fmt::Write as _,
+    fs::{read_to_string, File},
+    io::{self, BufWriter, Write as _},
+    process,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
 mod cfg;
+mod clock;
+#[cfg(feature = "gtk")]
+#[macro_use]
+mod draw;
+mod input;
 mod ir;
 mod parse;
+mod replay;
+mod resource;
+#[cfg(feature = "gtk")]
 mod sys_gtk;
+#[cfg(feature = "gtk")]
+mod sys_terminal;
+#[cfg(feature = "wasm")]
+mod sys_wasm;
 mod vm;
 
 fn main() {
@@ -25,6 +50,28 @@ fn main() {
         let mut opts = getopts::Options::new();
         opts.optflag("", "pedantic", "");
         opts.optflagopt("", "std", "", "");
+        opts.optopt("", "queue-cap", "", "");
+        opts.optflag("", "keep-aspect", "");
+        opts.optopt("", "replay", "", "");
+        opts.optflag("", "dump-vars", "");
+        opts.optflag("", "run-shell", "");
+        opts.optflag("", "confirm-run", "");
+        opts.optopt("", "font", "", "");
+        opts.optopt("", "record", "", "");
+        opts.optopt("", "fps", "", "");
+        opts.optopt("", "backend", "", "");
+        opts.optopt("", "bg", "", "");
+        opts.optflag("", "signed-coords", "");
+        opts.optopt("", "max-canvas", "", "");
+        opts.optflag("", "interpolate", "");
+        opts.optflag("", "indented-labels", "");
+        opts.optflag("", "fake-clock", "");
+        opts.optflag("", "image-placeholder", "");
+        opts.optopt("", "resource-dir", "", "");
+        opts.optopt("", "max-fps", "", "");
+        opts.optopt("", "snapshot", "", "");
+        opts.optopt("", "trace-json", "", "");
+        opts.optflag("", "profile", "");
         opts
     };
 
@@ -33,20 +80,51 @@ fn main() {
         Err(e) => panic!("{}", e),
     };
 
+    let filename = if let Some(filename) = matches.free.first() {
+        filename.as_str()
+    } else {
+        eprintln!("Provide a source file.");
+        return;
+    };
+
     let src = {
-        let mut src = if !matches.free.is_empty() {
-            match read_to_string(matches.free[0].clone()) {
-                Ok(src) => src,
-                Err(e) => panic!("{}", e),
-            }
+        // A filename of "-" reads the source from stdin
+        let src = if filename == "-" {
+            io::read_to_string(io::stdin())
         } else {
-            println!("Provide a source file.");
-            return;
+            read_to_string(filename)
+        };
+        let mut src = match src {
+            Ok(src) => src,
+            Err(e) => panic!("{}", e),
         };
         src.push('\n');
         src
     };
 
+    let (font_name, font_size) = if let Some(font) = matches.opt_str("font") {
+        match font
+            .rsplit_once(':')
+            .map(|(name, size)| (name, size.parse()))
+        {
+            Some((name, Ok(size))) if size > 0. => (name.to_string(), size),
+            _ => panic!("Invalid font '{}'", font),
+        }
+    } else {
+        let config = cfg::Config::default();
+        (config.font_name, config.font_size)
+    };
+
+    let background_rgb = if let Some(bg) = matches.opt_str("bg") {
+        let components: Vec<_> = bg.split(',').map(|c| c.trim().parse::<u8>()).collect();
+        match components[..] {
+            [Ok(r), Ok(g), Ok(b)] => (r, g, b),
+            _ => panic!("Invalid background color '{}'", bg),
+        }
+    } else {
+        cfg::Config::default().background_rgb
+    };
+
     let config = cfg::Config {
         pedantic: matches.opt_present("pedantic"),
         standard: if let Some(standard) = matches.opt_str("std") {
@@ -57,21 +135,232 @@ fn main() {
         } else {
             cfg::Standard::default()
         },
+        queue_capacity: if let Some(capacity) = matches.opt_str("queue-cap") {
+            match capacity.parse() {
+                Ok(capacity) => capacity,
+                Err(_) => panic!("Invalid queue capacity '{}'", capacity),
+            }
+        } else {
+            cfg::Config::default().queue_capacity
+        },
+        keep_aspect: matches.opt_present("keep-aspect"),
+        replay: matches.opt_str("replay"),
+        dump_vars: matches.opt_present("dump-vars"),
+        run_shell: matches.opt_present("run-shell"),
+        confirm_run: matches.opt_present("confirm-run"),
+        font_name,
+        font_size,
+        record: matches.opt_str("record"),
+        record_fps: matches.opt_str("fps").map(|fps| match fps.parse() {
+            Ok(fps) => fps,
+            Err(_) => panic!("Invalid frame rate '{}'", fps),
+        }),
+        backend: if let Some(backend) = matches.opt_str("backend") {
+            match backend.as_str().try_into() {
+                Ok(backend) => backend,
+                Err(_) => panic!("Unrecognized backend '{}'", backend),
+            }
+        } else {
+            cfg::Backend::default()
+        },
+        background_rgb,
+        signed_coords: matches.opt_present("signed-coords"),
+        max_canvas_size: if let Some(size) = matches.opt_str("max-canvas") {
+            match size.parse() {
+                Ok(size) if size > 0 => size,
+                _ => panic!("Invalid maximum canvas size '{}'", size),
+            }
+        } else {
+            cfg::Config::default().max_canvas_size
+        },
+        indented_labels: matches.opt_present("indented-labels"),
+        interpolate: matches.opt_present("interpolate"),
+        fake_clock: matches.opt_present("fake-clock"),
+        image_placeholder: matches.opt_present("image-placeholder"),
+        resource_dir: matches.opt_str("resource-dir"),
+        max_fps: matches.opt_str("max-fps").map(|fps| match fps.parse() {
+            Ok(fps) if fps > 0 => fps,
+            _ => panic!("Invalid frame rate '{}'", fps),
+        }),
+        snapshot: matches.opt_str("snapshot"),
+        trace_json: matches.opt_str("trace-json"),
+        profile: matches.opt_present("profile"),
     };
 
     let prog = match ir::Program::from_src(&src, &config) {
-        Ok(prog) => prog,
+        Ok((prog, warnings)) => {
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            prog
+        }
         Err(e) => panic!("{}", e),
     };
 
-    let mut sys = match sys_gtk::VMSysGtk::new(&args[1]) {
-        Ok(sys) => sys,
+    let exit_code = run_backend(&prog, &config, filename);
+    process::exit(exit_code.min(255).into());
+}
+
+#[cfg(feature = "gtk")]
+fn run_backend<'a>(prog: &'a ir::Program<'a>, config: &'a cfg::Config, filename: &str) -> u16 {
+    let resources: Box<dyn resource::ResourceProvider> = match &config.resource_dir {
+        Some(dir) => Box::new(resource::DirResources::new(dir)),
+        None => Box::new(resource::EmbeddedResources),
+    };
+
+    match config.backend {
+        cfg::Backend::Gtk => match sys_gtk::VMSysGtk::new(
+            if filename == "-" { "stdin" } else { filename },
+            config,
+            resources,
+        ) {
+            Ok(mut sys) => run(prog, config, &mut sys),
+            Err(e) => panic!("{}", e),
+        },
+        cfg::Backend::Terminal => match sys_terminal::VMSysTerminal::new(config, resources) {
+            Ok(mut sys) => run(prog, config, &mut sys),
+            Err(e) => panic!("{}", e),
+        },
+    }
+}
+
+/// Built without the `gtk` feature, so the program has been checked but cannot be run
+#[cfg(not(feature = "gtk"))]
+fn run_backend<'a>(_: &'a ir::Program<'a>, _: &'a cfg::Config, _: &str) -> u16 {
+    panic!("Running programs requires building with the gtk feature");
+}
+
+fn run<'a, S: vm::VMSys<'a>>(
+    prog: &'a ir::Program<'a>,
+    config: &'a cfg::Config,
+    sys: &'a mut S,
+) -> u16 {
+    let mut vm = vm::VM::new(prog, config, sys);
+    let mut trace = config
+        .trace_json
+        .as_ref()
+        .map(|filename| match File::create(filename) {
+            Ok(file) => BufWriter::new(file),
+            Err(e) => panic!("{}", e),
+        });
+    let profile = config
+        .profile
+        .then(|| Rc::new(RefCell::new(Profile::new())));
+    if trace.is_some() || profile.is_some() {
+        let profile = profile.clone();
+        vm.set_step_hook(move |step| {
+            if let Some(trace) = &mut trace {
+                if let Err(e) = writeln!(trace, "{}", trace_json(step)) {
+                    panic!("Failed to write trace: {}", e);
+                }
+            }
+            if let Some(profile) = &profile {
+                profile.borrow_mut().record(step);
+            }
+        });
+    }
+    let exit_code = match vm.run() {
+        Ok(exit_code) => exit_code,
         Err(e) => panic!("{}", e),
     };
 
-    let mut vm = vm::VM::new(&prog, &config, &mut sys);
-    let res = vm.run();
-    if let Err(e) = res {
-        panic!("{}", e);
+    if let Some(profile) = profile {
+        profile.borrow().print(prog);
+    }
+
+    if config.dump_vars {
+        for (name, val) in vm.variables() {
+            println!("{}={}", name, val);
+        }
+        for (name, val) in vm.str_variables() {
+            println!("{}={}", name, val);
+        }
+    }
+
+    exit_code
+}
+
+/// Execution counts and cumulative time of each command
+struct Profile {
+    counts: HashMap<usize, (u64, Duration)>,
+    last_step: Instant,
+}
+
+impl Profile {
+    fn new() -> Self {
+        Self {
+            counts: HashMap::new(),
+            last_step: Instant::now(),
+        }
+    }
+
+    /// Attributes the time since the previous step to this one
+    fn record(&mut self, step: &vm::Step) {
+        let now = Instant::now();
+        let entry = self.counts.entry(step.ip).or_default();
+        entry.0 += 1;
+        entry.1 += now - self.last_step;
+        self.last_step = now;
+    }
+
+    /// Prints commands to stderr, most frequently executed first
+    fn print(&self, prog: &ir::Program) {
+        let mut counts: Vec<_> = self.counts.iter().collect();
+        counts.sort_by(|(ip1, (count1, _)), (ip2, (count2, _))| {
+            count2.cmp(count1).then(ip1.cmp(ip2))
+        });
+        eprintln!(
+            "{:>6} {:>6} {:<16} {:>10} {:>12}",
+            "LINE", "COL", "COMMAND", "COUNT", "TIME (ms)"
+        );
+        for (&ip, (count, time)) in counts {
+            let (line, col) = prog.locations[ip];
+            eprintln!(
+                "{:>6} {:>6} {:<16} {:>10} {:>12.3}",
+                line,
+                col,
+                prog.commands[ip].name(),
+                count,
+                time.as_secs_f64() * 1000.
+            );
+        }
+    }
+}
+
+/// Formats an executed command as a JSON object on one line
+fn trace_json(step: &vm::Step) -> String {
+    let mut json = format!(
+        "{{\"ip\":{},\"line\":{},\"col\":{},\"command\":\"{}\",\"args\":[",
+        step.ip,
+        step.line,
+        step.col,
+        step.command.name()
+    );
+    for (i, arg) in step.args.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        match arg {
+            vm::Value::Integer(val) => write!(json, "{}", val).unwrap(),
+            vm::Value::Str(val) => json_string(&mut json, val),
+        }
+    }
+    json.push_str("]}");
+    json
+}
+
+fn json_string(json: &mut String, s: &str) {
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
     }
+    json.push('"');
 }
@@ -10,10 +10,14 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
-use std::{collections::HashMap, fmt::Display};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
 
 use pest::{
     iterators::{Pair, Pairs},
+    pratt_parser::{Assoc, Op, PrattParser},
     Parser,
 };
 use pest_derive::Parser;
@@ -23,9 +27,9 @@ use thiserror::Error;
 use crate::{
     cfg,
     ir::{
-        self, BackgroundTransparency, BrushType, Coordinates, FontSlant, FontUnderline, FontWeight,
-        LogicalOperator, MathOperator, MessageBoxIcon, MessageBoxType, PenType, SetWindowOption,
-        WaitMode,
+        self, BackgroundTransparency, BrushType, Coordinates, CursorShape, FontSlant,
+        FontUnderline, FontWeight, LineCap, LineJoin, LogicalOperator, MathOperator,
+        MessageBoxIcon, MessageBoxType, PenType, SetWindowOption, WaitMode,
     },
 };
 
@@ -34,8 +38,8 @@ use crate::{
 struct OrielParser;
 
 macro_rules! next_pair {
-    ($pairs:expr) => {
-        ($pairs.next().as_ref().ok_or_else(|| Error::MissingArgError))
+    ($pairs:expr, $call:expr) => {
+        ($pairs.next().as_ref().ok_or_else(|| $call.missing_arg()))
     };
 }
 
@@ -69,8 +73,11 @@ fn str_lit_parse(s: &str) -> Option<&str> {
     }
 }
 
-fn next_pair_str_lit<'a>(pairs: &mut Pairs<'a, Rule>) -> Result<&'a str, Error<'a>> {
-    let pair = &(pairs.next().ok_or_else(|| Error::MissingArgError)?);
+fn next_pair_str_lit<'a>(
+    pairs: &mut Pairs<'a, Rule>,
+    call: &FuncCall<'a>,
+) -> Result<&'a str, Error<'a>> {
+    let pair = &(pairs.next().ok_or_else(|| call.missing_arg())?);
     if let Rule::string = pair.as_rule() {
         Ok(str_lit_parse(pair.as_str())
             .ok_or_else(|| Error::ArgTypeError(pair.into(), pair.as_str()))?)
@@ -79,16 +86,102 @@ fn next_pair_str_lit<'a>(pairs: &mut Pairs<'a, Rule>) -> Result<&'a str, Error<'
     }
 }
 
+fn next_pair_str_identifier<'a>(
+    pairs: &mut Pairs<'a, Rule>,
+    call: &FuncCall<'a>,
+) -> Result<ir::Identifier<'a>, Error<'a>> {
+    let pair = &(pairs.next().ok_or_else(|| call.missing_arg())?);
+    if let Rule::str_identifier = pair.as_rule() {
+        Ok(ir::Identifier(pair.as_str()))
+    } else {
+        Err(Error::ArgTypeError(pair.into(), pair.as_str()))
+    }
+}
+
 fn next_pair_set_menu_label<'a>(
     pairs: &mut Pairs<'a, Rule>,
+    call: &FuncCall<'a>,
 ) -> Result<Option<ir::Identifier<'a>>, Error<'a>> {
-    let pair = pairs.next().ok_or_else(|| Error::MissingArgError)?;
+    let pair = pairs.next().ok_or_else(|| call.missing_arg())?;
     Ok(match pair.as_str() {
         "IGNORE" => None,
         _ => Some((&pair).try_into()?),
     })
 }
 
+/// Parses the optional `CHECKED` or `UNCHECKED` following a menu member
+fn next_pair_menu_check(pairs: &mut Pairs<Rule>) -> Option<bool> {
+    let checked = match pairs.peek() {
+        Some(pair) if pair.as_str() == "CHECKED" => true,
+        Some(pair) if pair.as_str() == "UNCHECKED" => false,
+        _ => return None,
+    };
+    pairs.next();
+    Some(checked)
+}
+
+/// Parses `BOLD`, `NOBOLD`, or a numeric weight
+fn next_pair_font_weight<'a>(
+    pairs: &mut Pairs<'a, Rule>,
+    call: &FuncCall<'a>,
+) -> Result<ir::FontWeight, Error<'a>> {
+    let pair = pairs.next().ok_or_else(|| call.missing_arg())?;
+    if pair.as_rule() != Rule::integer {
+        return (&pair).try_into();
+    }
+    match pair.as_str().parse() {
+        Ok(weight @ 100..=900) => Ok(ir::FontWeight::Weight(weight)),
+        _ => Err(Error::FontWeightError((&pair).into(), pair.as_str())),
+    }
+}
+
+/// Parses the optional cursor shape following a mouse region
+fn next_pair_mouse_cursor<'a>(
+    pairs: &mut Pairs<'a, Rule>,
+) -> Result<Option<ir::CursorShape>, Error<'a>> {
+    match pairs.peek() {
+        Some(pair) if pair.as_rule() == Rule::token => (),
+        _ => return Ok(None),
+    };
+    let pair = pairs.next().unwrap();
+    Ok(Some((&pair).try_into()?))
+}
+
+/// Parses the optional `ACCEL` and key combination, such as `"Ctrl+S"`, following a menu member
+fn next_pair_menu_accel<'a>(
+    pairs: &mut Pairs<'a, Rule>,
+    call: &FuncCall<'a>,
+) -> Result<Option<ir::Accelerator<'a>>, Error<'a>> {
+    match pairs.peek() {
+        Some(pair) if pair.as_str() == "ACCEL" => (),
+        _ => return Ok(None),
+    };
+    pairs.next();
+
+    let pair = pairs.next().ok_or_else(|| call.missing_arg())?;
+    let spec = str_lit_parse(pair.as_str())
+        .ok_or_else(|| Error::ArgTypeError((&pair).into(), pair.as_str()))?;
+    let (modifiers, key) = spec.rsplit_once('+').unwrap_or(("", spec));
+    let mut accelerator = ir::Accelerator {
+        key,
+        ctrl: false,
+        shift: false,
+        alt: false,
+    };
+    for modifier in modifiers.split('+').filter(|modifier| !modifier.is_empty()) {
+        match modifier.to_lowercase().as_str() {
+            "ctrl" => accelerator.ctrl = true,
+            "shift" => accelerator.shift = true,
+            "alt" => accelerator.alt = true,
+            _ => return Err(Error::AcceleratorError((&pair).into(), spec)),
+        }
+    }
+    if key.is_empty() {
+        return Err(Error::AcceleratorError((&pair).into(), spec));
+    }
+    Ok(Some(accelerator))
+}
+
 enum_impl_from_str!(
     LogicalOperator,
     (Equal, "="),
@@ -151,7 +244,20 @@ enum_impl_from_str!(
 
 enum_impl_from_str!(Coordinates, (Pixel, "PIXEL"), (Metric, "METRIC"));
 
-enum_impl_from_str!(WaitMode, (Null, "NULL"), (Focus, "FOCUS"));
+enum_impl_from_str!(
+    CursorShape,
+    (Arrow, "ARROW"),
+    (Cross, "CROSS"),
+    (Hand, "HAND"),
+    (Null, "NULL")
+);
+
+enum_impl_from_str!(
+    WaitMode,
+    (Null, "NULL"),
+    (Focus, "FOCUS"),
+    (AnyKey, "ANYKEY")
+);
 
 enum_impl_from_str!(
     PenType,
@@ -163,6 +269,20 @@ enum_impl_from_str!(
     (DashDotDot, "DASHDOTDOT")
 );
 
+enum_impl_from_str!(
+    LineCap,
+    (Butt, "BUTT"),
+    (Round, "ROUND"),
+    (Square, "SQUARE")
+);
+
+enum_impl_from_str!(
+    LineJoin,
+    (Miter, "MITER"),
+    (Round, "ROUND"),
+    (Bevel, "BEVEL")
+);
+
 enum_impl_from_str!(FontWeight, (Bold, "BOLD"), (NoBold, "NOBOLD"));
 
 enum_impl_from_str!(FontSlant, (Italic, "ITALIC"), (NoItalic, "NOITALIC"));
@@ -187,10 +307,7 @@ impl<'a> TryFrom<&Pair<'a, Rule>> for ir::PhysicalKey {
                 {
                     Err(Error::InvalidPhysicalKeyError(s))
                 } else {
-                    Ok(ir::PhysicalKey {
-                        chr: c,
-                        ctrl: len == 4,
-                    })
+                    Ok(ir::PhysicalKey::new(c, len == 4))
                 }
             }
             _ => Err(Error::InvalidPhysicalKeyError(s)),
@@ -222,15 +339,34 @@ impl<'a> TryFrom<&Pair<'a, Rule>> for ir::Identifier<'a> {
     }
 }
 
+impl<'a> TryFrom<&Pair<'a, Rule>> for ir::Str<'a> {
+    type Error = Error<'a>;
+
+    fn try_from(pair: &Pair<'a, Rule>) -> Result<ir::Str<'a>, Self::Error> {
+        match pair.as_rule() {
+            Rule::string => Ok(ir::Str::Literal(str_lit_parse(pair.as_str()).unwrap())),
+            Rule::str_identifier => Ok(ir::Str::Variable(ir::Identifier(pair.as_str()))),
+            _ => Err(Error::ArgTypeError(pair.into(), pair.as_str())),
+        }
+    }
+}
+
 impl<'a> TryFrom<&Pair<'a, Rule>> for ir::Integer<'a> {
     type Error = Error<'a>;
 
     fn try_from(pair: &Pair<'a, Rule>) -> Result<ir::Integer<'a>, Self::Error> {
         match pair.as_rule() {
             Rule::integer => {
-                Ok(ir::Integer::Literal(pair.as_str().parse::<u16>().map_err(
-                    |_| Self::Error::ParseIntError(pair.into(), pair.as_str()),
-                )?))
+                let s = pair.as_str();
+                // Negative literals are stored in two's complement
+                let val = if s.starts_with('-') {
+                    s.parse::<i16>().map(|val| val as u16)
+                } else {
+                    s.parse::<u16>()
+                };
+                Ok(ir::Integer::Literal(val.map_err(|_| {
+                    Self::Error::ParseIntError(pair.into(), pair.as_str())
+                })?))
             }
             Rule::identifier => Ok(ir::Integer::Variable(ir::Identifier(pair.as_str()))),
             _ => Err(Error::ArgTypeError(pair.into(), pair.as_str())),
@@ -238,7 +374,21 @@ impl<'a> TryFrom<&Pair<'a, Rule>> for ir::Integer<'a> {
     }
 }
 
-#[derive(Debug)]
+/// Command whose arguments are being parsed
+struct FuncCall<'a> {
+    loc: ErrorLoc,
+    name: &'a str,
+    /// Number of arguments passed
+    argc: usize,
+}
+
+impl<'a> FuncCall<'a> {
+    fn missing_arg(&self) -> Error<'a> {
+        Error::MissingArgError(self.loc, self.name, self.argc + 1)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
 pub struct ErrorLoc {
     line: usize,
     col: usize,
@@ -264,20 +414,58 @@ pub enum Error<'a> {
     ParseIntError(ErrorLoc, &'a str),
     #[error("{}", .0)]
     PestParseError(Box<pest::error::Error<Rule>>),
-    #[error("Expected another argument")]
-    MissingArgError,
+    #[error("{} Command '{}' is missing argument {}", .0, .1, .2)]
+    MissingArgError(ErrorLoc, &'a str, usize),
     #[error("{} Failed to match token '{}'", .0, .1)]
     MatchTokenError(ErrorLoc, &'a str),
     #[error("{} Label '{}' is not at line start", .0, .1)]
     LabelIndentationError(ErrorLoc, &'a str),
-    #[error("{} Command '{}' has too many arguments", .0, .1)]
-    ExtraneousArgError(ErrorLoc, &'a str),
+    #[error("{} Command '{}' has too many arguments, expected {}", .0, .1, .2)]
+    ExtraneousArgError(ErrorLoc, &'a str, usize),
     #[error("{} Argument '{}' has incorrect type", .0, .1)]
     ArgTypeError(ErrorLoc, &'a str),
     #[error("Number of labels exceeds 500")]
     ExcessLabelsError,
     #[error("Physical key '{}' is invalid", .0)]
     InvalidPhysicalKeyError(&'a str),
+    #[error("{} '{}' is not supported by standard {}", .0, .1, .2)]
+    StandardUnsupportedError(ErrorLoc, &'a str, cfg::Standard),
+    #[error("{} Invalid accelerator '{}'", .0, .1)]
+    AcceleratorError(ErrorLoc, &'a str),
+    #[error("{} Font weight '{}' is not between 100 and 900", .0, .1)]
+    FontWeightError(ErrorLoc, &'a str),
+    #[error("{} ELSEIF without matching IF", .0)]
+    UnmatchedElseIfError(ErrorLoc),
+    #[error("{} FOR without matching NEXT", .0)]
+    UnclosedForError(ErrorLoc),
+    #[error("{} NEXT without matching FOR", .0)]
+    UnmatchedNextError(ErrorLoc),
+    #[error("{} NEXT variable '{}' does not match FOR", .0, .1)]
+    NextVariableError(ErrorLoc, &'a str),
+    #[error("{} WHILE without matching WEND", .0)]
+    UnclosedWhileError(ErrorLoc),
+    #[error("{} WEND without matching WHILE", .0)]
+    UnmatchedWendError(ErrorLoc),
+    #[error("{} NEXT closes the WHILE at {}:{}, which needs a WEND first", .0, .1.line, .1.col)]
+    NextClosesWhileError(ErrorLoc, ErrorLoc),
+    #[error("{} WEND closes the FOR at {}:{}, which needs a NEXT first", .0, .1.line, .1.col)]
+    WendClosesForError(ErrorLoc, ErrorLoc),
+    #[error("{}", .0.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("\n"))]
+    MultipleError(Vec<Error<'a>>),
+    #[error("{} Label '{}' does not exist", .0, .1)]
+    NonexistentLabelError(ErrorLoc, &'a str),
+    #[error("{} Unreachable code", .0)]
+    UnreachableCodeError(ErrorLoc),
+    #[error("{} Unknown command '{}'{}", .0, .1, .2.map(|s| format!(", did you mean '{}'?", s)).unwrap_or_default())]
+    UnknownCommandError(ErrorLoc, &'a str, Option<&'static str>),
+}
+
+/// Non-fatal issues found while parsing
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Warning<'a> {
+    #[error("{} Label '{}' is never jumped to", .0, .1)]
+    UnusedLabelWarning(ErrorLoc, &'a str),
 }
 
 impl From<pest::error::Error<Rule>> for Error<'_> {
@@ -286,120 +474,314 @@ impl From<pest::error::Error<Rule>> for Error<'_> {
     }
 }
 
+const COMMAND_NAMES: &[&str] = &[
+    "Beep",
+    "ClearTransparentColor",
+    "DrawArc",
+    "DrawBackground",
+    "DrawBitmap",
+    "DrawChord",
+    "DrawEllipse",
+    "DrawFlood",
+    "DrawLine",
+    "DrawNumber",
+    "DrawPie",
+    "DrawRectangle",
+    "DrawRoundRectangle",
+    "DrawSizedBitmap",
+    "DrawText",
+    "End",
+    "Exit",
+    "GetEnv",
+    "GetTicks",
+    "GetTime",
+    "Locate",
+    "MessageBeep",
+    "MessageBox",
+    "OnClick",
+    "OutputLn",
+    "PollInput",
+    "Print",
+    "ProgressEnd",
+    "ProgressSet",
+    "ProgressStart",
+    "RestoreState",
+    "Return",
+    "Run",
+    "SaveState",
+    "SetCanvasSize",
+    "SetCursor",
+    "SetKeyboard",
+    "SetMenu",
+    "SetMenuCheck",
+    "SetMenuEnabled",
+    "SetMouse",
+    "SetNumberBase",
+    "SetNumberFormat",
+    "SetPenStyle",
+    "SetStatus",
+    "SetTimer",
+    "SetTransparentColor",
+    "SetWaitMode",
+    "SetWindow",
+    "Str",
+    "StrLen",
+    "SubStr",
+    "TextWidth",
+    "UseBackground",
+    "UseBrush",
+    "UseCaption",
+    "UseCoordinates",
+    "UseFont",
+    "UsePen",
+    "Val",
+    "WaitInput",
+];
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = (prev + usize::from(ca != cb)).min(row[j] + 1).min(cur + 1);
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+/// Closest command name to a misspelled one, if it is within two edits.
+fn suggest_command(name: &str) -> Option<&'static str> {
+    let name = name.to_lowercase();
+    COMMAND_NAMES
+        .iter()
+        .map(|&command| (levenshtein(&name, &command.to_lowercase()), command))
+        .filter(|&(distance, _)| distance <= 2)
+        .min()
+        .map(|(_, command)| command)
+}
+
+/// First part of `command` that is an extension of this interpreter rather than part of the
+/// language for Windows 3, if any.
+fn find_extension<'a>(command: &Pair<'a, Rule>) -> Option<Pair<'a, Rule>> {
+    if command.as_rule() == Rule::command_func {
+        let mut args = command.clone().into_inner();
+        let name = args.next().unwrap().as_str().to_lowercase();
+        let mut prev_rule = None;
+        for (idx, arg) in args.enumerate() {
+            let extended = match (name.as_str(), arg.as_rule()) {
+                // Numeric weights
+                ("usefont", Rule::integer) => idx == 3,
+                // Cursor shapes following a region
+                ("setmouse", Rule::token) => true,
+                // Check marks and accelerators following the label of a member
+                ("setmenu", Rule::identifier) => {
+                    matches!(arg.as_str(), "CHECKED" | "UNCHECKED" | "ACCEL")
+                        && prev_rule != Some(Rule::string)
+                }
+                _ => false,
+            };
+            if extended {
+                return Some(arg);
+            }
+            prev_rule = Some(arg.as_rule());
+        }
+    }
+
+    std::iter::once(command.clone())
+        .chain(command.clone().into_inner().flatten())
+        .find(|pair| match pair.as_rule() {
+            Rule::kword_command_func_ext
+            | Rule::kword_command_nfunc_ext
+            | Rule::kword_elseif
+            | Rule::kword_for
+            | Rule::kword_next
+            | Rule::kword_while
+            | Rule::kword_wend
+            | Rule::logical_connective
+            | Rule::math_function
+            | Rule::str_identifier => true,
+            Rule::integer => pair.as_str().starts_with('-'),
+            Rule::token => pair.as_str() == "ANYKEY",
+            // Anything beyond a single operation
+            Rule::expression => {
+                let mut terms = pair.clone().into_inner();
+                terms.clone().count() > 3 || terms.any(|term| term.as_rule() == Rule::expression)
+            }
+            _ => false,
+        })
+}
+
 impl<'a> ir::Command<'a> {
-    fn from_keyword(command: &Pair<'a, Rule>) -> ir::Command<'a> {
-        match command.as_str().to_lowercase().as_str() {
+    fn try_from_keyword(command: &Pair<'a, Rule>) -> Result<ir::Command<'a>, Error<'a>> {
+        Ok(match command.as_str().to_lowercase().as_str() {
             "beep" => ir::Command::Beep,
+            "cleartransparentcolor" => ir::Command::ClearTransparentColor,
             "drawbackground" => ir::Command::DrawBackground,
             "end" => ir::Command::End,
+            "pollinput" => ir::Command::PollInput,
+            "progressend" => ir::Command::ProgressEnd,
+            "restorestate" => ir::Command::RestoreState,
             "return" => ir::Command::Return,
+            "savestate" => ir::Command::SaveState,
             _ => unreachable!(),
-        }
+        })
     }
 
     fn try_from_func(kwords: &mut Pairs<'a, Rule>) -> Result<ir::Command<'a>, Error<'a>> {
-        let fname = next_pair_unchecked!(kwords).as_str();
+        let fname = &kwords.next().unwrap();
+        let call = FuncCall {
+            loc: fname.into(),
+            name: fname.as_str(),
+            argc: kwords.clone().count(),
+        };
+        let fname = fname.as_str();
         let command = match fname.to_lowercase().as_str() {
             "drawarc" => ir::Command::DrawArc {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
-                x3: next_pair!(kwords)?.try_into()?,
-                y3: next_pair!(kwords)?.try_into()?,
-                x4: next_pair!(kwords)?.try_into()?,
-                y4: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
+                x3: next_pair!(kwords, call)?.try_into()?,
+                y3: next_pair!(kwords, call)?.try_into()?,
+                x4: next_pair!(kwords, call)?.try_into()?,
+                y4: next_pair!(kwords, call)?.try_into()?,
             },
             "drawbitmap" => ir::Command::DrawBitmap {
-                x: next_pair!(kwords)?.try_into()?,
-                y: next_pair!(kwords)?.try_into()?,
-                filename: next_pair_str_lit(kwords)?,
+                x: next_pair!(kwords, call)?.try_into()?,
+                y: next_pair!(kwords, call)?.try_into()?,
+                filename: next_pair!(kwords, call)?.try_into()?,
             },
             "drawchord" => ir::Command::DrawChord {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
-                x3: next_pair!(kwords)?.try_into()?,
-                y3: next_pair!(kwords)?.try_into()?,
-                x4: next_pair!(kwords)?.try_into()?,
-                y4: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
+                x3: next_pair!(kwords, call)?.try_into()?,
+                y3: next_pair!(kwords, call)?.try_into()?,
+                x4: next_pair!(kwords, call)?.try_into()?,
+                y4: next_pair!(kwords, call)?.try_into()?,
             },
             "drawellipse" => ir::Command::DrawEllipse {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
             },
             "drawflood" => ir::Command::DrawFlood {
-                x: next_pair!(kwords)?.try_into()?,
-                y: next_pair!(kwords)?.try_into()?,
-                r: next_pair!(kwords)?.try_into()?,
-                g: next_pair!(kwords)?.try_into()?,
-                b: next_pair!(kwords)?.try_into()?,
+                x: next_pair!(kwords, call)?.try_into()?,
+                y: next_pair!(kwords, call)?.try_into()?,
+                r: next_pair!(kwords, call)?.try_into()?,
+                g: next_pair!(kwords, call)?.try_into()?,
+                b: next_pair!(kwords, call)?.try_into()?,
             },
             "drawline" => ir::Command::DrawLine {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
             },
             "drawnumber" => ir::Command::DrawNumber {
-                x: next_pair!(kwords)?.try_into()?,
-                y: next_pair!(kwords)?.try_into()?,
-                n: next_pair!(kwords)?.try_into()?,
+                x: next_pair!(kwords, call)?.try_into()?,
+                y: next_pair!(kwords, call)?.try_into()?,
+                n: next_pair!(kwords, call)?.try_into()?,
             },
             "drawpie" => ir::Command::DrawPie {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
-                x3: next_pair!(kwords)?.try_into()?,
-                y3: next_pair!(kwords)?.try_into()?,
-                x4: next_pair!(kwords)?.try_into()?,
-                y4: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
+                x3: next_pair!(kwords, call)?.try_into()?,
+                y3: next_pair!(kwords, call)?.try_into()?,
+                x4: next_pair!(kwords, call)?.try_into()?,
+                y4: next_pair!(kwords, call)?.try_into()?,
             },
             "drawrectangle" => ir::Command::DrawRectangle {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
             },
             "drawroundrectangle" => ir::Command::DrawRoundRectangle {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
-                x3: next_pair!(kwords)?.try_into()?,
-                y3: next_pair!(kwords)?.try_into()?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
+                x3: next_pair!(kwords, call)?.try_into()?,
+                y3: next_pair!(kwords, call)?.try_into()?,
             },
             "drawsizedbitmap" => ir::Command::DrawSizedBitmap {
-                x1: next_pair!(kwords)?.try_into()?,
-                y1: next_pair!(kwords)?.try_into()?,
-                x2: next_pair!(kwords)?.try_into()?,
-                y2: next_pair!(kwords)?.try_into()?,
-                filename: next_pair_str_lit(kwords)?,
+                x1: next_pair!(kwords, call)?.try_into()?,
+                y1: next_pair!(kwords, call)?.try_into()?,
+                x2: next_pair!(kwords, call)?.try_into()?,
+                y2: next_pair!(kwords, call)?.try_into()?,
+                filename: next_pair!(kwords, call)?.try_into()?,
             },
             "drawtext" => ir::Command::DrawText {
-                x: next_pair!(kwords)?.try_into()?,
-                y: next_pair!(kwords)?.try_into()?,
-                text: next_pair_str_lit(kwords)?,
+                x: next_pair!(kwords, call)?.try_into()?,
+                y: next_pair!(kwords, call)?.try_into()?,
+                text: next_pair!(kwords, call)?.try_into()?,
+            },
+            "exit" => ir::Command::Exit {
+                code: next_pair!(kwords, call)?.try_into()?,
             },
+            "getenv" => ir::Command::GetEnv {
+                name: next_pair!(kwords, call)?.try_into()?,
+                result: next_pair_str_identifier(kwords, &call)?,
+            },
+            "getticks" => ir::Command::GetTicks {
+                result: next_pair!(kwords, call)?.try_into()?,
+            },
+            "gettime" => ir::Command::GetTime {
+                hour: next_pair!(kwords, call)?.try_into()?,
+                minute: next_pair!(kwords, call)?.try_into()?,
+                second: next_pair!(kwords, call)?.try_into()?,
+            },
+            "locate" => ir::Command::Locate {
+                x: next_pair!(kwords, call)?.try_into()?,
+                y: next_pair!(kwords, call)?.try_into()?,
+            },
+            "messagebeep" => ir::Command::MessageBeep(next_pair!(kwords, call)?.try_into()?),
             "messagebox" => ir::Command::MessageBox {
-                typ: next_pair!(kwords)?.try_into()?,
-                default_button: next_pair!(kwords)?.try_into()?,
-                icon: next_pair!(kwords)?.try_into()?,
-                text: next_pair_str_lit(kwords)?,
-                caption: next_pair_str_lit(kwords)?,
-                button_pushed: next_pair!(kwords)?.try_into()?,
-            },
-            "run" => ir::Command::Run(next_pair_str_lit(kwords)?),
+                typ: next_pair!(kwords, call)?.try_into()?,
+                default_button: next_pair!(kwords, call)?.try_into()?,
+                icon: next_pair!(kwords, call)?.try_into()?,
+                text: next_pair!(kwords, call)?.try_into()?,
+                caption: next_pair!(kwords, call)?.try_into()?,
+                button_pushed: next_pair!(kwords, call)?.try_into()?,
+            },
+            "onclick" => ir::Command::OnClick(if kwords.peek().is_some() {
+                Some(ir::MouseCallbacks {
+                    label: next_pair!(kwords, call)?.try_into()?,
+                    x: next_pair!(kwords, call)?.try_into()?,
+                    y: next_pair!(kwords, call)?.try_into()?,
+                })
+            } else {
+                None
+            }),
+            "outputln" => ir::Command::OutputLn(next_pair!(kwords, call)?.try_into()?),
+            "print" => ir::Command::Print(next_pair!(kwords, call)?.try_into()?),
+            "progressset" => ir::Command::ProgressSet {
+                value: next_pair!(kwords, call)?.try_into()?,
+            },
+            "progressstart" => ir::Command::ProgressStart {
+                max: next_pair!(kwords, call)?.try_into()?,
+            },
+            "run" => ir::Command::Run(next_pair!(kwords, call)?.try_into()?),
+            "setcanvassize" => ir::Command::SetCanvasSize {
+                width: next_pair!(kwords, call)?.try_into()?,
+                height: next_pair!(kwords, call)?.try_into()?,
+            },
+            "setcursor" => ir::Command::SetCursor(next_pair!(kwords, call)?.try_into()?),
             "setkeyboard" => ir::Command::SetKeyboard({
                 let mut params: HashMap<ir::Key, ir::Identifier> = HashMap::new();
                 while kwords.peek().is_some() {
                     params.insert(
-                        next_pair!(kwords)?.try_into()?,
-                        next_pair!(kwords)?.try_into()?,
+                        next_pair!(kwords, call)?.try_into()?,
+                        next_pair!(kwords, call)?.try_into()?,
                     );
                 }
                 params
@@ -409,13 +791,15 @@ impl<'a> ir::Command<'a> {
                 while kwords.peek().is_some() {
                     items.push(ir::MenuCategory {
                         item: ir::MenuItem {
-                            name: next_pair_str_lit(kwords)?,
-                            label: next_pair_set_menu_label(kwords)?,
+                            name: next_pair_str_lit(kwords, &call)?,
+                            label: next_pair_set_menu_label(kwords, &call)?,
+                            checked: None,
+                            accelerator: None,
                         },
                         members: {
                             let mut members = Vec::new();
                             loop {
-                                let pair = kwords.next().ok_or_else(|| Error::MissingArgError)?;
+                                let pair = kwords.next().ok_or_else(|| call.missing_arg())?;
                                 members.push(match pair.as_str() {
                                     "ENDPOPUP" => break,
                                     "SEPARATOR" => ir::MenuMember::Separator,
@@ -423,7 +807,9 @@ impl<'a> ir::Command<'a> {
                                         name: str_lit_parse(s).ok_or_else(|| {
                                             Error::ArgTypeError((&pair).into(), pair.as_str())
                                         })?,
-                                        label: next_pair_set_menu_label(kwords)?,
+                                        label: next_pair_set_menu_label(kwords, &call)?,
+                                        checked: next_pair_menu_check(kwords),
+                                        accelerator: next_pair_menu_accel(kwords, &call)?,
                                     }),
                                 });
                             }
@@ -433,56 +819,110 @@ impl<'a> ir::Command<'a> {
                 }
                 ir::Command::SetMenu(items)
             }
+            "setmenucheck" => ir::Command::SetMenuCheck {
+                label: next_pair!(kwords, call)?.try_into()?,
+                on: next_pair!(kwords, call)?.try_into()?,
+            },
+            "setmenuenabled" => ir::Command::SetMenuEnabled {
+                label: next_pair!(kwords, call)?.try_into()?,
+                enabled: next_pair!(kwords, call)?.try_into()?,
+            },
             "setmouse" => ir::Command::SetMouse({
                 let mut params: Vec<ir::MouseRegion> = Vec::new();
                 while kwords.peek().is_some() {
                     params.push(ir::MouseRegion {
-                        x1: next_pair!(kwords)?.try_into()?,
-                        y1: next_pair!(kwords)?.try_into()?,
-                        x2: next_pair!(kwords)?.try_into()?,
-                        y2: next_pair!(kwords)?.try_into()?,
+                        x1: next_pair!(kwords, call)?.try_into()?,
+                        y1: next_pair!(kwords, call)?.try_into()?,
+                        x2: next_pair!(kwords, call)?.try_into()?,
+                        y2: next_pair!(kwords, call)?.try_into()?,
                         callbacks: ir::MouseCallbacks {
-                            label: next_pair!(kwords)?.try_into()?,
-                            x: next_pair!(kwords)?.try_into()?,
-                            y: next_pair!(kwords)?.try_into()?,
+                            label: next_pair!(kwords, call)?.try_into()?,
+                            x: next_pair!(kwords, call)?.try_into()?,
+                            y: next_pair!(kwords, call)?.try_into()?,
                         },
+                        cursor: next_pair_mouse_cursor(kwords)?,
                     });
                 }
                 params
             }),
-            "setwaitmode" => ir::Command::SetWaitMode(next_pair!(kwords)?.try_into()?),
-            "setwindow" => ir::Command::SetWindow(next_pair!(kwords)?.try_into()?),
+            "setnumberbase" => ir::Command::SetNumberBase(next_pair!(kwords, call)?.try_into()?),
+            "setnumberformat" => ir::Command::SetNumberFormat {
+                width: next_pair!(kwords, call)?.try_into()?,
+                pad: next_pair!(kwords, call)?.try_into()?,
+            },
+            "setpenstyle" => ir::Command::SetPenStyle {
+                cap: next_pair!(kwords, call)?.try_into()?,
+                join: next_pair!(kwords, call)?.try_into()?,
+            },
+            "setstatus" => ir::Command::SetStatus(next_pair!(kwords, call)?.try_into()?),
+            "settimer" => ir::Command::SetTimer {
+                milliseconds: next_pair!(kwords, call)?.try_into()?,
+                label: if let Some(ref label) = kwords.next() {
+                    Some(label.try_into()?)
+                } else {
+                    None
+                },
+            },
+            "settransparentcolor" => ir::Command::SetTransparentColor {
+                r: next_pair!(kwords, call)?.try_into()?,
+                g: next_pair!(kwords, call)?.try_into()?,
+                b: next_pair!(kwords, call)?.try_into()?,
+            },
+            "setwaitmode" => ir::Command::SetWaitMode(next_pair!(kwords, call)?.try_into()?),
+            "setwindow" => ir::Command::SetWindow(next_pair!(kwords, call)?.try_into()?),
+            "str" => ir::Command::Str {
+                value: next_pair!(kwords, call)?.try_into()?,
+                result: next_pair_str_identifier(kwords, &call)?,
+            },
+            "strlen" => ir::Command::StrLen {
+                text: next_pair!(kwords, call)?.try_into()?,
+                result: next_pair!(kwords, call)?.try_into()?,
+            },
+            "substr" => ir::Command::SubStr {
+                text: next_pair!(kwords, call)?.try_into()?,
+                start: next_pair!(kwords, call)?.try_into()?,
+                count: next_pair!(kwords, call)?.try_into()?,
+                result: next_pair_str_identifier(kwords, &call)?,
+            },
+            "textwidth" => ir::Command::TextWidth {
+                text: next_pair!(kwords, call)?.try_into()?,
+                result: next_pair!(kwords, call)?.try_into()?,
+            },
             "usebackground" => ir::Command::UseBackground {
-                option: next_pair!(kwords)?.try_into()?,
-                r: next_pair!(kwords)?.try_into()?,
-                g: next_pair!(kwords)?.try_into()?,
-                b: next_pair!(kwords)?.try_into()?,
+                option: next_pair!(kwords, call)?.try_into()?,
+                r: next_pair!(kwords, call)?.try_into()?,
+                g: next_pair!(kwords, call)?.try_into()?,
+                b: next_pair!(kwords, call)?.try_into()?,
             },
             "usebrush" => ir::Command::UseBrush {
-                option: next_pair!(kwords)?.try_into()?,
-                r: next_pair!(kwords)?.try_into()?,
-                g: next_pair!(kwords)?.try_into()?,
-                b: next_pair!(kwords)?.try_into()?,
+                option: next_pair!(kwords, call)?.try_into()?,
+                r: next_pair!(kwords, call)?.try_into()?,
+                g: next_pair!(kwords, call)?.try_into()?,
+                b: next_pair!(kwords, call)?.try_into()?,
             },
-            "usecaption" => ir::Command::UseCaption(next_pair_str_lit(kwords)?),
-            "usecoordinates" => ir::Command::UseCoordinates(next_pair!(kwords)?.try_into()?),
+            "usecaption" => ir::Command::UseCaption(next_pair!(kwords, call)?.try_into()?),
+            "usecoordinates" => ir::Command::UseCoordinates(next_pair!(kwords, call)?.try_into()?),
             "usefont" => ir::Command::UseFont {
-                name: next_pair_str_lit(kwords)?,
-                width: next_pair!(kwords)?.try_into()?,
-                height: next_pair!(kwords)?.try_into()?,
-                bold: next_pair!(kwords)?.try_into()?,
-                italic: next_pair!(kwords)?.try_into()?,
-                underline: next_pair!(kwords)?.try_into()?,
-                r: next_pair!(kwords)?.try_into()?,
-                g: next_pair!(kwords)?.try_into()?,
-                b: next_pair!(kwords)?.try_into()?,
+                name: next_pair_str_lit(kwords, &call)?,
+                width: next_pair!(kwords, call)?.try_into()?,
+                height: next_pair!(kwords, call)?.try_into()?,
+                bold: next_pair_font_weight(kwords, &call)?,
+                italic: next_pair!(kwords, call)?.try_into()?,
+                underline: next_pair!(kwords, call)?.try_into()?,
+                r: next_pair!(kwords, call)?.try_into()?,
+                g: next_pair!(kwords, call)?.try_into()?,
+                b: next_pair!(kwords, call)?.try_into()?,
             },
             "usepen" => ir::Command::UsePen {
-                option: next_pair!(kwords)?.try_into()?,
-                width: next_pair!(kwords)?.try_into()?,
-                r: next_pair!(kwords)?.try_into()?,
-                g: next_pair!(kwords)?.try_into()?,
-                b: next_pair!(kwords)?.try_into()?,
+                option: next_pair!(kwords, call)?.try_into()?,
+                width: next_pair!(kwords, call)?.try_into()?,
+                r: next_pair!(kwords, call)?.try_into()?,
+                g: next_pair!(kwords, call)?.try_into()?,
+                b: next_pair!(kwords, call)?.try_into()?,
+            },
+            "val" => ir::Command::Val {
+                text: next_pair!(kwords, call)?.try_into()?,
+                result: next_pair!(kwords, call)?.try_into()?,
             },
             "waitinput" => ir::Command::WaitInput(if let Some(ref milliseconds) = kwords.next() {
                 Some(milliseconds.try_into()?)
@@ -493,106 +933,786 @@ impl<'a> ir::Command<'a> {
         };
 
         if let Some(ref pair) = kwords.next() {
-            Err(Error::ExtraneousArgError(pair.into(), fname))
+            // Every argument before the first extraneous one was consumed
+            Err(Error::ExtraneousArgError(
+                pair.into(),
+                fname,
+                call.argc - kwords.count() - 1,
+            ))
         } else {
             Ok(command)
         }
     }
 }
 
+/// Loop awaiting its closing command, with the index of the IF testing its condition
+enum Block<'a> {
+    For {
+        var: ir::Identifier<'a>,
+        end: ir::Integer<'a>,
+        step: ir::Integer<'a>,
+        descending: bool,
+        test_idx: usize,
+        loc: ErrorLoc,
+    },
+    While {
+        start_idx: usize,
+        /// Tests jumping past the loop once false
+        tests: Vec<usize>,
+        loc: ErrorLoc,
+    },
+}
+
+/// Parses math expressions, where `*` and `/` bind tighter than `+` and `-`
+fn expression_parser() -> PrattParser<Rule> {
+    PrattParser::new()
+        .op(Op::infix(Rule::add, Assoc::Left) | Op::infix(Rule::subtract, Assoc::Left))
+        .op(Op::infix(Rule::multiply, Assoc::Left) | Op::infix(Rule::divide, Assoc::Left))
+}
+
+fn expression_parse<'a>(
+    pairs: Pairs<'a, Rule>,
+    parser: &PrattParser<Rule>,
+) -> Result<ir::SetValue<'a>, Error<'a>> {
+    parser
+        .map_primary(|primary| match primary.as_rule() {
+            Rule::expression => expression_parse(primary.into_inner(), parser),
+            Rule::math_function => math_function_parse(primary, parser),
+            _ => Ok(ir::SetValue::Value((&primary).try_into()?)),
+        })
+        .map_infix(|i1, op, i2| {
+            Ok(ir::SetValue::Expression {
+                i1: Box::new(i1?),
+                op: (&op).try_into()?,
+                i2: Box::new(i2?),
+            })
+        })
+        .parse(pairs)
+}
+
+/// Parses `Abs`, `Clamp`, `Max`, or `Min`, lowering `Clamp` to a `Max` within a `Min`
+fn math_function_parse<'a>(
+    pair: Pair<'a, Rule>,
+    parser: &PrattParser<Rule>,
+) -> Result<ir::SetValue<'a>, Error<'a>> {
+    let mut inner = pair.clone().into_inner();
+    let name = inner.next().unwrap().as_str();
+    let argc = match name.to_lowercase().as_str() {
+        "abs" => 1,
+        "clamp" => 3,
+        _ => 2,
+    };
+    let mut args = Vec::with_capacity(argc);
+    for arg in inner {
+        if args.len() == argc {
+            return Err(Error::ExtraneousArgError((&arg).into(), name, argc));
+        }
+        args.push(Box::new(expression_parse(arg.into_inner(), parser)?));
+    }
+    if args.len() < argc {
+        return Err(Error::MissingArgError((&pair).into(), name, args.len() + 1));
+    }
+
+    let mut args = args.into_iter();
+    let mut next_arg = || args.next().unwrap();
+    Ok(match name.to_lowercase().as_str() {
+        "abs" => ir::SetValue::Abs(next_arg()),
+        "clamp" => {
+            let (val, min, max) = (next_arg(), next_arg(), next_arg());
+            ir::SetValue::Expression {
+                i1: Box::new(ir::SetValue::Expression {
+                    i1: val,
+                    op: MathOperator::Max,
+                    i2: min,
+                }),
+                op: MathOperator::Min,
+                i2: max,
+            }
+        }
+        "max" => ir::SetValue::Expression {
+            i1: next_arg(),
+            op: MathOperator::Max,
+            i2: next_arg(),
+        },
+        "min" => ir::SetValue::Expression {
+            i1: next_arg(),
+            op: MathOperator::Min,
+            i2: next_arg(),
+        },
+        _ => unreachable!(),
+    })
+}
+
+/// Unconditional jump, expressed as an IF whose condition never holds.
+fn jump<'a>(target: usize) -> ir::Command<'a> {
+    ir::Command::If {
+        i1: ir::SetValue::Value(ir::Integer::Literal(0)),
+        op: LogicalOperator::NEqual,
+        i2: ir::SetValue::Value(ir::Integer::Literal(0)),
+        goto_false: target,
+    }
+}
+
+fn set_goto_false(command: &mut ir::Command, target: usize) {
+    if let ir::Command::If {
+        i1: _,
+        op: _,
+        i2: _,
+        goto_false,
+    } = command
+    {
+        *goto_false = target;
+    }
+}
+
 impl<'a> ir::Program<'a> {
-    pub fn from_src(src: &'a str, config: &cfg::Config) -> Result<Self, Error<'a>> {
+    /// Lowers a condition of comparisons joined by AND and OR to a chain of tests, which stops
+    /// at the first test deciding the outcome. Returns the tests that must jump past the guarded
+    /// commands once the condition is false.
+    fn push_condition(&mut self, condition: Pair<'a, Rule>) -> Result<Vec<usize>, Error<'a>> {
+        // AND binds tighter than OR, so the condition is an OR of terms made of ANDs
+        let mut terms: Vec<Vec<Pair<'a, Rule>>> = vec![Vec::new()];
+        for pair in condition.into_inner() {
+            match pair.as_rule() {
+                Rule::comparison => terms.last_mut().unwrap().push(pair),
+                Rule::logical_connective => {
+                    if pair.as_str().eq_ignore_ascii_case("or") {
+                        terms.push(Vec::new());
+                    }
+                }
+                _ => unreachable!(),
+            }
+        }
+
+        let last_term = terms.len() - 1;
+        let mut false_tests: Vec<usize> = Vec::new();
+        let mut true_jumps: Vec<usize> = Vec::new();
+        for (i, term) in terms.into_iter().enumerate() {
+            // A failed test of the previous term moves on to this one
+            let term_start = self.commands.len();
+            for idx in false_tests.drain(..) {
+                set_goto_false(&mut self.commands[idx], term_start);
+            }
+            for comparison in term {
+                let mut kwords = comparison.into_inner();
+                false_tests.push(self.commands.len());
+                self.commands.push(ir::Command::If {
+                    i1: ir::SetValue::Value(next_pair_unchecked!(kwords).try_into()?),
+                    op: next_pair_unchecked!(kwords).try_into()?,
+                    i2: ir::SetValue::Value(next_pair_unchecked!(kwords).try_into()?),
+                    goto_false: 0,
+                });
+            }
+            // A term passing every test makes the whole condition true
+            if i != last_term {
+                true_jumps.push(self.commands.len());
+                self.commands.push(jump(0));
+            }
+        }
+        let body_start = self.commands.len();
+        for idx in true_jumps {
+            set_goto_false(&mut self.commands[idx], body_start);
+        }
+        Ok(false_tests)
+    }
+
+    fn push_command(
+        &mut self,
+        command_part: Pair<'a, Rule>,
+        config: &cfg::Config,
+        if_indices: &mut Vec<Vec<usize>>,
+        blocks: &mut Vec<Block<'a>>,
+    ) -> Result<(), Error<'a>> {
+        if config.standard < cfg::Standard::Extended {
+            if let Some(pair) = find_extension(&command_part) {
+                return Err(Error::StandardUnsupportedError(
+                    (&pair).into(),
+                    pair.as_str(),
+                    config.standard,
+                ));
+            }
+        }
+        match command_part.as_rule() {
+            Rule::kword_command_nfunc => {
+                self.commands
+                    .push(ir::Command::try_from_keyword(&command_part)?);
+            }
+            Rule::command_func => self
+                .commands
+                .push(ir::Command::try_from_func(&mut command_part.into_inner())?),
+            Rule::command_goto => {
+                self.commands.push(ir::Command::Goto(
+                    next_pair_unchecked!(command_part.into_inner()).try_into()?,
+                ));
+            }
+            Rule::command_gosub => {
+                self.commands.push(ir::Command::Gosub(
+                    next_pair_unchecked!(command_part.into_inner()).try_into()?,
+                ));
+            }
+            Rule::command_if_then => {
+                let mut kwords = command_part.into_inner();
+                let tests = self.push_condition(kwords.next().unwrap())?;
+                if_indices.push(tests);
+            }
+            Rule::command_elseif => {
+                let mut kwords = command_part.into_inner();
+                let kword = &kwords.next().unwrap();
+                // Belongs to the closest preceding IF on the line
+                let tests = if_indices
+                    .pop()
+                    .ok_or(Error::UnmatchedElseIfError(kword.into()))?;
+                // The previous branch skips the rest of the chain, jumping to the line end
+                if_indices.push(vec![self.commands.len()]);
+                self.commands.push(jump(0));
+                let next_test_idx = self.commands.len();
+                for idx in tests {
+                    set_goto_false(&mut self.commands[idx], next_test_idx);
+                }
+                let tests = self.push_condition(kwords.next().unwrap())?;
+                if_indices.push(tests);
+            }
+            Rule::command_set => {
+                let mut kwords = command_part.into_inner();
+                let var = next_pair_unchecked!(kwords).try_into()?;
+                let expression = kwords.next().unwrap();
+                let val = expression_parse(expression.into_inner(), &expression_parser())?;
+                self.commands.push(ir::Command::Set { var, val });
+            }
+            Rule::command_set_str => {
+                let mut kwords = command_part.into_inner();
+                self.commands.push(ir::Command::SetStr {
+                    var: ir::Identifier(next_pair_unchecked!(kwords).as_str()),
+                    val: next_pair_unchecked!(kwords).try_into()?,
+                });
+            }
+            Rule::command_for => {
+                let mut kwords = command_part.into_inner();
+                let kword = &kwords.next().unwrap();
+                let var = next_pair_unchecked!(kwords).try_into()?;
+                let start = next_pair_unchecked!(kwords).try_into()?;
+                let end = next_pair_unchecked!(kwords).try_into()?;
+                let (step, descending) = if let Some(step) = kwords.next() {
+                    (
+                        next_pair_unchecked!(step.clone().into_inner()).try_into()?,
+                        step.as_str().starts_with('-'),
+                    )
+                } else {
+                    (ir::Integer::Literal(1), false)
+                };
+                self.commands.push(ir::Command::Set {
+                    var,
+                    val: ir::SetValue::Value(start),
+                });
+                blocks.push(Block::For {
+                    var,
+                    end,
+                    step,
+                    descending,
+                    test_idx: self.commands.len(),
+                    loc: kword.into(),
+                });
+                self.commands.push(ir::Command::If {
+                    i1: ir::SetValue::Value(ir::Integer::Variable(var)),
+                    op: if descending {
+                        LogicalOperator::GEqual
+                    } else {
+                        LogicalOperator::LEqual
+                    },
+                    i2: ir::SetValue::Value(end),
+                    goto_false: 0,
+                });
+            }
+            Rule::command_next => {
+                let mut kwords = command_part.into_inner();
+                let kword = &kwords.next().unwrap();
+                // A stray NEXT leaves the innermost WHILE open for its WEND
+                if let Some(&Block::While { loc, .. }) = blocks.last() {
+                    return Err(Error::NextClosesWhileError(kword.into(), loc));
+                }
+                let (var, end, step, descending, test_idx) = match blocks.pop() {
+                    Some(Block::For {
+                        var,
+                        end,
+                        step,
+                        descending,
+                        test_idx,
+                        ..
+                    }) => (var, end, step, descending, test_idx),
+                    _ => return Err(Error::UnmatchedNextError(kword.into())),
+                };
+                if let Some(next_var) = kwords.next() {
+                    if next_var.as_str() != var.0 {
+                        return Err(Error::NextVariableError(
+                            (&next_var).into(),
+                            next_var.as_str(),
+                        ));
+                    }
+                }
+                // Stop before stepping past the end, so that the variable cannot
+                // under/overflow when the end is near either limit of its range. The first test
+                // keeps the bound of the second within range.
+                let value = |i| ir::SetValue::Value(i);
+                let bound = |i1, op, i2| ir::SetValue::Expression {
+                    i1: Box::new(value(i1)),
+                    op,
+                    i2: Box::new(value(i2)),
+                };
+                let exit_idx = self.commands.len() + 4;
+                let (range_test, step_test) = if descending {
+                    (
+                        ir::Command::If {
+                            i1: value(end),
+                            op: LogicalOperator::LEqual,
+                            i2: bound(ir::Integer::Literal(u16::MAX), MathOperator::Subtract, step),
+                            goto_false: exit_idx,
+                        },
+                        ir::Command::If {
+                            i1: value(ir::Integer::Variable(var)),
+                            op: LogicalOperator::GEqual,
+                            i2: bound(end, MathOperator::Add, step),
+                            goto_false: exit_idx,
+                        },
+                    )
+                } else {
+                    (
+                        ir::Command::If {
+                            i1: value(step),
+                            op: LogicalOperator::LEqual,
+                            i2: value(end),
+                            goto_false: exit_idx,
+                        },
+                        ir::Command::If {
+                            i1: value(ir::Integer::Variable(var)),
+                            op: LogicalOperator::LEqual,
+                            i2: bound(end, MathOperator::Subtract, step),
+                            goto_false: exit_idx,
+                        },
+                    )
+                };
+                self.commands.push(range_test);
+                self.commands.push(step_test);
+                self.commands.push(ir::Command::Set {
+                    var,
+                    val: bound(
+                        ir::Integer::Variable(var),
+                        if descending {
+                            MathOperator::Subtract
+                        } else {
+                            MathOperator::Add
+                        },
+                        step,
+                    ),
+                });
+                self.commands.push(jump(test_idx));
+                set_goto_false(&mut self.commands[test_idx], exit_idx);
+            }
+            Rule::command_while => {
+                let mut kwords = command_part.into_inner();
+                let kword = &kwords.next().unwrap();
+                let start_idx = self.commands.len();
+                let tests = self.push_condition(kwords.next().unwrap())?;
+                blocks.push(Block::While {
+                    start_idx,
+                    tests,
+                    loc: kword.into(),
+                });
+            }
+            Rule::kword_wend => {
+                // A stray WEND leaves the innermost FOR open for its NEXT
+                if let Some(&Block::For { loc, .. }) = blocks.last() {
+                    return Err(Error::WendClosesForError((&command_part).into(), loc));
+                }
+                let (start_idx, tests) = match blocks.pop() {
+                    Some(Block::While {
+                        start_idx, tests, ..
+                    }) => (start_idx, tests),
+                    _ => return Err(Error::UnmatchedWendError((&command_part).into())),
+                };
+                self.commands.push(jump(start_idx));
+                let exit_idx = self.commands.len();
+                for idx in tests {
+                    set_goto_false(&mut self.commands[idx], exit_idx);
+                }
+            }
+            Rule::command_unknown => {
+                let name = next_pair_unchecked!(command_part.clone().into_inner()).as_str();
+                return Err(Error::UnknownCommandError(
+                    (&command_part).into(),
+                    name,
+                    suggest_command(name),
+                ));
+            }
+            Rule::label => {
+                if config.pedantic && self.labels.len() >= 500 {
+                    return Err(Error::ExcessLabelsError);
+                }
+                let label = &(command_part.into_inner().next().unwrap());
+                let start = label.as_span().start_pos();
+                let indented = start.line_col().1 > 1;
+                // Whitespace may precede a label if it starts its line
+                let allowed = config.indented_labels
+                    && start.line_of().trim_start().starts_with(label.as_str());
+                if indented && !allowed {
+                    return Err(Error::LabelIndentationError(label.into(), label.as_str()));
+                }
+                self.labels
+                    .insert(label.as_str().to_lowercase(), self.commands.len());
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Labels a command may jump to
+    fn jump_targets(command: &ir::Command<'a>) -> Vec<ir::Identifier<'a>> {
+        match command {
+            ir::Command::Gosub(label) | ir::Command::Goto(label) => vec![*label],
+            ir::Command::SetKeyboard(params) => params.values().copied().collect(),
+            ir::Command::SetMenu(items) => items
+                .iter()
+                .flat_map(|category| {
+                    std::iter::once(&category.item).chain(category.members.iter().filter_map(
+                        |member| match member {
+                            ir::MenuMember::Item(item) => Some(item),
+                            ir::MenuMember::Separator => None,
+                        },
+                    ))
+                })
+                .filter_map(|item| item.label)
+                .collect(),
+            ir::Command::SetMenuCheck { label, .. } | ir::Command::SetMenuEnabled { label, .. } => {
+                vec![*label]
+            }
+            ir::Command::OnClick(Some(callbacks)) => vec![callbacks.label],
+            ir::Command::SetMouse(regions) => regions
+                .iter()
+                .map(|region| region.callbacks.label)
+                .collect(),
+            ir::Command::SetTimer {
+                label: Some(label), ..
+            } => vec![*label],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Finds every label jumped to by a command that is not defined
+    fn nonexistent_labels(&self) -> Vec<Error<'a>> {
+        let mut errors = Vec::new();
+        for (command, &(line, col)) in self.commands.iter().zip(&self.locations) {
+            errors.extend(
+                Self::jump_targets(command)
+                    .into_iter()
+                    .filter(|label| !self.labels.contains_key(&label.0.to_lowercase()))
+                    .map(|label| Error::NonexistentLabelError(ErrorLoc { line, col }, label.0)),
+            );
+        }
+        errors
+    }
+
+    /// Finds every label in `defined` that no command jumps to
+    fn unused_labels(&self, defined: &[(ErrorLoc, &'a str)]) -> Vec<Warning<'a>> {
+        let targets: HashSet<String> = self
+            .commands
+            .iter()
+            .flat_map(Self::jump_targets)
+            .map(|label| label.0.to_lowercase())
+            .collect();
+        defined
+            .iter()
+            .filter(|(_, label)| !targets.contains(&label.to_lowercase()))
+            .map(|&(loc, label)| Warning::UnusedLabelWarning(loc, label))
+            .collect()
+    }
+
+    /// Finds the start of each run of commands that cannot be reached from the
+    /// start of the program or any label
+    fn unreachable_code(&self) -> impl Iterator<Item = ErrorLoc> + '_ {
+        let mut reachable = vec![false; self.commands.len()];
+        let mut pending: Vec<usize> = std::iter::once(0)
+            .chain(self.labels.values().copied())
+            .collect();
+        while let Some(idx) = pending.pop() {
+            if idx >= self.commands.len() || reachable[idx] {
+                continue;
+            }
+            reachable[idx] = true;
+            match self.commands[idx] {
+                ir::Command::End
+                | ir::Command::Exit { .. }
+                | ir::Command::Goto(_)
+                | ir::Command::Return => (),
+                // Conditions comparing two literals, such as loop jumps, always go one way
+                ir::Command::If {
+                    i1: ir::SetValue::Value(ir::Integer::Literal(i1)),
+                    op,
+                    i2: ir::SetValue::Value(ir::Integer::Literal(i2)),
+                    goto_false,
+                } => pending.push(if op.cmp(i1, i2) { idx + 1 } else { goto_false }),
+                ir::Command::If { goto_false, .. } => pending.extend([idx + 1, goto_false]),
+                _ => pending.push(idx + 1),
+            }
+        }
+
+        (0..self.commands.len())
+            .filter(move |&idx| !reachable[idx] && (idx == 0 || reachable[idx - 1]))
+            .map(|idx| {
+                let (line, col) = self.locations[idx];
+                ErrorLoc { line, col }
+            })
+    }
+
+    pub fn from_src(
+        src: &'a str,
+        config: &cfg::Config,
+    ) -> Result<(Self, Vec<Warning<'a>>), Error<'a>> {
         let mut pairs = OrielParser::parse(Rule::program, src)?;
 
         let mut prog = Self {
             commands: Vec::new(),
             labels: HashMap::new(),
+            locations: Vec::new(),
         };
 
-        for command_group in pairs.next().unwrap().into_inner() {
-            let mut if_indices: Vec<usize> = Vec::new();
+        let program = pairs.next().unwrap();
+        let end_loc = program.as_span().end_pos().line_col();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut errors: Vec<Error> = Vec::new();
+        let mut defined_labels: Vec<(ErrorLoc, &str)> = Vec::new();
+
+        for command_group in program.into_inner() {
+            // Tests jumping to the line end once false, grouped by condition
+            let mut if_indices: Vec<Vec<usize>> = Vec::new();
             for command in command_group.into_inner() {
                 for command_part in command.into_inner() {
-                    match command_part.as_rule() {
-                        Rule::kword_command_nfunc => {
-                            prog.commands.push(ir::Command::from_keyword(&command_part));
-                        }
-                        Rule::command_func => prog
-                            .commands
-                            .push(ir::Command::try_from_func(&mut command_part.into_inner())?),
-                        Rule::command_goto => {
-                            prog.commands.push(ir::Command::Goto(
-                                next_pair_unchecked!(command_part.into_inner()).try_into()?,
-                            ));
-                        }
-                        Rule::command_gosub => {
-                            prog.commands.push(ir::Command::Gosub(
-                                next_pair_unchecked!(command_part.into_inner()).try_into()?,
-                            ));
-                        }
-                        Rule::command_if_then => {
-                            let mut kwords = command_part.into_inner();
-                            if_indices.push(prog.commands.len());
-                            prog.commands.push(ir::Command::If {
-                                i1: next_pair_unchecked!(kwords).try_into()?,
-                                op: next_pair_unchecked!(kwords).try_into()?,
-                                i2: next_pair_unchecked!(kwords).try_into()?,
-                                goto_false: 0,
-                            });
-                        }
-                        Rule::command_set => {
-                            let mut kwords = command_part.into_inner();
-                            let var = next_pair_unchecked!(kwords).try_into()?;
-                            let i1 = next_pair_unchecked!(kwords).try_into()?;
-                            let val = {
-                                if kwords.peek().is_none() {
-                                    ir::SetValue::Value(i1)
-                                } else {
-                                    ir::SetValue::Expression {
-                                        i1,
-                                        op: next_pair_unchecked!(kwords).try_into()?,
-                                        i2: next_pair_unchecked!(kwords).try_into()?,
-                                    }
-                                }
-                            };
-                            prog.commands.push(ir::Command::Set { var, val });
-                        }
-                        Rule::label => {
-                            if config.pedantic && prog.labels.len() >= 500 {
-                                return Err(Error::ExcessLabelsError);
-                            }
-                            let label = &(command_part.into_inner().next().unwrap());
-                            if label.as_span().start_pos().line_col().1 > 1 {
-                                println!("{}", label.as_span().start_pos().line_col().1);
-                                return Err(Error::LabelIndentationError(
-                                    label.into(),
-                                    label.as_str(),
-                                ));
-                            }
-                            prog.labels
-                                .insert(ir::Identifier(label.as_str()), prog.commands.len());
-                        }
-                        _ => unreachable!(),
-                    };
+                    let loc = command_part.as_span().start_pos().line_col();
+                    if command_part.as_rule() == Rule::label {
+                        let label = command_part.clone().into_inner().next().unwrap();
+                        defined_labels.push(((&label).into(), label.as_str()));
+                    }
+                    if let Err(e) =
+                        prog.push_command(command_part, config, &mut if_indices, &mut blocks)
+                    {
+                        errors.push(e);
+                    }
+                    prog.locations.resize(prog.commands.len(), loc);
                 }
             }
 
-            for idx in if_indices {
+            for idx in if_indices.into_iter().flatten() {
                 let goto_false_tgt = prog.commands.len();
-                if let ir::Command::If {
-                    i1: _,
-                    op: _,
-                    i2: _,
-                    goto_false: goto_false_idx,
-                } = &mut prog.commands[idx]
-                {
-                    *goto_false_idx = goto_false_tgt;
-                }
+                set_goto_false(&mut prog.commands[idx], goto_false_tgt);
             }
         }
 
+        errors.extend(blocks.iter().map(|block| match *block {
+            Block::For { loc, .. } => Error::UnclosedForError(loc),
+            Block::While { loc, .. } => Error::UnclosedWhileError(loc),
+        }));
+
+        // Labels may be used before they are defined, so they can only be checked
+        // once every command has been parsed
+        errors.extend(prog.nonexistent_labels());
+
+        if config.pedantic && errors.is_empty() {
+            errors.extend(prog.unreachable_code().map(Error::UnreachableCodeError));
+        }
+
+        match errors.len() {
+            0 => (),
+            1 => return Err(errors.pop().unwrap()),
+            _ => return Err(Error::MultipleError(errors)),
+        }
+
+        let warnings = prog.unused_labels(&defined_labels);
+
         prog.commands.push(ir::Command::End);
+        prog.locations.push(end_loc);
+
+        Ok((prog, warnings))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(src: &str) -> Result<ir::Program<'_>, Error<'_>> {
+        ir::Program::from_src(src, &cfg::Config::default()).map(|(prog, _)| prog)
+    }
+
+    fn parse_win3_0(src: &str) -> Result<ir::Program<'_>, Error<'_>> {
+        let config = cfg::Config {
+            standard: cfg::Standard::WIN3_0,
+            ..Default::default()
+        };
+        ir::Program::from_src(src, &config).map(|(prog, _)| prog)
+    }
+
+    #[test]
+    fn wend_without_while() {
+        assert!(matches!(
+            parse("Set i=0\nWend\n"),
+            Err(Error::UnmatchedWendError(_))
+        ));
+    }
+
+    #[test]
+    fn while_without_wend() {
+        assert!(matches!(
+            parse("While i<5\nSet i=i+1\n"),
+            Err(Error::UnclosedWhileError(_))
+        ));
+    }
+
+    #[test]
+    fn forward_label_reference() {
+        assert!(parse("Goto later\nEnd\nlater:\nEnd\n").is_ok());
+    }
+
+    #[test]
+    fn nonexistent_label() {
+        match parse("Gosub sub\nEnd\nsbu:\nReturn\n") {
+            Err(Error::NonexistentLabelError(loc, label)) => {
+                assert_eq!((loc.line, loc.col, label), (1, 1, "sub"));
+            }
+            _ => panic!("expected NonexistentLabelError"),
+        }
+    }
+
+    #[test]
+    fn nonexistent_timer_label() {
+        assert!(matches!(
+            parse("SetTimer(100,tick)\nEnd\n"),
+            Err(Error::NonexistentLabelError(_, "tick"))
+        ));
+    }
+
+    #[test]
+    fn val_and_str_as_variable_names() {
+        let src = "Set val=1\nSet str=val+1\nDrawNumber(0,0,str)\nEnd\n";
+        assert!(parse(src).is_ok());
+        assert!(parse_win3_0(src).is_ok());
+    }
+
+    #[test]
+    fn print_and_locate_as_variable_names() {
+        let src = "Set print=1\nSet locate=print*2\nDrawNumber(0,0,locate)\nEnd\n";
+        assert!(parse(src).is_ok());
+        assert!(parse_win3_0(src).is_ok());
+    }
 
-        Ok(prog)
+    #[test]
+    fn exit_as_label_and_variable_name() {
+        let src = "Set exit=1\nGoto exit\nexit:\nDrawNumber(0,0,exit)\nEnd\n";
+        assert!(parse(src).is_ok());
+        assert!(parse_win3_0(src).is_ok());
+    }
+
+    #[test]
+    fn win3_is_win3_0() {
+        assert_eq!(cfg::Standard::try_from("win3"), Ok(cfg::Standard::WIN3_0));
+    }
+
+    #[test]
+    fn win3_0_rejects_string_variables() {
+        for src in ["Set name$=\"text\"\nEnd\n", "DrawText(0,0,name$)\nEnd\n"] {
+            assert!(parse(src).is_ok());
+            assert!(matches!(
+                parse_win3_0(src),
+                Err(Error::StandardUnsupportedError(_, "name$", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn win3_0_rejects_negative_literals() {
+        for src in ["DrawLine(-10,0,100,0)\nEnd\n", "Set x=-1\nEnd\n"] {
+            assert!(parse(src).is_ok());
+            assert!(matches!(
+                parse_win3_0(src),
+                Err(Error::StandardUnsupportedError(_, "-1" | "-10", _))
+            ));
+        }
+    }
+
+    #[test]
+    fn win3_0_rejects_extension_commands() {
+        for (src, part) in [
+            ("Print(\"text\")\nEnd\n", "Print"),
+            ("SaveState\nEnd\n", "SaveState"),
+            ("SetWaitMode(ANYKEY)\nEnd\n", "ANYKEY"),
+            ("GetTicks(t)\nEnd\n", "GetTicks"),
+        ] {
+            assert!(parse(src).is_ok());
+            match parse_win3_0(src) {
+                Err(Error::StandardUnsupportedError(_, found, cfg::Standard::WIN3_0)) => {
+                    assert_eq!(found, part);
+                }
+                _ => panic!("expected StandardUnsupportedError for {}", part),
+            }
+        }
+    }
+
+    #[test]
+    fn next_inside_while_inside_for() {
+        assert!(parse("For i=1 To 3\nWhile j<i\nSet j=j+1\nWend\nNext i\nEnd\n").is_ok());
+        match parse("For i=1 To 3\nWhile j<5\nNext i\nWend\n") {
+            Err(Error::MultipleError(errors)) => match errors.as_slice() {
+                [Error::NextClosesWhileError(next, open), Error::UnclosedForError(unclosed)] => {
+                    assert_eq!((next.line, open.line, unclosed.line), (3, 2, 1));
+                }
+                _ => panic!(
+                    "expected NEXT closing WHILE and unclosed FOR, got {:?}",
+                    errors
+                ),
+            },
+            result => panic!("expected MultipleError, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn every_unclosed_block_is_reported() {
+        match parse("For i=1 To 3\nWhile j<5\n") {
+            Err(Error::MultipleError(errors)) => match errors.as_slice() {
+                [Error::UnclosedForError(outer), Error::UnclosedWhileError(inner)] => {
+                    assert_eq!((outer.line, inner.line), (1, 2));
+                }
+                _ => panic!("expected unclosed FOR and WHILE, got {:?}", errors),
+            },
+            result => panic!("expected MultipleError, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn wend_inside_for_inside_while() {
+        assert!(parse("While j<5\nFor i=1 To 3\nNext i\nSet j=j+1\nWend\nEnd\n").is_ok());
+        match parse("While j<5\nFor i=1 To 3\nWend\nNext i\n") {
+            Err(Error::MultipleError(errors)) => match errors.as_slice() {
+                [Error::WendClosesForError(wend, open), Error::UnclosedWhileError(unclosed)] => {
+                    assert_eq!((wend.line, open.line, unclosed.line), (3, 2, 1));
+                }
+                _ => panic!(
+                    "expected WEND closing FOR and unclosed WHILE, got {:?}",
+                    errors
+                ),
+            },
+            result => panic!("expected MultipleError, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn ctrl_key_is_separate_from_plain_key() {
+        let prog =
+            parse("SetKeyboard(\"^c\",ctrl,\"C\",plain)\nEnd\nctrl:\nEnd\nplain:\nEnd\n").unwrap();
+        match &prog.commands[0] {
+            ir::Command::SetKeyboard(params) => {
+                let label = |chr, ctrl| params[&ir::Key::Physical(ir::PhysicalKey { chr, ctrl })].0;
+                assert_eq!(label('C', true), "ctrl");
+                assert_eq!(label('C', false), "plain");
+                assert_eq!(params.len(), 2);
+            }
+            _ => panic!("expected SetKeyboard"),
+        }
     }
 }
@@ -0,0 +1,103 @@
+use std::{collections::VecDeque, fs::read_to_string};
+
+use thiserror::Error;
+
+use crate::{input, ir, vm};
+
+/// Scripted input event, delivered in place of live input and read from a line of a replay file.
+///
+/// Each non-empty line not starting with `#` holds a delay in milliseconds, measured from the
+/// start of the first `WaitInput` since the previous event, followed by one of:
+/// - `key CODE`: virtual key
+/// - `char C` or `char ^C`: physical key, optionally with control held
+/// - `click X Y`: mouse click, in the current coordinate units
+/// - `menu N`: the `N`th menu item with a label, counting from 0
+/// - `tick`: timer tick
+pub enum Event {
+    Key(vm::Key),
+    Click(u16, u16),
+    Menu(usize),
+    Tick,
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to read replay file: {}", .0)]
+    IoError(#[from] std::io::Error),
+    #[error("Line {}: Invalid replay event '{}'", .0, .1)]
+    InvalidEventError(usize, String),
+}
+
+impl Event {
+    /// Adds the event to `queue`, scaling click coordinates to pixels
+    pub fn push(self, queue: &mut input::InputQueue, scale: f64) {
+        match self {
+            Event::Key(key) => queue.push_keyboard([key]),
+            Event::Click(x, y) => queue.push_mouse((f64::from(x) * scale, f64::from(y) * scale)),
+            Event::Menu(index) => queue.push_menu(index),
+            Event::Tick => queue.timer = true,
+        }
+    }
+}
+
+pub struct Replay {
+    events: VecDeque<(u64, Event)>,
+}
+
+impl Replay {
+    pub fn from_file(filename: &str) -> Result<Self, Error> {
+        let src = read_to_string(filename)?;
+        let events = src
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+            .map(|(idx, line)| {
+                parse_event(line).ok_or_else(|| Error::InvalidEventError(idx + 1, line.to_string()))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { events })
+    }
+
+    pub fn next(&mut self) -> Option<(u64, Event)> {
+        self.events.pop_front()
+    }
+
+    /// Delay of the next event, if any
+    pub fn delay(&self) -> Option<u64> {
+        self.events.front().map(|(delay, _)| *delay)
+    }
+}
+
+fn parse_event(line: &str) -> Option<(u64, Event)> {
+    let mut words = line.split_whitespace();
+    let delay = words.next()?.parse().ok()?;
+    let event = match words.next()? {
+        "key" => Event::Key(vm::Key::Virtual(
+            words.next()?.parse::<u16>().ok()?.try_into().ok()?,
+        )),
+        "char" => {
+            let word = words.next()?;
+            let (chr, ctrl) = match word.strip_prefix('^') {
+                Some(chr) if !chr.is_empty() => (chr, true),
+                _ => (word, false),
+            };
+            let mut chars = chr.chars();
+            let chr = chars.next()?;
+            if chars.next().is_some() {
+                return None;
+            }
+            Event::Key(vm::Key::Physical(ir::PhysicalKey::new(chr, ctrl)))
+        }
+        "click" => Event::Click(words.next()?.parse().ok()?, words.next()?.parse().ok()?),
+        "menu" => Event::Menu(words.next()?.parse().ok()?),
+        "tick" => Event::Tick,
+        _ => return None,
+    };
+    if words.next().is_some() {
+        None
+    } else {
+        Some((delay, event))
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2023  Wojciech Graj
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+use std::borrow::Cow;
+use std::fs;
+use std::path::PathBuf;
+
+/// Source of the images named by programs, consulted before the filesystem
+pub trait ResourceProvider {
+    /// Gives the contents of the resource called `name`, or `None` if there is no such resource
+    fn resource(&self, name: &str) -> Option<Cow<'static, [u8]>>;
+}
+
+/// Bitmaps bundled with Windows 3, under their original paths
+pub struct EmbeddedResources;
+
+impl ResourceProvider for EmbeddedResources {
+    fn resource(&self, name: &str) -> Option<Cow<'static, [u8]>> {
+        let bytes: &'static [u8] = match name {
+            "C:\\WINDOWS\\BOXES.BMP" => include_bytes!("res/BOXES.BMP"),
+            "C:\\WINDOWS\\CHESS.BMP" => include_bytes!("res/CHESS.BMP"),
+            "C:\\WINDOWS\\PAPER.BMP" => include_bytes!("res/PAPER.BMP"),
+            "C:\\WINDOWS\\PARTY.BMP" => include_bytes!("res/PARTY.BMP"),
+            "C:\\WINDOWS\\PYRAMID.BMP" => include_bytes!("res/PYRAMID.BMP"),
+            "C:\\WINDOWS\\RIBBONS.BMP" => include_bytes!("res/RIBBONS.BMP"),
+            "C:\\WINDOWS\\WEAVE.BMP" => include_bytes!("res/WEAVE.BMP"),
+            _ => return None,
+        };
+        Some(Cow::Borrowed(bytes))
+    }
+}
+
+/// Files in a directory, found by the last component of a resource's Windows or Unix path,
+/// falling back to the embedded bitmaps
+pub struct DirResources {
+    dir: PathBuf,
+}
+
+impl DirResources {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl ResourceProvider for DirResources {
+    fn resource(&self, name: &str) -> Option<Cow<'static, [u8]>> {
+        let basename = name.rsplit(['\\', '/']).next().unwrap_or(name);
+        fs::read(self.dir.join(basename))
+            .ok()
+            .map(Cow::Owned)
+            .or_else(|| EmbeddedResources.resource(name))
+    }
+}
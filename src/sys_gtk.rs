@@ -10,47 +10,167 @@
 // MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
 // GNU General Public License for more details.
 
+use std::cell::Cell;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::f64::consts::PI;
-use std::f64::consts::TAU;
 use std::process;
 use std::rc::Rc;
 use std::time;
 
-use gtk::cairo;
 use gtk::gdk;
 use gtk::gdk::prelude::*;
-use gtk::gdk_pixbuf;
 use gtk::glib;
 use gtk::prelude::*;
 use thiserror::Error;
 
+use crate::cfg;
+use crate::clock;
+use crate::draw;
+use crate::input;
 use crate::ir;
+use crate::replay;
+use crate::resource;
 use crate::vm;
 use crate::vm::VMSys;
 
-#[macro_use]
-mod draw;
-mod input;
+mod record;
+
+/// Key pausing and resuming execution
+const PAUSE_KEY: gdk::keys::Key = gdk::keys::constants::F12;
+/// Longest time between checks of the window's focus while waiting for it with a timeout
+const FOCUS_POLL_INTERVAL: time::Duration = time::Duration::from_millis(10);
 
 pub struct VMSysGtk<'a> {
     window: gtk::Window,
     help: gtk::MenuItem,
     menu_bar: gtk::MenuBar,
+    /// Menu items with labels, by their key in the input context
+    menu_items: HashMap<usize, MenuHandle>,
+    /// Holds the accelerators of the menu items
+    accel_group: gtk::AccelGroup,
+    drawing_area: gtk::DrawingArea,
+    hover_cursor: Rc<RefCell<HoverCursor>>,
+    /// Hidden while empty
+    statusbar: gtk::Statusbar,
+    progress: Option<Progress>,
+    /// Collapsible pane holding `output`, hidden until the first line is output
+    output_pane: gtk::Expander,
+    output: gtk::TextView,
+    /// Size of the canvas if set by the program, rather than following the window's size
+    canvas_size: Rc<Cell<Option<(i32, i32)>>>,
     draw_ctx: Rc<RefCell<draw::DrawCtx>>,
     input_ctx: input::InputCtx<'a>,
     wait_mode: ir::WaitMode,
+    timer: Option<glib::SourceId>,
+    keep_aspect: bool,
+    replay: Option<replay::Replay>,
+    recorder: Option<record::Recorder>,
+    /// PNG file the canvas is saved to once the program ends
+    snapshot: Option<String>,
+    run_shell: bool,
+    confirm_run: bool,
+    pedantic: bool,
+    /// Toggled by the pause key
+    paused: Rc<Cell<bool>>,
+    clock: Box<dyn clock::Clock>,
+    /// Shortest time between the ends of timed waits, and the clock's time when the last one ended
+    frame_interval: Option<time::Duration>,
+    last_frame: Option<time::Duration>,
+}
+
+/// Wall clock sleeping in the main loop, so that the window stays responsive. A sleep ends early
+/// once the window is closed.
+struct MainLoopClock {
+    start: time::Instant,
+    queue: Rc<RefCell<input::InputQueue>>,
+}
+
+impl clock::Clock for MainLoopClock {
+    fn elapsed(&self) -> time::Duration {
+        self.start.elapsed()
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        let elapsed = Rc::new(Cell::new(false));
+        let elapsed_clone = elapsed.clone();
+        // Wakes the blocking main loop iteration below once the time is up
+        let source = glib::timeout_add_local_once(duration, move || elapsed_clone.set(true));
+        while !elapsed.get() && !self.queue.borrow().closed {
+            gtk::main_iteration();
+        }
+        if !elapsed.get() {
+            source.remove();
+        }
+    }
+}
+
+/// Cursor shapes of the canvas, shared with its motion handler
+#[derive(Default)]
+struct HoverCursor {
+    /// Shape set by `SetCursor`
+    base: ir::CursorShape,
+    /// Mouse regions in the order clicks are matched
+    regions: Vec<CursorRegion>,
+    /// Shape of the region under the pointer
+    hovered: Option<ir::CursorShape>,
+}
+
+impl HoverCursor {
+    fn apply(&self, window: &gdk::Window) {
+        window
+            .set_cursor(cursor_conv(&window.display(), self.hovered.unwrap_or(self.base)).as_ref());
+    }
+
+    /// Updates the shape for the pointer having moved to `(x, y)`
+    fn motion(&mut self, window: &gdk::Window, x: f64, y: f64) {
+        let hovered = self
+            .regions
+            .iter()
+            .find(|region| region.x1 <= x && region.y1 <= y && region.x2 >= x && region.y2 >= y)
+            .and_then(|region| region.shape);
+        if hovered != self.hovered {
+            self.hovered = hovered;
+            self.apply(window);
+        }
+    }
+}
+
+/// Bounds of a mouse region, and its own cursor shape if any
+struct CursorRegion {
+    x1: f64,
+    y1: f64,
+    x2: f64,
+    y2: f64,
+    shape: Option<ir::CursorShape>,
+}
+
+/// Dialog opened by `ProgressStart`
+struct Progress {
+    dialog: gtk::Window,
+    bar: gtk::ProgressBar,
+    max: u16,
+}
+
+/// Menu item with a label
+struct MenuHandle {
+    item: gtk::MenuItem,
+    /// Pushes the item's activation to the input queue
+    handler: glib::SignalHandlerId,
 }
 
 impl<'a> VMSysGtk<'a> {
-    pub fn new(filename: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn new(
+        filename: &str,
+        config: &cfg::Config,
+        resources: Box<dyn resource::ResourceProvider>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         gtk::init()?;
 
-        let logo = pixbuf_from_bytes(include_bytes!("res/LOGO.png"), None)?;
+        let logo = draw::pixbuf_from_bytes(include_bytes!("res/LOGO.png"), None)?;
 
-        let input_ctx = input::InputCtx::new();
-        let draw_ctx = Rc::new(RefCell::new(draw::DrawCtx::new()?));
+        let input_ctx = input::InputCtx::new(config.queue_capacity);
+        let paused = Rc::new(Cell::new(false));
+        let draw_ctx = Rc::new(RefCell::new(draw::DrawCtx::new(config, resources)?));
 
         let window = {
             let window = gtk::Window::new(gtk::WindowType::Toplevel);
@@ -59,9 +179,15 @@ impl<'a> VMSysGtk<'a> {
             window.set_icon(Some(&logo));
 
             let queue_clone = input_ctx.queue.clone();
+            let paused_clone = paused.clone();
             window.connect_key_press_event(move |_, event_key| {
+                // Reserved for the interpreter, so never delivered to the program
+                if event_key.keyval() == PAUSE_KEY {
+                    paused_clone.set(!paused_clone.get());
+                    return Inhibit(true);
+                }
                 let mut queue = queue_clone.borrow_mut();
-                queue.keyboard.extend(eventkey_conv(event_key));
+                queue.push_keyboard(eventkey_conv(event_key));
                 Inhibit(false)
             });
 
@@ -103,6 +229,9 @@ impl<'a> VMSysGtk<'a> {
             help
         };
 
+        let accel_group = gtk::AccelGroup::new();
+        window.add_accel_group(&accel_group);
+
         let menu_bar = {
             let menu_bar = gtk::MenuBar::new();
             menu_bar.append(&help);
@@ -110,9 +239,14 @@ impl<'a> VMSysGtk<'a> {
         };
         mainbox.pack_start(&menu_bar, false, true, 0);
 
+        let canvas_size: Rc<Cell<Option<(i32, i32)>>> = Rc::new(Cell::new(None));
+        let hover_cursor: Rc<RefCell<HoverCursor>> = Rc::new(RefCell::new(HoverCursor::default()));
+
         let drawing_area = {
             let drawing_area = gtk::DrawingArea::new();
-            drawing_area.add_events(gdk::EventMask::BUTTON_PRESS_MASK);
+            drawing_area.add_events(
+                gdk::EventMask::BUTTON_PRESS_MASK | gdk::EventMask::POINTER_MOTION_MASK,
+            );
 
             let draw_ctx_clone = draw_ctx.clone();
             drawing_area.connect_draw(move |_, cr| {
@@ -124,42 +258,147 @@ impl<'a> VMSysGtk<'a> {
             });
 
             let draw_ctx_clone = draw_ctx.clone();
+            let canvas_size_clone = canvas_size.clone();
             drawing_area.connect_size_allocate(move |_, rect| {
-                draw_ctx_clone
-                    .borrow_mut()
-                    .resize(rect.width(), rect.height())
-                    .ok();
+                // A canvas with a set size is scrolled instead
+                if canvas_size_clone.get().is_none() {
+                    draw_ctx_clone
+                        .borrow_mut()
+                        .resize(rect.width(), rect.height())
+                        .ok();
+                }
             });
 
+            // The coordinates are relative to the drawing area, which already accounts for
+            // scrolling, so they are in canvas pixels
             let queue_clone = input_ctx.queue.clone();
             drawing_area.connect_button_press_event(move |_, event_button| {
                 if let Some(coords) = event_button.coords() {
                     let mut queue = queue_clone.borrow_mut();
-                    queue.mouse.push(coords);
+                    queue.push_mouse(coords);
+                }
+                Inhibit(false)
+            });
+
+            let hover_cursor_clone = hover_cursor.clone();
+            drawing_area.connect_motion_notify_event(move |drawing_area, event_motion| {
+                if let Some(window) = drawing_area.window() {
+                    let (x, y) = event_motion.position();
+                    hover_cursor_clone.borrow_mut().motion(&window, x, y);
                 }
                 Inhibit(false)
             });
 
             drawing_area
         };
-        mainbox.pack_start(&drawing_area, true, true, 0);
+
+        let scrolled_window = {
+            let scrolled_window =
+                gtk::ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+            scrolled_window.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+            scrolled_window.add(&drawing_area);
+            scrolled_window
+        };
+        mainbox.pack_start(&scrolled_window, true, true, 0);
+
+        let output = gtk::TextView::new();
+        output.set_editable(false);
+        output.set_cursor_visible(false);
+        output.set_monospace(true);
+        let output_pane = {
+            let output_pane = gtk::Expander::new(Some("Output"));
+            output_pane.set_expanded(true);
+            let scrolled_window =
+                gtk::ScrolledWindow::new(gtk::Adjustment::NONE, gtk::Adjustment::NONE);
+            scrolled_window.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+            scrolled_window.set_min_content_height(120);
+            scrolled_window.add(&output);
+            output_pane.add(&scrolled_window);
+            output_pane.set_no_show_all(true);
+            output_pane
+        };
+        mainbox.pack_start(&output_pane, false, true, 0);
+
+        let statusbar = gtk::Statusbar::new();
+        // Only shown once the program sets a status, so the canvas keeps its size otherwise
+        statusbar.set_no_show_all(true);
+        mainbox.pack_start(&statusbar, false, true, 0);
 
         window.show_all();
         window.set_mnemonics_visible(true);
 
+        let clock = Box::new(MainLoopClock {
+            start: time::Instant::now(),
+            queue: input_ctx.queue.clone(),
+        });
+
         let mut sys = VMSysGtk {
             window,
             menu_bar,
+            menu_items: HashMap::new(),
+            accel_group,
             help,
+            drawing_area,
+            hover_cursor,
+            statusbar,
+            progress: None,
+            output_pane,
+            output,
+            canvas_size,
             draw_ctx,
             input_ctx,
             wait_mode: ir::WaitMode::Null,
+            timer: None,
+            keep_aspect: config.keep_aspect,
+            replay: if let Some(filename) = &config.replay {
+                Some(replay::Replay::from_file(filename)?)
+            } else {
+                None
+            },
+            recorder: config
+                .record
+                .as_ref()
+                .map(|filename| record::Recorder::new(filename, config.record_fps)),
+            snapshot: config.snapshot.clone(),
+            run_shell: config.run_shell,
+            confirm_run: config.confirm_run,
+            pedantic: config.pedantic,
+            paused,
+            clock,
+            frame_interval: config
+                .max_fps
+                .map(|fps| time::Duration::from_secs(1) / fps.into()),
+            last_frame: None,
         };
 
         sys.use_coordinates(ir::Coordinates::Metric)?;
 
         Ok(sys)
     }
+
+    /// Processes events until `duration` passes on the clock, or the window is closed. Pending
+    /// events are always processed, even if `duration` is 0.
+    fn process_events_for(&mut self, duration: time::Duration) {
+        let deadline = self.clock.elapsed() + duration;
+        while gtk::events_pending() {
+            gtk::main_iteration();
+        }
+        while !self.input_ctx.queue.borrow().closed {
+            let now = self.clock.elapsed();
+            if now >= deadline {
+                break;
+            }
+            self.clock.sleep(deadline - now);
+        }
+    }
+
+    /// Snapshots the canvas if recording
+    fn capture_frame(&mut self) -> Result<(), record::Error> {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.capture(&self.draw_ctx.borrow().surface)?;
+        }
+        Ok(())
+    }
 }
 
 #[allow(clippy::enum_variant_names)]
@@ -167,17 +406,19 @@ impl<'a> VMSysGtk<'a> {
 enum Error {
     #[error("Failed to get window")]
     WindowMissingError,
-    #[error("Failed to create cairo surface")]
-    SurfaceCreateError,
     #[error("Failed to get monitor")]
     MonitorMissingError,
     #[error("Glib error: {}", .0)]
     GlibError(#[from] glib::Error),
-    #[error("Failed to create Pixbuf from image")]
-    PixbufLoadError,
+    #[error("ProgressSet without a matching ProgressStart")]
+    ProgressMissingError,
+    #[error("Unknown accelerator key '{}'", .0)]
+    AcceleratorKeyError(String),
 }
 
 impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
+    type Error = Box<dyn std::error::Error>;
+
     fn beep(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.window
             .window()
@@ -197,20 +438,13 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x4: u16,
         y4: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2, x3, y3, x4, y4));
-
-        draw_ctx.arc_path_rect_bound(x1, y1, x2, y2, x3, y3, x4, y4, false);
-        draw_ctx.stroke()?;
-        Ok(())
+        self.draw_ctx
+            .borrow()
+            .draw_arc(x1, y1, x2, y2, x3, y3, x4, y4)
     }
 
     fn draw_background(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        draw_ctx.cr_background().paint()?;
-        Ok(())
+        self.draw_ctx.borrow_mut().draw_background()
     }
 
     fn draw_bitmap(
@@ -219,20 +453,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         y: u16,
         filename: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x, y));
-
-        let pixbuf = pixbuf_from_filename(filename, None)?;
-
-        let surface = pixbuf
-            .create_surface(1, self.window.window().as_ref())
-            .ok_or_else(|| Error::SurfaceCreateError)?;
-
-        let cr = cairo::Context::new(draw_ctx.surface.as_ref())?;
-        cr.set_source_surface(&surface, x, y)?;
-        cr.paint()?;
-        Ok(())
+        self.draw_ctx.borrow().draw_bitmap(x, y, filename)
     }
 
     fn draw_chord(
@@ -246,17 +467,9 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x4: u16,
         y4: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2, x3, y3, x4, y4));
-
-        let pts = draw_ctx.arc_path_rect_bound(x1, y1, x2, y2, x3, y3, x4, y4, true);
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.line_to(pts.0, pts.1);
-        });
-
-        draw_ctx.draw()?;
-        Ok(())
+        self.draw_ctx
+            .borrow()
+            .draw_chord(x1, y1, x2, y2, x3, y3, x4, y4)
     }
 
     fn draw_ellipse(
@@ -266,22 +479,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x2: u16,
         y2: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2));
-
-        draw_ctx.arc_path(
-            (x2 + x1) / 2.,
-            (y2 + y1) / 2.,
-            (x2 - x1) / 2.,
-            (y2 - y1) / 2.,
-            TAU,
-            0.0,
-            true,
-            true,
-        );
-        draw_ctx.draw()?;
-        Ok(())
+        self.draw_ctx.borrow().draw_ellipse(x1, y1, x2, y2)
     }
 
     fn draw_flood(
@@ -292,52 +490,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         g: u16,
         b: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x, y));
-
-        let tgt = [b as u8, g as u8, r as u8];
-
-        let width = draw_ctx.surface.width() as usize;
-        let height = draw_ctx.surface.height() as usize;
-
-        let mut mask_surface: Option<Result<cairo::ImageSurface, cairo::Error>> = None;
-
-        // This is inefficient, but implementing a more efficient flood-fill is a hassle
-        draw_ctx.surface.with_data(|data| {
-            let mut mask: Vec<u8> = (0..(data.len() / 4)).map(|_| 0u8).collect();
-            let mut q: Vec<(usize, usize)> = vec![(x as usize, y as usize)];
-            while let Some((x, y)) = q.pop() {
-                let i = x + y * width;
-                if mask[i] == 0 && data[(i * 4)..(i * 4 + 3)] != tgt {
-                    mask[i] = 255;
-                    if x > 0 {
-                        q.push((x - 1, y));
-                    }
-                    if x < width - 1 {
-                        q.push((x + 1, y));
-                    }
-                    if y > 0 {
-                        q.push((x, y - 1));
-                    }
-                    if y < height - 1 {
-                        q.push((x, y + 1));
-                    }
-                }
-            }
-            mask_surface = Some(cairo::ImageSurface::create_for_data(
-                mask,
-                cairo::Format::A8,
-                width as i32,
-                height as i32,
-                width as i32,
-            ));
-        })?;
-
-        let mask_surface = mask_surface.unwrap()?;
-
-        draw_ctx.cr_brush().mask_surface(&mask_surface, 0., 0.)?;
-        Ok(())
+        self.draw_ctx.borrow().draw_flood(x, y, r, g, b)
     }
 
     fn draw_line(
@@ -347,20 +500,12 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x2: u16,
         y2: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2));
-
-        draw_ctx.line_exec(false, |ctx| {
-            ctx.move_to(x1, y1);
-            ctx.line_to(x2, y2);
-        });
-        draw_ctx.stroke()?;
-        Ok(())
+        self.draw_ctx.borrow().draw_line(x1, y1, x2, y2)
     }
 
     fn draw_number(&mut self, x: u16, y: u16, n: u16) -> Result<(), Box<dyn std::error::Error>> {
-        self.draw_text(x, y, n.to_string().as_str())
+        let text = self.draw_ctx.borrow().format_number(n);
+        self.draw_text(x, y, &text)
     }
 
     fn draw_pie(
@@ -374,17 +519,9 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x4: u16,
         y4: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2, x3, y3, x4, y4));
-
-        let pts = draw_ctx.arc_path_rect_bound(x1, y1, x2, y2, x3, y3, x4, y4, true);
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.line_to((x2 + x1) / 2., (y2 + y1) / 2.);
-            ctx.line_to(pts.0, pts.1);
-        });
-        draw_ctx.draw()?;
-        Ok(())
+        self.draw_ctx
+            .borrow()
+            .draw_pie(x1, y1, x2, y2, x3, y3, x4, y4)
     }
 
     fn draw_rectangle(
@@ -394,19 +531,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x2: u16,
         y2: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2));
-
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.move_to(x1, y1);
-            ctx.line_to(x2, y1);
-            ctx.line_to(x2, y2);
-            ctx.line_to(x1, y2);
-            ctx.line_to(x1, y1);
-        });
-        draw_ctx.draw()?;
-        Ok(())
+        self.draw_ctx.borrow().draw_rectangle(x1, y1, x2, y2)
     }
 
     fn draw_round_rectangle(
@@ -418,32 +543,9 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         x3: u16,
         y3: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2, x3, y3));
-
-        let x3 = x3 / 2.;
-        let y3 = y3 / 2.;
-
-        draw_ctx.arc_path(x1 + x3, y1 + y3, x3, y3, PI * 1.5, PI, false, true);
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.line_to(x1, y2 - y3 / 2.);
-        });
-        draw_ctx.arc_path(x1 + x3, y2 - y3, x3, y3, PI, PI * 0.5, false, true);
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.line_to(x2 - x3 / 2., y2);
-        });
-        draw_ctx.arc_path(x2 - x3, y2 - y3, x3, y3, PI * 0.5, 0., false, true);
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.line_to(x2, y1 + y3 / 2.);
-        });
-        draw_ctx.arc_path(x2 - x3, y1 + y3, x3, y3, 0., PI * -0.5, false, true);
-        draw_ctx.line_exec(true, |ctx| {
-            ctx.line_to(x1 + x3 / 2., y1);
-        });
-
-        draw_ctx.draw()?;
-        Ok(())
+        self.draw_ctx
+            .borrow()
+            .draw_round_rectangle(x1, y1, x2, y2, x3, y3)
     }
 
     fn draw_sized_bitmap(
@@ -454,96 +556,66 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         y2: u16,
         filename: &str,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
-
-        scale_vars!(draw_ctx, (x1, y1, x2, y2));
-
-        let pixbuf = pixbuf_from_filename(
-            filename,
-            Some(((x2 - x1).abs() as i32, (y2 - y1).abs() as i32)),
-        )?;
-
-        let surface = pixbuf
-            .create_surface(1, self.window.window().as_ref())
-            .ok_or_else(|| Error::SurfaceCreateError)?;
-
-        let cr = cairo::Context::new(draw_ctx.surface.as_ref())?;
-        cr.scale(
-            if x1 < x2 { 1. } else { -1. },
-            if y1 < y2 { 1. } else { -1. },
-        );
-        cr.translate(
-            if x1 < x2 {
-                x1.min(x2)
-            } else {
-                f64::from(pixbuf.width()) - x1.min(x2)
-            },
-            if y1 < y2 {
-                y1.min(y2)
-            } else {
-                f64::from(-pixbuf.height()) - y1.min(y2)
-            },
-        );
-        cr.set_source_surface(&surface, 0., 0.)?;
-        cr.paint()?;
-        Ok(())
+        self.draw_ctx
+            .borrow()
+            .draw_sized_bitmap(x1, y1, x2, y2, filename, self.keep_aspect)
     }
 
     fn draw_text(&mut self, x: u16, y: u16, text: &str) -> Result<(), Box<dyn std::error::Error>> {
-        let draw_ctx = self.draw_ctx.borrow();
+        self.draw_ctx.borrow().draw_text(x, y, text)
+    }
 
-        scale_vars!(draw_ctx, (x, y));
+    fn end(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        *self.hover_cursor.borrow_mut() = HoverCursor::default();
+        if let Some(window) = self.drawing_area.window() {
+            window.set_cursor(None);
+        }
+        self.capture_frame()?;
+        if let Some(recorder) = &self.recorder {
+            recorder.save()?;
+        }
+        if let Some(filename) = &self.snapshot {
+            self.draw_ctx.borrow().save_png(filename)?;
+        }
+        Ok(())
+    }
 
-        let font_extents = draw_ctx.cr_text().font_extents()?;
-        let y = y + font_extents.height();
+    fn get_env(&mut self, name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(std::env::var(name).unwrap_or_default())
+    }
 
-        let width = {
-            if let Some(width) = draw_ctx.text_width {
-                width * (text.len() as f64)
-            } else {
-                draw_ctx.cr_text().text_extents(text)?.width()
-            }
-        };
+    fn get_ticks(&mut self) -> Result<u16, Box<dyn std::error::Error>> {
+        Ok(clock::ticks(self.clock.as_ref()))
+    }
 
-        if let ir::BackgroundTransparency::Opaque = draw_ctx.background_transparency {
-            draw_ctx.cr_background().rectangle(
-                x,
-                y - font_extents.ascent(),
-                width,
-                font_extents.height(),
-            );
-            draw_ctx.cr_background().fill()?;
-        }
+    fn get_time(&mut self) -> Result<(u16, u16, u16), Box<dyn std::error::Error>> {
+        let now = glib::DateTime::now_local()?;
+        Ok((now.hour() as u16, now.minute() as u16, now.second() as u16))
+    }
 
-        if let ir::FontUnderline::Underline = draw_ctx.text_underline {
-            draw_ctx.cr_text().move_to(x, y + font_extents.descent());
-            draw_ctx.cr_text().rel_line_to(width, 0.);
-            draw_ctx.cr_text().stroke()?;
-        }
+    fn locate(&mut self, x: u16, y: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().locate(x, y)
+    }
 
-        if let Some(width) = draw_ctx.text_width {
-            let mut x = x;
-            let orig_matrix = draw_ctx.cr_text().font_matrix();
-            for c in text.chars() {
-                let s = c.to_string();
-                let c = s.as_str();
-                let text_width = draw_ctx.cr_text().text_extents(c)?.width();
-                if text_width > 0. {
-                    let mut matrix = orig_matrix;
-                    matrix.set_xx(width / text_width);
-                    draw_ctx.cr_text().set_font_matrix(matrix);
-
-                    draw_ctx.cr_text().move_to(x, y);
-                    draw_ctx.cr_text().show_text(c)?;
-                }
-                x += width;
-                draw_ctx.cr_text().set_font_matrix(orig_matrix);
+    fn message_beep(&mut self, icon: ir::MessageBoxIcon) -> Result<(), Box<dyn std::error::Error>> {
+        let sound = match icon {
+            ir::MessageBoxIcon::Information => Some("dialog-information"),
+            ir::MessageBoxIcon::Exclamation => Some("dialog-warning"),
+            ir::MessageBoxIcon::Question => Some("dialog-question"),
+            ir::MessageBoxIcon::Stop => Some("dialog-error"),
+            ir::MessageBoxIcon::NoIcon => None,
+        };
+        // Play the themed sound if libcanberra's player is available, and beep otherwise
+        if let Some(sound) = sound {
+            if process::Command::new("canberra-gtk-play")
+                .args(["-i", sound])
+                .spawn()
+                .is_ok()
+            {
+                return Ok(());
             }
-        } else {
-            draw_ctx.cr_text().move_to(x, y);
-            draw_ctx.cr_text().show_text(text)?;
         }
-        Ok(())
+        self.beep()
     }
 
     fn message_box(
@@ -598,10 +670,163 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         })
     }
 
+    fn on_click(
+        &mut self,
+        callbacks: Option<&'a ir::MouseCallbacks<'a>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.input_ctx.click = callbacks;
+        Ok(())
+    }
+
+    fn output_ln(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        if !self.output_pane.is_visible() {
+            self.output_pane.set_no_show_all(false);
+            self.output_pane.show_all();
+        }
+        if let Some(buffer) = self.output.buffer() {
+            let mut end = buffer.end_iter();
+            buffer.insert(&mut end, text);
+            buffer.insert(&mut end, "\n");
+            if let Some(mark) = buffer.create_mark(None, &end, false) {
+                self.output.scroll_mark_onscreen(&mark);
+                buffer.delete_mark(&mark);
+            }
+        }
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<vm::Input<'a>>, Box<dyn std::error::Error>> {
+        self.window.queue_draw();
+        self.capture_frame()?;
+        while gtk::events_pending() {
+            gtk::main_iteration();
+        }
+        let scale = self.draw_ctx.borrow().scale;
+        Ok(self.input_ctx.take_input(scale))
+    }
+
+    fn print(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().print(text)
+    }
+
+    fn progress_end(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(progress) = self.progress.take() {
+            progress.dialog.close();
+        }
+        Ok(())
+    }
+
+    fn progress_set(&mut self, value: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let progress = self.progress.as_ref().ok_or(Error::ProgressMissingError)?;
+        progress.bar.set_fraction(if progress.max == 0 {
+            1.
+        } else {
+            f64::from(value.min(progress.max)) / f64::from(progress.max)
+        });
+        // Repaint the bar, as the program may not wait for input until it is done
+        while gtk::events_pending() {
+            gtk::main_iteration();
+        }
+        Ok(())
+    }
+
+    fn progress_start(&mut self, max: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.progress_end()?;
+        let dialog = gtk::Window::new(gtk::WindowType::Toplevel);
+        dialog.set_transient_for(Some(&self.window));
+        dialog.set_modal(true);
+        dialog.set_deletable(false);
+        dialog.set_resizable(false);
+        dialog.set_title(&self.window.title().unwrap_or_default());
+        dialog.set_position(gtk::WindowPosition::CenterOnParent);
+        let bar = gtk::ProgressBar::new();
+        bar.set_show_text(true);
+        bar.set_size_request(300, -1);
+        bar.set_margin(12);
+        dialog.add(&bar);
+        dialog.show_all();
+        self.progress = Some(Progress { dialog, bar, max });
+        self.progress_set(0)
+    }
+
+    fn restore_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().restore_state()
+    }
+
     fn run(&mut self, command: &str) -> Result<(), Box<dyn std::error::Error>> {
         let command = command_conv(command);
 
-        process::Command::new("sh").arg("-c").arg(command).spawn()?;
+        if self.confirm_run {
+            let dialog = gtk::MessageDialog::new(
+                Some(&self.window),
+                gtk::DialogFlags::DESTROY_WITH_PARENT,
+                gtk::MessageType::Warning,
+                gtk::ButtonsType::YesNo,
+                "Allow the program to run this command?",
+            );
+            dialog.set_secondary_text(Some(command));
+            let response = dialog.run();
+            dialog.close();
+            while gtk::events_pending() {
+                gtk::main_iteration();
+            }
+            if response != gtk::ResponseType::Yes {
+                return Ok(());
+            }
+        }
+
+        if self.run_shell {
+            process::Command::new("sh").arg("-c").arg(command).spawn()?;
+        } else {
+            let args = shell_words::split(command)?;
+            if let Some((program, args)) = args.split_first() {
+                process::Command::new(program).args(args).spawn()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().save_state()
+    }
+
+    fn set_canvas_size(
+        &mut self,
+        width: u16,
+        height: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut draw_ctx = self.draw_ctx.borrow_mut();
+
+        if width == 0 || height == 0 {
+            self.canvas_size.set(None);
+            self.drawing_area.set_size_request(-1, -1);
+            let allocation = self.drawing_area.allocation();
+            draw_ctx.resize(allocation.width(), allocation.height())?;
+        } else {
+            let size = (
+                draw_ctx.scaled(width).round() as i32,
+                draw_ctx.scaled(height).round() as i32,
+            );
+            self.canvas_size.set(Some(size));
+            self.drawing_area.set_size_request(size.0, size.1);
+            draw_ctx.resize(size.0, size.1)?;
+        }
+        drop(draw_ctx);
+
+        while gtk::events_pending() {
+            gtk::main_iteration();
+        }
+        Ok(())
+    }
+
+    fn set_cursor(&mut self, shape: ir::CursorShape) -> Result<(), Box<dyn std::error::Error>> {
+        let window = self
+            .drawing_area
+            .window()
+            .ok_or_else(|| Error::WindowMissingError)?;
+        let mut hover_cursor = self.hover_cursor.borrow_mut();
+        hover_cursor.base = shape;
+        hover_cursor.apply(&window);
         Ok(())
     }
 
@@ -622,27 +847,35 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
             .iter()
             .for_each(|child| self.menu_bar.remove(child));
         self.input_ctx.menu = HashMap::new();
+        self.input_ctx.menu_disabled.clear();
+        self.menu_items.clear();
         for category in menu.iter() {
-            self.menu_bar.append(&{
-                let item = menu_item_conv(&category.item, &mut self.input_ctx);
-                if !category.members.is_empty() {
-                    item.set_submenu(Some(&{
-                        let submenu = gtk::Menu::new();
-                        category.members.iter().for_each(|member| {
-                            match member {
-                                ir::MenuMember::Item(subitem) => {
-                                    submenu.append(&menu_item_conv(subitem, &mut self.input_ctx));
-                                }
-                                ir::MenuMember::Separator => {
-                                    submenu.append(&gtk::SeparatorMenuItem::new());
-                                }
-                            };
-                        });
-                        submenu
-                    }));
+            let item = menu_item_conv(
+                &category.item,
+                &mut self.input_ctx,
+                &mut self.menu_items,
+                &self.accel_group,
+            )?;
+            if !category.members.is_empty() {
+                let submenu = gtk::Menu::new();
+                for member in category.members.iter() {
+                    match member {
+                        ir::MenuMember::Item(subitem) => {
+                            submenu.append(&menu_item_conv(
+                                subitem,
+                                &mut self.input_ctx,
+                                &mut self.menu_items,
+                                &self.accel_group,
+                            )?);
+                        }
+                        ir::MenuMember::Separator => {
+                            submenu.append(&gtk::SeparatorMenuItem::new());
+                        }
+                    };
                 }
-                item
-            });
+                item.set_submenu(Some(&submenu));
+            }
+            self.menu_bar.append(&item);
         }
         self.menu_bar.append(&self.help);
         self.window.show_all();
@@ -650,6 +883,40 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         Ok(())
     }
 
+    fn set_menu_check(
+        &mut self,
+        label: ir::Identifier<'a>,
+        on: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.input_ctx.menu_keys(label) {
+            let handle = &self.menu_items[&key];
+            if let Some(check_item) = handle.item.downcast_ref::<gtk::CheckMenuItem>() {
+                // Changing the state activates the item, which must not reach the program
+                check_item.block_signal(&handle.handler);
+                check_item.set_active(on);
+                check_item.unblock_signal(&handle.handler);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_menu_enabled(
+        &mut self,
+        label: ir::Identifier<'a>,
+        enabled: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.input_ctx.menu_keys(label) {
+            self.menu_items[&key].item.set_sensitive(enabled);
+            // Replayed input bypasses the widget
+            if enabled {
+                self.input_ctx.menu_disabled.remove(&key);
+            } else {
+                self.input_ctx.menu_disabled.insert(key);
+            }
+        }
+        Ok(())
+    }
+
     fn set_mouse(
         &mut self,
         regions: &[vm::MouseRegion<'a>],
@@ -657,14 +924,101 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         let draw_ctx = self.draw_ctx.borrow();
         self.input_ctx.mouse = regions
             .iter()
-            .map(|region| input::MouseRegion {
-                x1: draw_ctx.scaled(region.x1),
-                y1: draw_ctx.scaled(region.y1),
-                x2: draw_ctx.scaled(region.x2),
-                y2: draw_ctx.scaled(region.y2),
-                callbacks: region.callbacks,
+            .map(|region| {
+                input::MouseRegion::new(
+                    (draw_ctx.scaled(region.x1), draw_ctx.scaled(region.y1)),
+                    (draw_ctx.scaled(region.x2), draw_ctx.scaled(region.y2)),
+                    region.callbacks,
+                )
             })
             .collect();
+
+        let mut hover_cursor = self.hover_cursor.borrow_mut();
+        hover_cursor.regions = self
+            .input_ctx
+            .mouse
+            .iter()
+            .zip(regions)
+            .map(|(bounds, region)| CursorRegion {
+                x1: bounds.x1,
+                y1: bounds.y1,
+                x2: bounds.x2,
+                y2: bounds.y2,
+                shape: region.cursor,
+            })
+            .collect();
+        // The pointer is assumed to be outside the new regions until it next moves
+        hover_cursor.hovered = None;
+        if let Some(window) = self.drawing_area.window() {
+            hover_cursor.apply(&window);
+        }
+        Ok(())
+    }
+
+    fn set_number_base(&mut self, hex: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().number_hex = hex;
+        Ok(())
+    }
+
+    fn set_number_format(
+        &mut self,
+        width: u16,
+        pad: char,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().set_number_format(width, pad);
+        Ok(())
+    }
+
+    fn set_pen_style(
+        &mut self,
+        cap: ir::LineCap,
+        join: ir::LineJoin,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().set_pen_style(cap, join)
+    }
+
+    fn set_status(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let context_id = self.statusbar.context_id("status");
+        self.statusbar.remove_all(context_id);
+        if text.is_empty() {
+            self.statusbar.hide();
+        } else {
+            self.statusbar.push(context_id, text);
+            self.statusbar.show();
+        }
+        Ok(())
+    }
+
+    fn set_timer(
+        &mut self,
+        milliseconds: u16,
+        label: Option<ir::Identifier<'a>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(timer) = self.timer.take() {
+            timer.remove();
+        }
+        self.input_ctx.timer = match label {
+            Some(label) if milliseconds > 0 => {
+                let queue_clone = self.input_ctx.queue.clone();
+                self.timer = Some(glib::timeout_add_local(
+                    time::Duration::from_millis(milliseconds.into()),
+                    move || {
+                        queue_clone.borrow_mut().timer = true;
+                        glib::Continue(true)
+                    },
+                ));
+                Some(label)
+            }
+            _ => None,
+        };
+        Ok(())
+    }
+
+    fn set_transparent_color(
+        &mut self,
+        rgb: Option<(u16, u16, u16)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow_mut().set_transparent_color(rgb);
         Ok(())
     }
 
@@ -673,6 +1027,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         mode: crate::ir::WaitMode,
     ) -> Result<(), Box<dyn std::error::Error>> {
         self.wait_mode = mode;
+        self.input_ctx.any_key = matches!(mode, ir::WaitMode::AnyKey);
         Ok(())
     }
 
@@ -694,6 +1049,10 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         Ok(())
     }
 
+    fn text_width(&mut self, text: &str) -> Result<u16, Box<dyn std::error::Error>> {
+        self.draw_ctx.borrow().text_width(text)
+    }
+
     fn use_background(
         &mut self,
         option: crate::ir::BackgroundTransparency,
@@ -701,16 +1060,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         g: u16,
         b: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut draw_ctx = self.draw_ctx.borrow_mut();
-        draw_ctx.background_transparency = option;
-        draw_ctx.background_rgb = (
-            f64::from(r) / 255.,
-            f64::from(g) / 255.,
-            f64::from(b) / 255.,
-        );
-        draw_ctx.cr_background_inval();
-        draw_ctx.cr_brush_inval();
-        Ok(())
+        self.draw_ctx.borrow_mut().use_background(option, r, g, b)
     }
 
     fn use_brush(
@@ -720,15 +1070,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         g: u16,
         b: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut draw_ctx = self.draw_ctx.borrow_mut();
-        draw_ctx.brush_type = option;
-        draw_ctx.brush_rgb = (
-            f64::from(r) / 255.,
-            f64::from(g) / 255.,
-            f64::from(b) / 255.,
-        );
-        draw_ctx.cr_brush_inval();
-        Ok(())
+        self.draw_ctx.borrow_mut().use_brush(option, r, g, b)
     }
 
     fn use_caption(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
@@ -742,7 +1084,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
     ) -> Result<(), Box<dyn std::error::Error>> {
         let mut draw_ctx = self.draw_ctx.borrow_mut();
 
-        draw_ctx.scale = match option {
+        let scale = match option {
             ir::Coordinates::Pixel => 1.,
             ir::Coordinates::Metric => {
                 let window_gdk = self
@@ -753,9 +1095,15 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
                     .display()
                     .monitor_at_window(&window_gdk)
                     .ok_or_else(|| Error::MonitorMissingError)?;
-                f64::from(monitor.geometry().width()) / f64::from(monitor.width_mm())
+                // The geometry is in logical pixels, so convert it to device pixels using the
+                // monitor's scale, then to the window's logical pixels that drawing happens in
+                let device_width = monitor.geometry().width() * monitor.scale_factor();
+                f64::from(device_width)
+                    / f64::from(window_gdk.scale_factor())
+                    / f64::from(monitor.width_mm())
             }
         };
+        draw_ctx.set_scale(scale);
         Ok(())
     }
 
@@ -771,46 +1119,18 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         g: u16,
         b: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut draw_ctx = self.draw_ctx.borrow_mut();
-
-        draw_ctx.text_underline = underline;
-
-        let font_face = cairo::FontFace::toy_create(
+        self.draw_ctx.borrow_mut().use_font(
             name,
-            match italic {
-                ir::FontSlant::Italic => cairo::FontSlant::Italic,
-                ir::FontSlant::NoItalic => cairo::FontSlant::Normal,
-            },
-            match bold {
-                ir::FontWeight::Bold => cairo::FontWeight::Bold,
-                ir::FontWeight::NoBold => cairo::FontWeight::Normal,
-            },
-        )?;
-
-        draw_ctx.text_face = font_face;
-        draw_ctx.text_rgb = (
-            f64::from(r) / 255.,
-            f64::from(g) / 255.,
-            f64::from(b) / 255.,
-        );
-
-        draw_ctx.text_width = if width == 0 {
-            None
-        } else {
-            Some(draw_ctx.scaled(width))
-        };
-
-        draw_ctx.text_height_mul = if height == 0 {
-            None
-        } else {
-            draw_ctx.text_height_mul = Some(1.);
-            draw_ctx.cr_text_inval();
-            let font_extents = draw_ctx.cr_text().font_extents()?;
-            Some(draw_ctx.scaled(height) / font_extents.height())
-        };
-
-        draw_ctx.cr_text_inval();
-        Ok(())
+            width,
+            height,
+            bold,
+            italic,
+            underline,
+            r,
+            g,
+            b,
+            self.pedantic,
+        )
     }
 
     fn use_pen(
@@ -821,18 +1141,7 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         g: u16,
         b: u16,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut draw_ctx = self.draw_ctx.borrow_mut();
-
-        draw_ctx.pen_type = option;
-        draw_ctx.pen_width = width.into();
-        draw_ctx.pen_rgb = (
-            f64::from(r) / 255.,
-            f64::from(g) / 255.,
-            f64::from(b) / 255.,
-        );
-        draw_ctx.cr_pen_inval();
-        draw_ctx.cr_background_inval();
-        Ok(())
+        self.draw_ctx.borrow_mut().use_pen(option, width, r, g, b)
     }
 
     fn wait_input(
@@ -840,18 +1149,21 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
         milliseconds: Option<u16>,
     ) -> Result<Option<vm::Input<'a>>, Box<dyn std::error::Error>> {
         self.window.queue_draw();
+        self.capture_frame()?;
         match self.wait_mode {
-            ir::WaitMode::Null => {
+            ir::WaitMode::Null | ir::WaitMode::AnyKey => {
                 if let Some(milliseconds) = milliseconds {
-                    let milliseconds = if milliseconds == 0 { 1 } else { milliseconds };
-                    let start = time::Instant::now();
-                    while start.elapsed().as_millis() < milliseconds.into() {
-                        while gtk::events_pending() {
-                            gtk::main_iteration();
-                        }
-                        if self.input_ctx.queue.borrow().closed {
-                            return Ok(Some(vm::Input::End));
+                    self.process_events_for(time::Duration::from_millis(milliseconds.into()));
+                    if let Some(frame_interval) = self.frame_interval {
+                        if let Some(last_frame) = self.last_frame {
+                            let next_frame = last_frame + frame_interval;
+                            let now = self.clock.elapsed();
+                            self.process_events_for(next_frame.saturating_sub(now));
                         }
+                        self.last_frame = Some(self.clock.elapsed());
+                    }
+                    if self.input_ctx.queue.borrow().closed {
+                        return Ok(Some(vm::Input::End));
                     }
                 } else {
                     while gtk::events_pending() {
@@ -860,7 +1172,20 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
                     self.input_ctx.clear_queue();
                     let scale = self.draw_ctx.borrow().scale;
                     while self.window.is_visible() {
-                        while gtk::events_pending() {
+                        if let Some(replay) = &mut self.replay {
+                            let (delay, event) = match replay.next() {
+                                Some(event) => event,
+                                None => return Ok(Some(vm::Input::End)),
+                            };
+                            self.process_events_for(time::Duration::from_millis(delay));
+                            if self.input_ctx.queue.borrow().closed {
+                                return Ok(Some(vm::Input::End));
+                            }
+                            // Live input is ignored while replaying
+                            self.input_ctx.clear_queue();
+                            event.push(&mut self.input_ctx.queue.borrow_mut(), scale);
+                        } else {
+                            // Sleeps until an event arrives
                             gtk::main_iteration();
                         }
                         if let Some(input) = self.input_ctx.process_queue(scale) {
@@ -869,30 +1194,53 @@ impl<'a> vm::VMSys<'a> for VMSysGtk<'a> {
                     }
                 }
             }
+            // Blocks until the window has focus, giving up once the timeout (if any) elapses.
             ir::WaitMode::Focus => {
-                if let Some(_milliseconds) = milliseconds {
-                    while gtk::events_pending() {
-                        gtk::main_iteration();
+                let deadline = milliseconds.map(|milliseconds| {
+                    self.clock.elapsed() + time::Duration::from_millis(milliseconds.into())
+                });
+                while gtk::events_pending() {
+                    gtk::main_iteration();
+                }
+                while !self.window.is_active() {
+                    if self.input_ctx.queue.borrow().closed {
+                        return Ok(Some(vm::Input::End));
                     }
-                    while !self.window.is_active() {
-                        while gtk::events_pending() {
-                            gtk::main_iteration();
-                        }
-                        if self.input_ctx.queue.borrow().closed {
-                            return Ok(Some(vm::Input::End));
+                    if let Some(deadline) = deadline {
+                        let now = self.clock.elapsed();
+                        if now >= deadline {
+                            break;
                         }
+                        // Gaining focus does not end a sleep, so it is checked between short ones
+                        self.clock.sleep((deadline - now).min(FOCUS_POLL_INTERVAL));
+                    } else {
+                        gtk::main_iteration();
                     }
                 }
             }
         }
         Ok(None)
     }
+
+    fn wait_paused(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        if self.paused.get() {
+            self.window.queue_draw();
+            while self.paused.get() {
+                gtk::main_iteration();
+                if self.input_ctx.queue.borrow().closed {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
 }
 
 fn eventkey_conv(event: &gdk::EventKey) -> Vec<vm::Key> {
     let keys = match event.keyval() {
         gdk::keys::constants::BackSpace => Some((ir::VirtualKey::BackSpace, None)),
         gdk::keys::constants::Tab => Some((ir::VirtualKey::Tab, None)),
+        gdk::keys::constants::KP_Begin => Some((ir::VirtualKey::NumPad5NoLock, None)),
         gdk::keys::constants::Return => Some((ir::VirtualKey::Enter, None)),
         gdk::keys::constants::Shift_L | gdk::keys::constants::Shift_R => {
             Some((ir::VirtualKey::Shift, None))
@@ -1062,70 +1410,127 @@ fn eventkey_conv(event: &gdk::EventKey) -> Vec<vm::Key> {
         _ => None,
     };
 
-    match keys {
+    // Side-specific modifiers come first so that they take precedence over the generic key
+    let mut converted: Vec<vm::Key> = match event.keyval() {
+        gdk::keys::constants::Shift_L => Some(ir::VirtualKey::ShiftL),
+        gdk::keys::constants::Shift_R => Some(ir::VirtualKey::ShiftR),
+        gdk::keys::constants::Control_L => Some(ir::VirtualKey::CtrlL),
+        gdk::keys::constants::Control_R => Some(ir::VirtualKey::CtrlR),
+        gdk::keys::constants::Alt_L => Some(ir::VirtualKey::AltL),
+        gdk::keys::constants::Alt_R => Some(ir::VirtualKey::AltR),
+        _ => None,
+    }
+    .map(vm::Key::Virtual)
+    .into_iter()
+    .collect();
+
+    converted.extend(match keys {
         Some((virt, physical)) => match physical {
             Some(physical) => vec![
                 vm::Key::Virtual(virt),
-                vm::Key::Physical(ir::PhysicalKey {
-                    chr: physical,
-                    ctrl: event.state().contains(gdk::ModifierType::CONTROL_MASK),
-                }),
+                vm::Key::Physical(ir::PhysicalKey::new(
+                    physical,
+                    event.state().contains(gdk::ModifierType::CONTROL_MASK),
+                )),
             ],
             None => vec![vm::Key::Virtual(virt)],
         },
         None => Vec::new(),
+    });
+    converted
+}
+
+/// Converts a cursor shape to a GDK cursor, where `None` is the default cursor
+fn cursor_conv(display: &gdk::Display, shape: ir::CursorShape) -> Option<gdk::Cursor> {
+    match shape {
+        ir::CursorShape::Arrow => None,
+        ir::CursorShape::Cross => gdk::Cursor::from_name(display, "crosshair"),
+        ir::CursorShape::Hand => gdk::Cursor::from_name(display, "pointer"),
+        ir::CursorShape::Null => gdk::Cursor::from_name(display, "none"),
     }
 }
 
+/// Converts a Windows menu label, where `&` marks the mnemonic and `&&` is a literal `&`,
+/// to a GTK mnemonic label
+fn mnemonic_conv(name: &str) -> String {
+    let mut converted = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' if chars.peek() == Some(&'&') => {
+                chars.next();
+                converted.push('&');
+            }
+            '&' => converted.push('_'),
+            '_' => converted.push_str("__"),
+            c => converted.push(c),
+        }
+    }
+    converted
+}
+
 fn menu_item_conv<'a>(
     item: &ir::MenuItem<'a>,
     input_ctx: &mut input::InputCtx<'a>,
-) -> gtk::MenuItem {
-    let menu_item = if item.name.contains('&') {
-        gtk::MenuItem::with_mnemonic(&item.name.replace('&', "_"))
-    } else {
-        gtk::MenuItem::with_label(item.name)
+    menu_items: &mut HashMap<usize, MenuHandle>,
+    accel_group: &gtk::AccelGroup,
+) -> Result<gtk::MenuItem, Error> {
+    let menu_item = match item.checked {
+        Some(checked) => {
+            let check_item = gtk::CheckMenuItem::with_mnemonic(&mnemonic_conv(item.name));
+            check_item.set_active(checked);
+            check_item.upcast()
+        }
+        None => gtk::MenuItem::with_mnemonic(&mnemonic_conv(item.name)),
     };
+    if let Some(accelerator) = item.accelerator {
+        let (key, modifiers) = accelerator_conv(&accelerator)?;
+        menu_item.add_accelerator(
+            "activate",
+            accel_group,
+            key,
+            modifiers,
+            gtk::AccelFlags::VISIBLE,
+        );
+    }
     if let Some(label) = item.label {
         let queue_clone = input_ctx.queue.clone();
         let key = input_ctx.menu.len();
-        menu_item.connect_activate(move |_| queue_clone.borrow_mut().menu.push(key));
+        let handler = menu_item.connect_activate(move |_| queue_clone.borrow_mut().push_menu(key));
         input_ctx.menu.insert(key, label);
+        menu_items.insert(
+            key,
+            MenuHandle {
+                item: menu_item.clone(),
+                handler,
+            },
+        );
     }
-    menu_item
+    Ok(menu_item)
 }
 
-fn pixbuf_from_bytes(
-    bytes: &[u8],
-    size: Option<(i32, i32)>,
-) -> Result<gdk::gdk_pixbuf::Pixbuf, Error> {
-    let loader = gdk_pixbuf::PixbufLoader::new();
-    if let Some((width, height)) = size {
-        loader.set_size(width, height);
-    }
-    loader.write(bytes)?;
-    loader.close()?;
-    loader.pixbuf().ok_or_else(|| Error::PixbufLoadError)
-}
+fn accelerator_conv(accelerator: &ir::Accelerator) -> Result<(u32, gdk::ModifierType), Error> {
+    let mut chars = accelerator.key.chars();
+    let key = match (chars.next(), chars.next()) {
+        // Letters are bound in lowercase, as the uppercase keyvals require Shift
+        (Some(c), None) => *gdk::keys::Key::from_unicode(c.to_ascii_lowercase()),
+        _ => *gdk::keys::Key::from_name(accelerator.key),
+    };
+    if key == 0 || key == *gdk::keys::constants::VoidSymbol {
+        return Err(Error::AcceleratorKeyError(accelerator.key.to_string()));
+    }
 
-fn pixbuf_from_filename(
-    filename: &str,
-    size: Option<(i32, i32)>,
-) -> Result<gdk::gdk_pixbuf::Pixbuf, Error> {
-    match filename {
-        "C:\\WINDOWS\\BOXES.BMP" => pixbuf_from_bytes(include_bytes!("res/BOXES.BMP"), size),
-        "C:\\WINDOWS\\CHESS.BMP" => pixbuf_from_bytes(include_bytes!("res/CHESS.BMP"), size),
-        "C:\\WINDOWS\\PAPER.BMP" => pixbuf_from_bytes(include_bytes!("res/PAPER.BMP"), size),
-        "C:\\WINDOWS\\PARTY.BMP" => pixbuf_from_bytes(include_bytes!("res/PARTY.BMP"), size),
-        "C:\\WINDOWS\\PYRAMID.BMP" => pixbuf_from_bytes(include_bytes!("res/PYRAMID.BMP"), size),
-        "C:\\WINDOWS\\RIBBONS.BMP" => pixbuf_from_bytes(include_bytes!("res/RIBBONS.BMP"), size),
-        "C:\\WINDOWS\\WEAVE.BMP" => pixbuf_from_bytes(include_bytes!("res/WEAVE.BMP"), size),
-        filename => Ok(if let Some((width, height)) = size {
-            gdk_pixbuf::Pixbuf::from_file_at_size(filename, width, height)
-        } else {
-            gdk_pixbuf::Pixbuf::from_file(filename)
-        }?),
+    let mut modifiers = gdk::ModifierType::empty();
+    if accelerator.ctrl {
+        modifiers |= gdk::ModifierType::CONTROL_MASK;
+    }
+    if accelerator.shift {
+        modifiers |= gdk::ModifierType::SHIFT_MASK;
     }
+    if accelerator.alt {
+        modifiers |= gdk::ModifierType::MOD1_MASK;
+    }
+    Ok((key, modifiers))
 }
 
 fn command_conv(command: &str) -> &str {
@@ -1137,3 +1542,39 @@ fn command_conv(command: &str) -> &str {
         command => command,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfg;
+
+    #[test]
+    fn mnemonics() {
+        assert_eq!(mnemonic_conv("&File"), "_File");
+        assert_eq!(mnemonic_conv("Save && E&xit"), "Save & E_xit");
+        assert_eq!(mnemonic_conv("&&"), "&");
+        assert_eq!(mnemonic_conv("snake_case"), "snake__case");
+    }
+
+    #[test]
+    fn menu_labels_with_ampersands() {
+        let src = "SetMenu(\"&Edit && View\",IGNORE,\"Cut && &Paste\",paste,\"&&\",IGNORE,ENDPOPUP,\"R&&D\",IGNORE,ENDPOPUP)\npaste:\n";
+        let (prog, _) = ir::Program::from_src(src, &cfg::Config::default()).unwrap();
+        let ir::Command::SetMenu(menu) = &prog.commands[0] else {
+            panic!("expected SetMenu");
+        };
+        let names: Vec<_> = menu
+            .iter()
+            .flat_map(|category| {
+                std::iter::once(&category.item).chain(category.members.iter().filter_map(
+                    |member| match member {
+                        ir::MenuMember::Item(item) => Some(item),
+                        ir::MenuMember::Separator => None,
+                    },
+                ))
+            })
+            .map(|item| mnemonic_conv(item.name))
+            .collect();
+        assert_eq!(names, ["_Edit & View", "Cut & _Paste", "&", "R&D"]);
+    }
+}
@@ -0,0 +1,126 @@
+use std::{fs::File, time};
+
+use gtk::cairo;
+use thiserror::Error;
+
+use crate::draw;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Failed to write recording: {}", .0)]
+    IoError(#[from] std::io::Error),
+    #[error("Failed to encode recording: {}", .0)]
+    EncodingError(#[from] gif::EncodingError),
+    #[error("Failed to read canvas: {}", .0)]
+    BorrowError(#[from] cairo::BorrowError),
+}
+
+struct Frame {
+    width: u16,
+    height: u16,
+    rgb: Vec<u8>,
+    start: time::Instant,
+}
+
+/// Snapshots of the canvas, encoded into an animated GIF once the program ends.
+///
+/// A frame lasts until the next snapshot, so its duration follows the `WaitInput` timeouts.
+/// Frames are kept uncompressed until the end, at 3 bytes per pixel, so an 800x600 canvas takes
+/// about 1.4MB per frame. Snapshots identical to the previous frame are merged into it.
+pub struct Recorder {
+    filename: String,
+    interval: Option<time::Duration>,
+    frames: Vec<Frame>,
+}
+
+impl Recorder {
+    /// Creates a recorder keeping at most `fps` frames per second, if given.
+    pub fn new(filename: &str, fps: Option<u16>) -> Self {
+        Self {
+            filename: filename.to_string(),
+            interval: fps
+                .filter(|&fps| fps > 0)
+                .map(|fps| time::Duration::from_secs(1) / fps.into()),
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn capture(&mut self, surface: &cairo::ImageSurface) -> Result<(), Error> {
+        let (Ok(width), Ok(height)) = (
+            u16::try_from(surface.width()),
+            u16::try_from(surface.height()),
+        ) else {
+            return Ok(());
+        };
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+
+        let stride = surface.stride() as usize;
+        let format = surface.format();
+        let mut rgb = Vec::with_capacity(usize::from(width) * usize::from(height) * 3);
+        surface.with_data(|data| {
+            for y in 0..usize::from(height) {
+                for x in 0..usize::from(width) {
+                    let i = y * stride + x * 4;
+                    let [b, g, r] = draw::cairo_util::pixel_bgr(format, &data[i..(i + 4)]);
+                    rgb.extend([r, g, b]);
+                }
+            }
+        })?;
+
+        let now = time::Instant::now();
+        if let Some(last) = self.frames.last_mut() {
+            if last.width == width && last.height == height && last.rgb == rgb {
+                return Ok(());
+            }
+            // Too soon after the last frame, which is replaced while keeping its start
+            if let Some(interval) = self.interval {
+                if now - last.start < interval {
+                    last.width = width;
+                    last.height = height;
+                    last.rgb = rgb;
+                    return Ok(());
+                }
+            }
+        }
+        self.frames.push(Frame {
+            width,
+            height,
+            rgb,
+            start: now,
+        });
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let width = self
+            .frames
+            .iter()
+            .map(|frame| frame.width)
+            .max()
+            .unwrap_or(1);
+        let height = self
+            .frames
+            .iter()
+            .map(|frame| frame.height)
+            .max()
+            .unwrap_or(1);
+
+        let mut encoder = gif::Encoder::new(File::create(&self.filename)?, width, height, &[])?;
+        encoder.set_repeat(gif::Repeat::Infinite)?;
+
+        let end = time::Instant::now();
+        for (idx, frame) in self.frames.iter().enumerate() {
+            let next_start = self.frames.get(idx + 1).map_or(end, |next| next.start);
+            let mut gif_frame =
+                gif::Frame::from_rgb_speed(frame.width, frame.height, &frame.rgb, 10);
+            // GIF delays are in hundredths of a second
+            gif_frame.delay =
+                ((next_start - frame.start).as_millis() / 10).clamp(1, u16::MAX.into()) as u16;
+            encoder.write_frame(&gif_frame)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,733 @@
+// Copyright (C) 2023  Wojciech Graj
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+use std::collections::HashMap;
+use std::env;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::time;
+
+use gtk::cairo;
+use gtk::glib;
+use thiserror::Error;
+
+use crate::cfg;
+use crate::clock;
+use crate::draw;
+use crate::input;
+use crate::ir;
+use crate::replay;
+use crate::resource;
+use crate::vm;
+use crate::vm::VMSys;
+
+/// Size of the canvas in pixels, matching the default window size of the GTK backend
+const CANVAS_SIZE: (i32, i32) = (800, 600);
+
+/// Draws onto an offscreen canvas, which is printed to the terminal whenever the program waits
+/// for input. There is no live input, so input only comes from a replay file or a timer.
+pub struct VMSysTerminal<'a> {
+    draw_ctx: draw::DrawCtx,
+    input_ctx: input::InputCtx<'a>,
+    replay: Option<replay::Replay>,
+    /// Start of the first wait since the last replay event, from which the next event's delay
+    /// counts, so that timer ticks ending waits in between do not postpone it
+    replay_since: Option<time::Duration>,
+    /// PNG file the canvas is saved to once the program ends
+    snapshot: Option<String>,
+    timer: Option<clock::Timer>,
+    keep_aspect: bool,
+    pedantic: bool,
+    columns: usize,
+    last_frame: String,
+    /// Printed below the canvas if not empty
+    status: String,
+    /// Maximum of the progress bar if one is open, and the last percentage printed
+    progress: Option<(u16, Option<u16>)>,
+    clock: Box<dyn clock::Clock>,
+}
+
+impl<'a> VMSysTerminal<'a> {
+    pub fn new(
+        config: &cfg::Config,
+        resources: Box<dyn resource::ResourceProvider>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut draw_ctx = draw::DrawCtx::new(config, resources)?;
+        draw_ctx.resize(CANVAS_SIZE.0, CANVAS_SIZE.1)?;
+
+        let mut sys = VMSysTerminal {
+            draw_ctx,
+            input_ctx: input::InputCtx::new(config.queue_capacity),
+            replay: if let Some(filename) = &config.replay {
+                Some(replay::Replay::from_file(filename)?)
+            } else {
+                None
+            },
+            replay_since: None,
+            snapshot: config.snapshot.clone(),
+            timer: None,
+            keep_aspect: config.keep_aspect,
+            pedantic: config.pedantic,
+            columns: env::var("COLUMNS")
+                .ok()
+                .and_then(|columns| columns.parse().ok())
+                .filter(|&columns| columns > 0)
+                .unwrap_or(80),
+            last_frame: String::new(),
+            status: String::new(),
+            progress: None,
+            clock: if config.fake_clock {
+                Box::<clock::FakeClock>::default()
+            } else {
+                Box::new(clock::SystemClock::new())
+            },
+        };
+
+        sys.use_coordinates(ir::Coordinates::Metric)?;
+
+        Ok(sys)
+    }
+
+    /// Prints the canvas, unless it is unchanged since it was last printed
+    fn show(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let frame = self.render()?;
+        if frame != self.last_frame {
+            print!("{}", frame);
+            std::io::stdout().flush()?;
+            self.last_frame = frame;
+        }
+        Ok(())
+    }
+
+    /// Renders the canvas as lines of `▀` characters fitting in the terminal's width, with each
+    /// character showing two vertically adjacent cells as its foreground and background colors.
+    fn render(&self) -> Result<String, cairo::BorrowError> {
+        let surface = &self.draw_ctx.surface;
+        let width = surface.width() as usize;
+        let height = surface.height() as usize;
+        let stride = surface.stride() as usize;
+        let format = surface.format();
+
+        let cell = width as f64 / self.columns as f64;
+        let rows = ((height as f64 / cell).round() as usize).max(1).div_ceil(2);
+
+        let mut frame = String::new();
+        surface.with_data(|data| {
+            let sample = |column: usize, row: usize| {
+                let x = (((column as f64 + 0.5) * cell) as usize).min(width - 1);
+                let y = (((row as f64 + 0.5) * cell) as usize).min(height - 1);
+                let i = y * stride + x * 4;
+                draw::cairo_util::pixel_bgr(format, &data[i..(i + 4)])
+            };
+            for row in 0..rows {
+                for column in 0..self.columns {
+                    let [tb, tg, tr] = sample(column, row * 2);
+                    let [bb, bg, br] = sample(column, row * 2 + 1);
+                    write!(
+                        frame,
+                        "\x1b[38;2;{tr};{tg};{tb}m\x1b[48;2;{br};{bg};{bb}m\u{2580}"
+                    )
+                    .ok();
+                }
+                frame.push_str("\x1b[0m\n");
+            }
+        })?;
+        if !self.status.is_empty() {
+            frame.push_str(&self.status);
+            frame.push('\n');
+        }
+        frame.push('\n');
+        Ok(frame)
+    }
+
+    /// Sets the timer flag if the timer is due
+    fn check_timer(&mut self) {
+        if let Some(timer) = &mut self.timer {
+            if timer.tick(self.clock.as_ref()) {
+                self.input_ctx.queue.borrow_mut().timer = true;
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+enum Error {
+    #[error("ProgressSet without a matching ProgressStart")]
+    ProgressMissingError,
+}
+
+impl<'a> vm::VMSys<'a> for VMSysTerminal<'a> {
+    type Error = Box<dyn std::error::Error>;
+
+    fn beep(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        print!("\x07");
+        std::io::stdout().flush()?;
+        Ok(())
+    }
+
+    fn draw_arc(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_arc(x1, y1, x2, y2, x3, y3, x4, y4)
+    }
+
+    fn draw_background(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_background()
+    }
+
+    fn draw_bitmap(
+        &mut self,
+        x: u16,
+        y: u16,
+        filename: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_bitmap(x, y, filename)
+    }
+
+    fn draw_chord(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_chord(x1, y1, x2, y2, x3, y3, x4, y4)
+    }
+
+    fn draw_ellipse(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_ellipse(x1, y1, x2, y2)
+    }
+
+    fn draw_flood(
+        &mut self,
+        x: u16,
+        y: u16,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_flood(x, y, r, g, b)
+    }
+
+    fn draw_line(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_line(x1, y1, x2, y2)
+    }
+
+    fn draw_number(&mut self, x: u16, y: u16, n: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let text = self.draw_ctx.format_number(n);
+        self.draw_text(x, y, &text)
+    }
+
+    fn draw_pie(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_pie(x1, y1, x2, y2, x3, y3, x4, y4)
+    }
+
+    fn draw_rectangle(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_rectangle(x1, y1, x2, y2)
+    }
+
+    fn draw_round_rectangle(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_round_rectangle(x1, y1, x2, y2, x3, y3)
+    }
+
+    fn draw_sized_bitmap(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        filename: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx
+            .draw_sized_bitmap(x1, y1, x2, y2, filename, self.keep_aspect)
+    }
+
+    fn draw_text(&mut self, x: u16, y: u16, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.draw_text(x, y, text)
+    }
+
+    fn end(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.show()?;
+        if let Some(filename) = &self.snapshot {
+            self.draw_ctx.save_png(filename)?;
+        }
+        Ok(())
+    }
+
+    fn get_env(&mut self, name: &str) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(std::env::var(name).unwrap_or_default())
+    }
+
+    fn get_ticks(&mut self) -> Result<u16, Box<dyn std::error::Error>> {
+        Ok(clock::ticks(self.clock.as_ref()))
+    }
+
+    fn get_time(&mut self) -> Result<(u16, u16, u16), Box<dyn std::error::Error>> {
+        let now = glib::DateTime::now_local()?;
+        Ok((now.hour() as u16, now.minute() as u16, now.second() as u16))
+    }
+
+    fn locate(&mut self, x: u16, y: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.locate(x, y)
+    }
+
+    fn message_beep(&mut self, _: ir::MessageBoxIcon) -> Result<(), Box<dyn std::error::Error>> {
+        self.beep()
+    }
+
+    /// Prints the message, answering it with the default button
+    fn message_box(
+        &mut self,
+        _: ir::MessageBoxType,
+        default_button: u16,
+        _: ir::MessageBoxIcon,
+        text: &str,
+        caption: &str,
+    ) -> Result<u16, Box<dyn std::error::Error>> {
+        self.show()?;
+        println!("[{}] {}", caption, text);
+        Ok(default_button)
+    }
+
+    fn on_click(
+        &mut self,
+        callbacks: Option<&'a ir::MouseCallbacks<'a>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.input_ctx.click = callbacks;
+        Ok(())
+    }
+
+    fn output_ln(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        println!("{}", text);
+        Ok(())
+    }
+
+    fn poll_input(&mut self) -> Result<Option<vm::Input<'a>>, Box<dyn std::error::Error>> {
+        self.show()?;
+        self.check_timer();
+        Ok(self.input_ctx.take_input(self.draw_ctx.scale))
+    }
+
+    fn print(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.print(text)
+    }
+
+    fn progress_end(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.progress = None;
+        Ok(())
+    }
+
+    /// Prints the percentage to stderr whenever it changes, so that it does not interleave with
+    /// the canvas
+    fn progress_set(&mut self, value: u16) -> Result<(), Box<dyn std::error::Error>> {
+        let (max, last_percent) = self.progress.as_mut().ok_or(Error::ProgressMissingError)?;
+        let percent = if *max == 0 {
+            100
+        } else {
+            (u32::from(value.min(*max)) * 100 / u32::from(*max)) as u16
+        };
+        if *last_percent != Some(percent) {
+            eprintln!("Progress: {}%", percent);
+            *last_percent = Some(percent);
+        }
+        Ok(())
+    }
+
+    fn progress_start(&mut self, max: u16) -> Result<(), Box<dyn std::error::Error>> {
+        self.progress = Some((max, None));
+        self.progress_set(0)
+    }
+
+    fn restore_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.restore_state()
+    }
+
+    /// Commands are never run, as there is nowhere to show them
+    fn run(&mut self, command: &str) -> Result<(), Box<dyn std::error::Error>> {
+        eprintln!("Not running '{}' in the terminal", command);
+        Ok(())
+    }
+
+    fn save_state(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.save_state()
+    }
+
+    fn set_canvas_size(
+        &mut self,
+        width: u16,
+        height: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if width == 0 || height == 0 {
+            self.draw_ctx.resize(CANVAS_SIZE.0, CANVAS_SIZE.1)?;
+        } else {
+            let width = self.draw_ctx.scaled(width).round() as i32;
+            let height = self.draw_ctx.scaled(height).round() as i32;
+            self.draw_ctx.resize(width, height)?;
+        }
+        Ok(())
+    }
+
+    fn set_cursor(&mut self, _shape: ir::CursorShape) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn set_keyboard(
+        &mut self,
+        params: HashMap<vm::Key, ir::Identifier<'a>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.input_ctx.keyboard = params;
+        Ok(())
+    }
+
+    /// Numbers the menu items with labels in the same order as the GTK backend, for replays
+    fn set_menu(
+        &mut self,
+        menu: &[ir::MenuCategory<'a>],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let items = menu.iter().flat_map(|category| {
+            std::iter::once(&category.item).chain(category.members.iter().filter_map(|member| {
+                match member {
+                    ir::MenuMember::Item(item) => Some(item),
+                    ir::MenuMember::Separator => None,
+                }
+            }))
+        });
+        self.input_ctx.menu = items.filter_map(|item| item.label).enumerate().collect();
+        self.input_ctx.menu_disabled.clear();
+        Ok(())
+    }
+
+    fn set_menu_check(
+        &mut self,
+        _label: ir::Identifier<'a>,
+        _on: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn set_menu_enabled(
+        &mut self,
+        label: ir::Identifier<'a>,
+        enabled: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for key in self.input_ctx.menu_keys(label) {
+            if enabled {
+                self.input_ctx.menu_disabled.remove(&key);
+            } else {
+                self.input_ctx.menu_disabled.insert(key);
+            }
+        }
+        Ok(())
+    }
+
+    fn set_mouse(
+        &mut self,
+        regions: &[vm::MouseRegion<'a>],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.input_ctx.mouse = regions
+            .iter()
+            .map(|region| {
+                input::MouseRegion::new(
+                    (
+                        self.draw_ctx.scaled(region.x1),
+                        self.draw_ctx.scaled(region.y1),
+                    ),
+                    (
+                        self.draw_ctx.scaled(region.x2),
+                        self.draw_ctx.scaled(region.y2),
+                    ),
+                    region.callbacks,
+                )
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn set_number_base(&mut self, hex: bool) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.number_hex = hex;
+        Ok(())
+    }
+
+    fn set_number_format(
+        &mut self,
+        width: u16,
+        pad: char,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.set_number_format(width, pad);
+        Ok(())
+    }
+
+    fn set_pen_style(
+        &mut self,
+        cap: ir::LineCap,
+        join: ir::LineJoin,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.set_pen_style(cap, join)
+    }
+
+    fn set_status(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.status = text.to_string();
+        Ok(())
+    }
+
+    fn set_timer(
+        &mut self,
+        milliseconds: u16,
+        label: Option<ir::Identifier<'a>>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        (self.timer, self.input_ctx.timer) = match label {
+            Some(label) if milliseconds > 0 => (
+                Some(clock::Timer::new(
+                    time::Duration::from_millis(milliseconds.into()),
+                    self.clock.as_ref(),
+                )),
+                Some(label),
+            ),
+            _ => (None, None),
+        };
+        Ok(())
+    }
+
+    fn set_transparent_color(
+        &mut self,
+        rgb: Option<(u16, u16, u16)>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.set_transparent_color(rgb);
+        Ok(())
+    }
+
+    fn set_wait_mode(&mut self, mode: ir::WaitMode) -> Result<(), Box<dyn std::error::Error>> {
+        self.input_ctx.any_key = matches!(mode, ir::WaitMode::AnyKey);
+        Ok(())
+    }
+
+    fn set_window(&mut self, _: ir::SetWindowOption) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn text_width(&mut self, text: &str) -> Result<u16, Box<dyn std::error::Error>> {
+        self.draw_ctx.text_width(text)
+    }
+
+    fn use_background(
+        &mut self,
+        option: ir::BackgroundTransparency,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.use_background(option, r, g, b)
+    }
+
+    fn use_brush(
+        &mut self,
+        option: ir::BrushType,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.use_brush(option, r, g, b)
+    }
+
+    fn use_caption(&mut self, _: &str) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    /// Metric coordinates assume a resolution of 96 DPI
+    fn use_coordinates(
+        &mut self,
+        option: ir::Coordinates,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.set_scale(match option {
+            ir::Coordinates::Pixel => 1.,
+            ir::Coordinates::Metric => 96. / 25.4,
+        });
+        Ok(())
+    }
+
+    fn use_font(
+        &mut self,
+        name: &str,
+        width: u16,
+        height: u16,
+        bold: ir::FontWeight,
+        italic: ir::FontSlant,
+        underline: ir::FontUnderline,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.use_font(
+            name,
+            width,
+            height,
+            bold,
+            italic,
+            underline,
+            r,
+            g,
+            b,
+            self.pedantic,
+        )
+    }
+
+    fn use_pen(
+        &mut self,
+        option: ir::PenType,
+        width: u16,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.draw_ctx.use_pen(option, width, r, g, b)
+    }
+
+    /// An indefinite wait ends the program once neither a replay nor a timer can deliver input.
+    fn wait_input(
+        &mut self,
+        milliseconds: Option<u16>,
+    ) -> Result<Option<vm::Input<'a>>, Box<dyn std::error::Error>> {
+        self.show()?;
+        if let Some(milliseconds) = milliseconds {
+            self.clock
+                .sleep(time::Duration::from_millis(milliseconds.into()));
+            return Ok(None);
+        }
+
+        self.input_ctx.clear_queue();
+        let scale = self.draw_ctx.scale;
+        loop {
+            let event_due = match &self.replay {
+                Some(replay) => match replay.delay() {
+                    Some(delay) => {
+                        let since = *self
+                            .replay_since
+                            .get_or_insert_with(|| self.clock.elapsed());
+                        Some(since + time::Duration::from_millis(delay))
+                    }
+                    None => return Ok(Some(vm::Input::End)),
+                },
+                None => None,
+            };
+            let tick_due = self.timer.as_ref().map(clock::Timer::next_tick);
+            let due = match (event_due, tick_due) {
+                (Some(event_due), Some(tick_due)) => event_due.min(tick_due),
+                (Some(due), None) | (None, Some(due)) => due,
+                (None, None) => return Ok(Some(vm::Input::End)),
+            };
+
+            let now = self.clock.elapsed();
+            self.clock.sleep(due.saturating_sub(now));
+            self.check_timer();
+            if event_due == Some(due) {
+                if let Some((_, event)) = self.replay.as_mut().and_then(replay::Replay::next) {
+                    event.push(&mut self.input_ctx.queue.borrow_mut(), scale);
+                }
+                self.replay_since = None;
+            }
+            if let Some(input) = self.input_ctx.process_queue(scale) {
+                return Ok(Some(input));
+            }
+        }
+    }
+
+    fn wait_paused(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        Ok(true)
+    }
+}
+
+/// Runs `src` with a fake clock on a `width` by `height` canvas in pixel coordinates, returning
+/// the canvas once the program ends as rows of RGBA pixels
+#[cfg(test)]
+pub fn render(src: &str, width: i32, height: i32) -> Vec<u8> {
+    render_with(src, width, height, cfg::Config::default())
+}
+
+/// Like `render`, with the other options taken from `config`
+#[cfg(test)]
+pub fn render_with(src: &str, width: i32, height: i32, config: cfg::Config) -> Vec<u8> {
+    let config = cfg::Config {
+        fake_clock: true,
+        ..config
+    };
+    let src = format!("UseCoordinates(PIXEL)\n{}\n", src);
+    let (prog, _) = ir::Program::from_src(&src, &config).unwrap();
+    let mut sys = VMSysTerminal::new(&config, Box::new(resource::EmbeddedResources)).unwrap();
+    sys.draw_ctx.resize(width, height).unwrap();
+    // The VM holds the backend until it is dropped, so the canvas is read through another handle
+    let surface = sys.draw_ctx.surface.clone();
+    vm::VM::new(&prog, &config, &mut sys).run().unwrap();
+
+    let stride = surface.stride() as usize;
+    let format = surface.format();
+    let mut rgba = Vec::with_capacity((width * height * 4) as usize);
+    surface
+        .with_data(|data| {
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    let i = y * stride + x * 4;
+                    let [b, g, r] = draw::cairo_util::pixel_bgr(format, &data[i..(i + 4)]);
+                    rgba.extend([r, g, b, 255]);
+                }
+            }
+        })
+        .unwrap();
+    rgba
+}
@@ -0,0 +1,1196 @@
+// Copyright (C) 2023  Wojciech Graj
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fmt::Display;
+use std::time;
+
+use js_sys::{Array, Atomics, Date, Int32Array, SharedArrayBuffer};
+use thiserror::Error;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::{Clamped, JsCast};
+use web_sys::{
+    CanvasPattern, DedicatedWorkerGlobalScope, ImageData, OffscreenCanvas,
+    OffscreenCanvasRenderingContext2d, Path2d,
+};
+
+use crate::cfg;
+use crate::clock;
+use crate::input;
+use crate::ir;
+use crate::vm;
+use crate::vm::VMSys;
+
+/// Size of the canvas in pixels, matching the default window size of the GTK backend
+const CANVAS_SIZE: (u32, u32) = (800, 600);
+
+/// The buffer of input events shared with the page starts with the number of events written so
+/// far, followed by a ring of `EVENT_SLOTS` events of `EVENT_LEN` integers each. An event is one
+/// of:
+/// - `EVENT_KEY`, key code, character code or 0, and 1 if Ctrl is held: key press
+/// - `EVENT_CLICK`, x, y: mouse click, in pixels of the canvas
+const EVENT_SLOTS: u32 = 64;
+const EVENT_LEN: u32 = 4;
+const EVENT_KEY: i32 = 1;
+const EVENT_CLICK: i32 = 2;
+
+/// Shortest time between frames posted while the program polls for input
+const POLL_FRAME_INTERVAL: time::Duration = time::Duration::from_millis(16);
+
+/// Parses and runs `src`, taking input from `events`, a buffer shared with the page laid out as
+/// described by `EVENT_SLOTS`. Blocks the worker it is called from until the program ends, and
+/// gives the program's exit code.
+///
+/// Meanwhile, the page is sent messages, each an array of a kind followed by its values:
+/// - `frame`, `ImageData`: the canvas, whenever it has changed and the program waits for input
+/// - `caption`, `status`, `output`, `warning`, text: from `UseCaption`, `SetStatus`, `OutputLn`,
+///   and parsing or `Run`
+/// - `message`, caption, text: from `MessageBox`, which is answered with its default button
+/// - `cursor`, CSS cursor: from `SetCursor`
+/// - `progress`, percentage: from `ProgressSet`, or without a percentage from `ProgressEnd`
+#[wasm_bindgen]
+pub fn run(src: &str, events: Int32Array) -> Result<u16, JsValue> {
+    let config = cfg::Config::default();
+    let src = format!("{}\n", src);
+    let (prog, warnings) = ir::Program::from_src(&src, &config).map_err(js_error)?;
+    let mut sys = VMSysWasm::new(&config, events).map_err(js_error)?;
+    for warning in warnings {
+        sys.post(&["warning".into(), warning.to_string().into()])
+            .map_err(js_error)?;
+    }
+    let exit_code = vm::VM::new(&prog, &config, &mut sys)
+        .run()
+        .map_err(js_error)?;
+    Ok(exit_code)
+}
+
+fn js_error(e: impl Display) -> JsValue {
+    JsValue::from_str(&e.to_string())
+}
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("JavaScript error: {}", .0)]
+    JsError(String),
+    #[error("Failed to get a 2D context of the canvas")]
+    ContextError,
+    #[error("Bitmaps cannot be loaded in the browser")]
+    BitmapUnsupportedError,
+    #[error("RestoreState without a matching SaveState")]
+    StateStackEmptyError,
+    #[error("ProgressSet without a matching ProgressStart")]
+    ProgressMissingError,
+}
+
+impl From<JsValue> for Error {
+    fn from(value: JsValue) -> Self {
+        Error::JsError(value.as_string().unwrap_or_else(|| format!("{:?}", value)))
+    }
+}
+
+/// Wall clock of the browser, which sleeps by blocking the worker on a buffer nothing wakes it
+/// through
+struct BrowserClock {
+    start: f64,
+    sleeper: Int32Array,
+}
+
+impl BrowserClock {
+    fn new() -> Self {
+        Self {
+            start: Date::now(),
+            sleeper: Int32Array::new(&SharedArrayBuffer::new(4)),
+        }
+    }
+}
+
+impl clock::Clock for BrowserClock {
+    fn elapsed(&self) -> time::Duration {
+        time::Duration::from_secs_f64(((Date::now() - self.start) / 1000.).max(0.))
+    }
+
+    fn sleep(&mut self, duration: time::Duration) {
+        Atomics::wait_with_timeout(&self.sleeper, 0, 0, duration.as_secs_f64() * 1000.).ok();
+    }
+}
+
+/// Line crossing an 8x8 hatch brush tile, giving the pixel at each position from 0 to 7 along it
+type HatchLine = fn(f64) -> (f64, f64);
+
+/// Settings saved by `SaveState`
+#[derive(Clone)]
+struct Style {
+    font_family: String,
+    font_weight: u16,
+    font_italic: bool,
+    /// Font size set by a `UseFont` call with a nonzero height
+    text_size: Option<f64>,
+    text_width: Option<f64>,
+    text_underline: ir::FontUnderline,
+    text_rgb: (u16, u16, u16),
+    pen_type: ir::PenType,
+    pen_width: f64,
+    pen_rgb: (u16, u16, u16),
+    pen_cap: ir::LineCap,
+    pen_join: ir::LineJoin,
+    background_transparency: ir::BackgroundTransparency,
+    background_rgb: (u16, u16, u16),
+    brush_type: ir::BrushType,
+    brush_rgb: (u16, u16, u16),
+    scale: f64,
+}
+
+/// Draws onto an offscreen canvas in a web worker, which is posted to the page whenever the
+/// program waits for input. Input arrives from the page through a shared buffer, on which the
+/// worker blocks while the program waits.
+pub struct VMSysWasm<'a> {
+    scope: DedicatedWorkerGlobalScope,
+    canvas: OffscreenCanvas,
+    ctx: OffscreenCanvasRenderingContext2d,
+    style: Style,
+    saved_styles: Vec<Style>,
+    /// Font size used until a `UseFont` call with a nonzero height
+    default_font_size: f64,
+    /// Whether coordinates are interpreted as signed
+    signed_coords: bool,
+    /// Largest width and height of the canvas
+    max_size: u32,
+    /// Position at which `Print` draws its next line
+    cursor: (f64, f64),
+    /// Minimum number of characters `DrawNumber` draws, padding with `number_pad`
+    number_width: usize,
+    number_pad: char,
+    /// Whether `DrawNumber` draws numbers in hexadecimal
+    number_hex: bool,
+    /// Whether the canvas has changed since it was last posted
+    dirty: bool,
+    /// Time at which the canvas was last posted
+    last_frame: Option<time::Duration>,
+    input_ctx: input::InputCtx<'a>,
+    events: Int32Array,
+    /// Number of events read from `events`, wrapping around like the count written by the page
+    events_read: u32,
+    timer: Option<clock::Timer>,
+    /// Maximum of the progress bar if one is open, and the last percentage posted
+    progress: Option<(u16, Option<u16>)>,
+    clock: BrowserClock,
+}
+
+impl<'a> VMSysWasm<'a> {
+    fn new(config: &cfg::Config, events: Int32Array) -> Result<Self, Error> {
+        let canvas = OffscreenCanvas::new(CANVAS_SIZE.0, CANVAS_SIZE.1)?;
+        let ctx = context_2d(&canvas)?;
+
+        let background_rgb = (
+            config.background_rgb.0.into(),
+            config.background_rgb.1.into(),
+            config.background_rgb.2.into(),
+        );
+        ctx.set_fill_style_str(&css_rgb(background_rgb));
+        ctx.fill_rect(0., 0., CANVAS_SIZE.0.into(), CANVAS_SIZE.1.into());
+
+        let mut sys = VMSysWasm {
+            scope: js_sys::global().unchecked_into(),
+            canvas,
+            ctx,
+            style: Style {
+                font_family: font_family(&config.font_name),
+                font_weight: 400,
+                font_italic: false,
+                text_size: None,
+                text_width: None,
+                text_underline: ir::FontUnderline::NoUnderline,
+                text_rgb: (0, 0, 0),
+                pen_type: ir::PenType::Solid,
+                pen_width: 1.,
+                pen_rgb: (0, 0, 0),
+                pen_cap: ir::LineCap::Butt,
+                pen_join: ir::LineJoin::Miter,
+                background_transparency: ir::BackgroundTransparency::Opaque,
+                background_rgb,
+                brush_type: ir::BrushType::Null,
+                brush_rgb: (0, 0, 0),
+                scale: 1.,
+            },
+            saved_styles: Vec::new(),
+            default_font_size: config.font_size,
+            signed_coords: config.signed_coords,
+            max_size: config.max_canvas_size.max(1) as u32,
+            cursor: (0., 0.),
+            number_width: 0,
+            number_pad: ' ',
+            number_hex: false,
+            dirty: true,
+            last_frame: None,
+            input_ctx: input::InputCtx::new(config.queue_capacity),
+            events,
+            events_read: 0,
+            timer: None,
+            progress: None,
+            clock: BrowserClock::new(),
+        };
+
+        sys.use_coordinates(ir::Coordinates::Metric)?;
+
+        Ok(sys)
+    }
+
+    /// Sends a message to the page, as an array of its kind followed by its values
+    fn post(&self, message: &[JsValue]) -> Result<(), Error> {
+        self.scope
+            .post_message(&message.iter().collect::<Array>())?;
+        Ok(())
+    }
+
+    /// Posts the canvas, unless it is unchanged since it was last posted
+    fn show(&mut self) -> Result<(), Error> {
+        if self.dirty {
+            let image = self.ctx.get_image_data(
+                0.,
+                0.,
+                self.canvas.width().into(),
+                self.canvas.height().into(),
+            )?;
+            self.post(&["frame".into(), image.into()])?;
+            self.dirty = false;
+        }
+        self.last_frame = Some(clock::Clock::elapsed(&self.clock));
+        Ok(())
+    }
+
+    /// Adds the events written by the page since the last call to the input queue, giving the
+    /// number of events written so far
+    fn receive_events(&mut self) -> Result<i32, Error> {
+        let written = Atomics::load(&self.events, 0)?;
+        // Events the page has written over before they were read are lost
+        if (written as u32).wrapping_sub(self.events_read) > EVENT_SLOTS {
+            self.events_read = (written as u32).wrapping_sub(EVENT_SLOTS);
+        }
+
+        let mut queue = self.input_ctx.queue.borrow_mut();
+        while self.events_read != written as u32 {
+            let start = 1 + (self.events_read % EVENT_SLOTS) * EVENT_LEN;
+            let field = |i| self.events.get_index(start + i);
+            match field(0) {
+                EVENT_KEY => {
+                    let virt = ir::VirtualKey::try_from(field(1) as u16)
+                        .ok()
+                        .map(vm::Key::Virtual);
+                    let physical = char::from_u32(field(2) as u32)
+                        .filter(|&chr| chr != '\0')
+                        .map(|chr| vm::Key::Physical(ir::PhysicalKey::new(chr, field(3) != 0)));
+                    queue.push_keyboard(virt.into_iter().chain(physical));
+                }
+                EVENT_CLICK => queue.push_mouse((f64::from(field(1)), f64::from(field(2)))),
+                _ => {}
+            }
+            self.events_read = self.events_read.wrapping_add(1);
+        }
+        Ok(written)
+    }
+
+    /// Sets the timer flag if the timer is due
+    fn check_timer(&mut self) {
+        if let Some(timer) = &mut self.timer {
+            if timer.tick(&self.clock) {
+                self.input_ctx.queue.borrow_mut().timer = true;
+            }
+        }
+    }
+
+    fn scaled(&self, x: u16) -> f64 {
+        if self.signed_coords {
+            f64::from(x as i16) * self.style.scale
+        } else {
+            f64::from(x) * self.style.scale
+        }
+    }
+
+    /// Replaces the canvas with one of another size, keeping what was drawn on it
+    fn resize(&mut self, width: u32, height: u32) -> Result<(), Error> {
+        let canvas = OffscreenCanvas::new(width.min(self.max_size), height.min(self.max_size))?;
+        let ctx = context_2d(&canvas)?;
+        ctx.set_fill_style_str(&css_rgb(self.style.background_rgb));
+        ctx.fill_rect(0., 0., canvas.width().into(), canvas.height().into());
+        ctx.draw_image_with_offscreen_canvas(&self.canvas, 0., 0.)?;
+        self.canvas = canvas;
+        self.ctx = ctx;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Fills `path` with the brush if `fill`, then outlines it with the pen
+    fn paint(&mut self, path: &Path2d, fill: bool) -> Result<(), Error> {
+        let style = &self.style;
+        if fill && style.brush_type != ir::BrushType::Null {
+            self.set_brush(&self.ctx)?;
+            self.ctx.fill_with_path_2d(path);
+        }
+        if style.pen_type != ir::PenType::Null {
+            self.ctx.save();
+            self.ctx.set_line_width(style.pen_width);
+            self.ctx.set_line_cap(match style.pen_cap {
+                ir::LineCap::Butt => "butt",
+                ir::LineCap::Round => "round",
+                ir::LineCap::Square => "square",
+            });
+            self.ctx.set_line_join(match style.pen_join {
+                ir::LineJoin::Miter => "miter",
+                ir::LineJoin::Round => "round",
+                ir::LineJoin::Bevel => "bevel",
+            });
+            // Pixel coordinates lie on the boundaries between pixels, so an odd pen width would
+            // be centered on one and blur across two rows, unless shifted to the middle of a pixel
+            if style.scale == 1. && style.pen_width % 2. == 1. {
+                self.ctx.translate(0.5, 0.5)?;
+            }
+
+            let dashes: &[f64] = match style.pen_type {
+                ir::PenType::Solid | ir::PenType::Null => &[],
+                ir::PenType::Dash => &[24., 8.],
+                ir::PenType::Dot => &[4.],
+                ir::PenType::DashDot => &[12., 6., 3., 6.],
+                ir::PenType::DashDotDot => &[12., 3., 3., 3., 3., 3.],
+            };
+            // The gaps between dashes are filled with the background color in opaque mode
+            if !dashes.is_empty()
+                && style.background_transparency == ir::BackgroundTransparency::Opaque
+            {
+                self.ctx
+                    .set_stroke_style_str(&css_rgb(style.background_rgb));
+                self.ctx.stroke_with_path(path);
+            }
+            // Dashes grow with the pen, so thick dashed lines keep their proportions
+            let dash_scale = style.pen_width.max(1.);
+            let dashes: Array = dashes
+                .iter()
+                .map(|len| JsValue::from_f64(len * dash_scale))
+                .collect();
+            self.ctx.set_line_dash(&dashes)?;
+            self.ctx.set_stroke_style_str(&css_rgb(style.pen_rgb));
+            self.ctx.stroke_with_path(path);
+            self.ctx.restore();
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Sets the fill style of `ctx` to the brush
+    fn set_brush(&self, ctx: &OffscreenCanvasRenderingContext2d) -> Result<(), Error> {
+        let lines: &[HatchLine] = match self.style.brush_type {
+            ir::BrushType::Solid | ir::BrushType::Null => {
+                ctx.set_fill_style_str(&css_rgb(self.style.brush_rgb));
+                return Ok(());
+            }
+            ir::BrushType::DiagonalUp => &[|i| (i, 7. - i)],
+            ir::BrushType::DiagonalDown => &[|i| (i, i)],
+            ir::BrushType::DiagonalCross => &[|i| (i, 7. - i), |i| (i, i)],
+            ir::BrushType::Horizontal => &[|i| (i, 0.)],
+            ir::BrushType::Vertical => &[|i| (0., i)],
+            ir::BrushType::Cross => &[|i| (i, 0.), |i| (0., i)],
+        };
+        ctx.set_fill_style_canvas_pattern(&self.hatch_pattern(ctx, lines)?);
+        Ok(())
+    }
+
+    /// Creates a pattern of 8x8 tiles crossed by `lines`. The gaps between lines are the
+    /// background color in opaque mode, and show the underlying pixels in transparent mode.
+    fn hatch_pattern(
+        &self,
+        ctx: &OffscreenCanvasRenderingContext2d,
+        lines: &[HatchLine],
+    ) -> Result<CanvasPattern, Error> {
+        let tile = OffscreenCanvas::new(8, 8)?;
+        let tile_ctx = context_2d(&tile)?;
+        if self.style.background_transparency == ir::BackgroundTransparency::Opaque {
+            tile_ctx.set_fill_style_str(&css_rgb(self.style.background_rgb));
+            tile_ctx.fill_rect(0., 0., 8., 8.);
+        }
+        tile_ctx.set_fill_style_str(&css_rgb(self.style.brush_rgb));
+        for line in lines {
+            for i in 0..8 {
+                let (x, y) = line(i.into());
+                tile_ctx.fill_rect(x, y, 1., 1.);
+            }
+        }
+        ctx.create_pattern_with_offscreen_canvas(&tile, "repeat")?
+            .ok_or(Error::ContextError)
+    }
+
+    /// Path along the ellipse bounded by a rectangle, counterclockwise from the point where the
+    /// ray from its center through (x3, y3) crosses it to the one through (x4, y4)
+    fn arc_path(
+        &self,
+        (x1, y1, x2, y2): (u16, u16, u16, u16),
+        (x3, y3, x4, y4): (u16, u16, u16, u16),
+    ) -> Result<Path2d, Error> {
+        let (x1, y1, x2, y2) = (
+            self.scaled(x1),
+            self.scaled(y1),
+            self.scaled(x2),
+            self.scaled(y2),
+        );
+        let (x3, y3, x4, y4) = (
+            self.scaled(x3),
+            self.scaled(y3),
+            self.scaled(x4),
+            self.scaled(y4),
+        );
+        let sclx = (x2 - x1).abs() / 2.;
+        let scly = (y2 - y1).abs() / 2.;
+        let cx = (x2 + x1) / 2.;
+        let cy = (y2 + y1) / 2.;
+        // A zero-size rectangle degenerates the ellipse into a line or point, along which the
+        // angles are measured without scaling, as dividing by zero would make them NaN
+        let nonzero = |scl: f64| if scl == 0. { 1. } else { scl };
+        let theta1 = ((y3 - cy) / nonzero(scly)).atan2((x3 - cx) / nonzero(sclx));
+        let theta2 = ((y4 - cy) / nonzero(scly)).atan2((x4 - cx) / nonzero(sclx));
+        // Equal angles make a full turn rather than nothing
+        let theta2 = if theta1 == theta2 {
+            theta1 - 2. * PI
+        } else {
+            theta2
+        };
+
+        let path = Path2d::new()?;
+        path.ellipse_with_anticlockwise(cx, cy, sclx, scly, 0., theta1, theta2, true)?;
+        Ok(path)
+    }
+
+    fn set_font(&self, ctx: &OffscreenCanvasRenderingContext2d, size: f64) {
+        ctx.set_font(&format!(
+            "{}{} {}px {}",
+            if self.style.font_italic {
+                "italic "
+            } else {
+                ""
+            },
+            self.style.font_weight,
+            size,
+            self.style.font_family
+        ));
+    }
+
+    /// Sets the font of the canvas to the current font, giving its ascent and descent
+    fn font_extents(&self) -> Result<(f64, f64), Error> {
+        self.set_font(
+            &self.ctx,
+            self.style.text_size.unwrap_or(self.default_font_size),
+        );
+        let metrics = self.ctx.measure_text("")?;
+        Ok((
+            metrics.font_bounding_box_ascent(),
+            metrics.font_bounding_box_descent(),
+        ))
+    }
+
+    /// Computes the font size at which the current font has a height of `height`
+    fn font_size_for_height(&self, height: f64) -> Result<f64, Error> {
+        const SIZE: f64 = 100.;
+        self.set_font(&self.ctx, SIZE);
+        let metrics = self.ctx.measure_text("")?;
+        let extent = metrics.font_bounding_box_ascent() + metrics.font_bounding_box_descent();
+        Ok(if extent > 0. {
+            height * SIZE / extent
+        } else {
+            height
+        })
+    }
+
+    fn measure_text(&self, text: &str) -> Result<f64, Error> {
+        Ok(if let Some(width) = self.style.text_width {
+            width * (text.chars().count() as f64)
+        } else {
+            self.font_extents()?;
+            self.ctx.measure_text(text)?.width()
+        })
+    }
+
+    /// Draws text with its top left corner at (x, y)
+    fn show_text(&mut self, x: f64, y: f64, text: &str) -> Result<(), Error> {
+        let width = self.measure_text(text)?;
+        let (ascent, descent) = self.font_extents()?;
+        let baseline = y + ascent;
+
+        if self.style.background_transparency == ir::BackgroundTransparency::Opaque {
+            self.ctx
+                .set_fill_style_str(&css_rgb(self.style.background_rgb));
+            self.ctx.fill_rect(x, y, width, ascent + descent);
+        }
+
+        self.ctx.set_fill_style_str(&css_rgb(self.style.text_rgb));
+        if let ir::FontUnderline::Underline = self.style.text_underline {
+            self.ctx
+                .fill_rect(x, baseline + descent / 2., width, self.style.scale.max(1.));
+        }
+
+        if let Some(char_width) = self.style.text_width {
+            for (i, c) in text.chars().enumerate() {
+                let c = c.to_string();
+                let natural_width = self.ctx.measure_text(&c)?.width();
+                if natural_width > 0. {
+                    self.ctx.save();
+                    self.ctx.translate(x + char_width * i as f64, baseline)?;
+                    self.ctx.scale(char_width / natural_width, 1.)?;
+                    self.ctx.fill_text(&c, 0., 0.)?;
+                    self.ctx.restore();
+                }
+            }
+        } else {
+            self.ctx.fill_text(text, x, baseline)?;
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Splits text into lines no wider than `width` at spaces. Words wider than `width` are kept
+    /// whole on their own line.
+    fn wrap_text(&self, text: &str, width: f64) -> Result<Vec<String>, Error> {
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for word in text.split(' ') {
+            let candidate = if line.is_empty() {
+                word.to_string()
+            } else {
+                format!("{} {}", line, word)
+            };
+            if line.is_empty() || self.measure_text(&candidate)? <= width {
+                line = candidate;
+            } else {
+                lines.push(line);
+                line = word.to_string();
+            }
+        }
+        lines.push(line);
+        Ok(lines)
+    }
+
+    fn format_number(&self, n: u16) -> String {
+        let digits = if self.number_hex {
+            format!("{:X}", n)
+        } else {
+            n.to_string()
+        };
+        let mut text = String::new();
+        for _ in digits.len()..self.number_width {
+            text.push(self.number_pad);
+        }
+        text + &digits
+    }
+}
+
+fn context_2d(canvas: &OffscreenCanvas) -> Result<OffscreenCanvasRenderingContext2d, Error> {
+    Ok(canvas
+        .get_context("2d")?
+        .ok_or(Error::ContextError)?
+        .unchecked_into())
+}
+
+fn css_rgb((r, g, b): (u16, u16, u16)) -> String {
+    format!("rgb({},{},{})", r, g, b)
+}
+
+/// Maps fonts bundled with Windows 3 to generic CSS families, falling back to sans-serif for
+/// fonts the browser lacks
+fn font_family(name: &str) -> String {
+    match name.to_lowercase().as_str() {
+        "ms sans serif" | "system" | "helv" | "helvetica" | "arial" | "small fonts" | "sans" => {
+            "sans-serif".to_string()
+        }
+        "ms serif" | "tms rmn" | "times new roman" | "roman" => "serif".to_string(),
+        "courier" | "courier new" | "terminal" | "fixedsys" | "modern" => "monospace".to_string(),
+        _ => format!("\"{}\", sans-serif", name.replace(['"', '\\'], "")),
+    }
+}
+
+impl<'a> vm::VMSys<'a> for VMSysWasm<'a> {
+    type Error = Error;
+
+    /// Workers cannot play sounds, so beeps are silent
+    fn beep(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn draw_arc(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Error> {
+        let path = self.arc_path((x1, y1, x2, y2), (x3, y3, x4, y4))?;
+        self.paint(&path, false)
+    }
+
+    fn draw_background(&mut self) -> Result<(), Error> {
+        self.ctx
+            .set_fill_style_str(&css_rgb(self.style.background_rgb));
+        self.ctx.fill_rect(
+            0.,
+            0.,
+            self.canvas.width().into(),
+            self.canvas.height().into(),
+        );
+        self.cursor = (0., 0.);
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Images can only be decoded asynchronously, which a blocked worker cannot wait for
+    fn draw_bitmap(&mut self, _: u16, _: u16, _: &str) -> Result<(), Error> {
+        Err(Error::BitmapUnsupportedError)
+    }
+
+    fn draw_chord(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Error> {
+        let path = self.arc_path((x1, y1, x2, y2), (x3, y3, x4, y4))?;
+        path.close_path();
+        self.paint(&path, true)
+    }
+
+    fn draw_ellipse(&mut self, x1: u16, y1: u16, x2: u16, y2: u16) -> Result<(), Error> {
+        let (x1, y1, x2, y2) = (
+            self.scaled(x1),
+            self.scaled(y1),
+            self.scaled(x2),
+            self.scaled(y2),
+        );
+        let path = Path2d::new()?;
+        path.ellipse(
+            (x2 + x1) / 2.,
+            (y2 + y1) / 2.,
+            (x2 - x1).abs() / 2.,
+            (y2 - y1).abs() / 2.,
+            0.,
+            0.,
+            2. * PI,
+        )?;
+        path.close_path();
+        self.paint(&path, true)
+    }
+
+    fn draw_flood(&mut self, x: u16, y: u16, r: u16, g: u16, b: u16) -> Result<(), Error> {
+        let (x, y) = (self.scaled(x), self.scaled(y));
+        let width = self.canvas.width() as usize;
+        let height = self.canvas.height() as usize;
+        if self.style.brush_type == ir::BrushType::Null
+            || x < 0.
+            || y < 0.
+            || x as usize >= width
+            || y as usize >= height
+        {
+            return Ok(());
+        }
+
+        let tgt = [r as u8, g as u8, b as u8];
+        let data = self
+            .ctx
+            .get_image_data(0., 0., width as f64, height as f64)?
+            .data();
+        // Opaque where the fill reaches, with every pixel taking 4 bytes like the canvas data
+        let mut mask = vec![0u8; width * height * 4];
+        let mut q = vec![(x as usize, y as usize)];
+        while let Some((x, y)) = q.pop() {
+            let i = (x + y * width) * 4;
+            if mask[i + 3] == 0 && data[i..(i + 3)] != tgt {
+                mask[i + 3] = 255;
+                if x > 0 {
+                    q.push((x - 1, y));
+                }
+                if x < width - 1 {
+                    q.push((x + 1, y));
+                }
+                if y > 0 {
+                    q.push((x, y - 1));
+                }
+                if y < height - 1 {
+                    q.push((x, y + 1));
+                }
+            }
+        }
+
+        let fill = OffscreenCanvas::new(width as u32, height as u32)?;
+        let fill_ctx = context_2d(&fill)?;
+        fill_ctx.put_image_data(
+            &ImageData::new_with_u8_clamped_array_and_sh(
+                Clamped(&mask),
+                width as u32,
+                height as u32,
+            )?,
+            0.,
+            0.,
+        )?;
+        // The brush only stays on the pixels of the mask
+        fill_ctx.set_global_composite_operation("source-in")?;
+        self.set_brush(&fill_ctx)?;
+        fill_ctx.fill_rect(0., 0., width as f64, height as f64);
+        self.ctx.draw_image_with_offscreen_canvas(&fill, 0., 0.)?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn draw_line(&mut self, x1: u16, y1: u16, x2: u16, y2: u16) -> Result<(), Error> {
+        let path = Path2d::new()?;
+        path.move_to(self.scaled(x1), self.scaled(y1));
+        path.line_to(self.scaled(x2), self.scaled(y2));
+        self.paint(&path, false)
+    }
+
+    fn draw_number(&mut self, x: u16, y: u16, n: u16) -> Result<(), Error> {
+        let text = self.format_number(n);
+        self.draw_text(x, y, &text)
+    }
+
+    fn draw_pie(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+        x4: u16,
+        y4: u16,
+    ) -> Result<(), Error> {
+        let path = self.arc_path((x1, y1, x2, y2), (x3, y3, x4, y4))?;
+        path.line_to(
+            (self.scaled(x2) + self.scaled(x1)) / 2.,
+            (self.scaled(y2) + self.scaled(y1)) / 2.,
+        );
+        path.close_path();
+        self.paint(&path, true)
+    }
+
+    fn draw_rectangle(&mut self, x1: u16, y1: u16, x2: u16, y2: u16) -> Result<(), Error> {
+        let (x1, y1, x2, y2) = (
+            self.scaled(x1),
+            self.scaled(y1),
+            self.scaled(x2),
+            self.scaled(y2),
+        );
+        let path = Path2d::new()?;
+        path.move_to(x1, y1);
+        path.line_to(x2, y1);
+        path.line_to(x2, y2);
+        path.line_to(x1, y2);
+        path.close_path();
+        self.paint(&path, true)
+    }
+
+    fn draw_round_rectangle(
+        &mut self,
+        x1: u16,
+        y1: u16,
+        x2: u16,
+        y2: u16,
+        x3: u16,
+        y3: u16,
+    ) -> Result<(), Error> {
+        let (x1, y1, x2, y2) = (
+            self.scaled(x1),
+            self.scaled(y1),
+            self.scaled(x2),
+            self.scaled(y2),
+        );
+        let (x3, y3) = (self.scaled(x3), self.scaled(y3));
+
+        let (x1, x2) = (x1.min(x2), x1.max(x2));
+        let (y1, y2) = (y1.min(y2), y1.max(y2));
+        // Corners wider or taller than the rectangle would overlap, so they meet at its middle
+        let x3 = (x3 / 2.).min((x2 - x1) / 2.);
+        let y3 = (y3 / 2.).min((y2 - y1) / 2.);
+
+        // Each corner's arc is joined to the end of the previous one by a straight edge
+        let path = Path2d::new()?;
+        for (cx, cy, theta) in [
+            (x1 + x3, y1 + y3, PI * 1.5),
+            (x1 + x3, y2 - y3, PI),
+            (x2 - x3, y2 - y3, PI * 0.5),
+            (x2 - x3, y1 + y3, 0.),
+        ] {
+            path.ellipse_with_anticlockwise(cx, cy, x3, y3, 0., theta, theta - PI * 0.5, true)?;
+        }
+        path.close_path();
+        self.paint(&path, true)
+    }
+
+    /// Images can only be decoded asynchronously, which a blocked worker cannot wait for
+    fn draw_sized_bitmap(&mut self, _: u16, _: u16, _: u16, _: u16, _: &str) -> Result<(), Error> {
+        Err(Error::BitmapUnsupportedError)
+    }
+
+    fn draw_text(&mut self, x: u16, y: u16, text: &str) -> Result<(), Error> {
+        self.show_text(self.scaled(x), self.scaled(y), text)
+    }
+
+    fn end(&mut self) -> Result<(), Error> {
+        self.show()
+    }
+
+    /// Workers have no environment, so every variable is unset
+    fn get_env(&mut self, _: &str) -> Result<String, Error> {
+        Ok(String::new())
+    }
+
+    fn get_ticks(&mut self) -> Result<u16, Error> {
+        Ok(clock::ticks(&self.clock))
+    }
+
+    fn get_time(&mut self) -> Result<(u16, u16, u16), Error> {
+        let now = Date::new_0();
+        Ok((
+            now.get_hours() as u16,
+            now.get_minutes() as u16,
+            now.get_seconds() as u16,
+        ))
+    }
+
+    fn locate(&mut self, x: u16, y: u16) -> Result<(), Error> {
+        self.cursor = (self.scaled(x), self.scaled(y));
+        Ok(())
+    }
+
+    fn message_beep(&mut self, _: ir::MessageBoxIcon) -> Result<(), Error> {
+        self.beep()
+    }
+
+    /// Posts the message, answering it with the default button
+    fn message_box(
+        &mut self,
+        _: ir::MessageBoxType,
+        default_button: u16,
+        _: ir::MessageBoxIcon,
+        text: &str,
+        caption: &str,
+    ) -> Result<u16, Error> {
+        self.show()?;
+        self.post(&["message".into(), caption.into(), text.into()])?;
+        Ok(default_button)
+    }
+
+    fn on_click(&mut self, callbacks: Option<&'a ir::MouseCallbacks<'a>>) -> Result<(), Error> {
+        self.input_ctx.click = callbacks;
+        Ok(())
+    }
+
+    fn output_ln(&mut self, text: &str) -> Result<(), Error> {
+        self.post(&["output".into(), text.into()])
+    }
+
+    fn poll_input(&mut self) -> Result<Option<vm::Input<'a>>, Error> {
+        let now = clock::Clock::elapsed(&self.clock);
+        if self
+            .last_frame
+            .is_none_or(|last_frame| now >= last_frame + POLL_FRAME_INTERVAL)
+        {
+            self.show()?;
+        }
+        self.receive_events()?;
+        self.check_timer();
+        Ok(self.input_ctx.take_input(self.style.scale))
+    }
+
+    fn print(&mut self, text: &str) -> Result<(), Error> {
+        let (ascent, descent) = self.font_extents()?;
+        let wrap_width = f64::from(self.canvas.width()) - self.cursor.0;
+        for line in self.wrap_text(text, wrap_width)? {
+            let (x, y) = self.cursor;
+            self.show_text(x, y, &line)?;
+            self.cursor.1 += ascent + descent;
+        }
+        Ok(())
+    }
+
+    fn progress_end(&mut self) -> Result<(), Error> {
+        self.progress = None;
+        self.post(&["progress".into()])
+    }
+
+    /// Posts the percentage whenever it changes
+    fn progress_set(&mut self, value: u16) -> Result<(), Error> {
+        let (max, last_percent) = self.progress.as_mut().ok_or(Error::ProgressMissingError)?;
+        let percent = if *max == 0 {
+            100
+        } else {
+            (u32::from(value.min(*max)) * 100 / u32::from(*max)) as u16
+        };
+        if *last_percent != Some(percent) {
+            *last_percent = Some(percent);
+            self.post(&["progress".into(), percent.into()])?;
+        }
+        Ok(())
+    }
+
+    fn progress_start(&mut self, max: u16) -> Result<(), Error> {
+        self.progress = Some((max, None));
+        self.progress_set(0)
+    }
+
+    fn restore_state(&mut self) -> Result<(), Error> {
+        self.style = self.saved_styles.pop().ok_or(Error::StateStackEmptyError)?;
+        Ok(())
+    }
+
+    /// Commands are never run, as a browser has nothing to run them with
+    fn run(&mut self, command: &str) -> Result<(), Error> {
+        self.post(&[
+            "warning".into(),
+            format!("Not running '{}' in the browser", command).into(),
+        ])
+    }
+
+    fn save_state(&mut self) -> Result<(), Error> {
+        self.saved_styles.push(self.style.clone());
+        Ok(())
+    }
+
+    fn set_canvas_size(&mut self, width: u16, height: u16) -> Result<(), Error> {
+        if width == 0 || height == 0 {
+            self.resize(CANVAS_SIZE.0, CANVAS_SIZE.1)
+        } else {
+            let width = self.scaled(width).round() as u32;
+            let height = self.scaled(height).round() as u32;
+            self.resize(width, height)
+        }
+    }
+
+    fn set_cursor(&mut self, shape: ir::CursorShape) -> Result<(), Error> {
+        let cursor = match shape {
+            ir::CursorShape::Arrow => "default",
+            ir::CursorShape::Cross => "crosshair",
+            ir::CursorShape::Hand => "pointer",
+            ir::CursorShape::Null => "none",
+        };
+        self.post(&["cursor".into(), cursor.into()])
+    }
+
+    fn set_keyboard(&mut self, params: HashMap<vm::Key, ir::Identifier<'a>>) -> Result<(), Error> {
+        self.input_ctx.keyboard = params;
+        Ok(())
+    }
+
+    /// The page has no menu bar, so menus are ignored
+    fn set_menu(&mut self, _: &[ir::MenuCategory<'a>]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_menu_check(&mut self, _: ir::Identifier<'a>, _: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_menu_enabled(&mut self, _: ir::Identifier<'a>, _: bool) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_mouse(&mut self, regions: &[vm::MouseRegion<'a>]) -> Result<(), Error> {
+        self.input_ctx.mouse = regions
+            .iter()
+            .map(|region| {
+                input::MouseRegion::new(
+                    (self.scaled(region.x1), self.scaled(region.y1)),
+                    (self.scaled(region.x2), self.scaled(region.y2)),
+                    region.callbacks,
+                )
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn set_number_base(&mut self, hex: bool) -> Result<(), Error> {
+        self.number_hex = hex;
+        Ok(())
+    }
+
+    fn set_number_format(&mut self, width: u16, pad: char) -> Result<(), Error> {
+        self.number_width = width.into();
+        self.number_pad = pad;
+        Ok(())
+    }
+
+    fn set_pen_style(&mut self, cap: ir::LineCap, join: ir::LineJoin) -> Result<(), Error> {
+        self.style.pen_cap = cap;
+        self.style.pen_join = join;
+        Ok(())
+    }
+
+    fn set_status(&mut self, text: &str) -> Result<(), Error> {
+        self.post(&["status".into(), text.into()])
+    }
+
+    fn set_timer(
+        &mut self,
+        milliseconds: u16,
+        label: Option<ir::Identifier<'a>>,
+    ) -> Result<(), Error> {
+        (self.timer, self.input_ctx.timer) = match label {
+            Some(label) if milliseconds > 0 => (
+                Some(clock::Timer::new(
+                    time::Duration::from_millis(milliseconds.into()),
+                    &self.clock,
+                )),
+                Some(label),
+            ),
+            _ => (None, None),
+        };
+        Ok(())
+    }
+
+    /// Only bitmaps have a transparent color, and they cannot be drawn
+    fn set_transparent_color(&mut self, _: Option<(u16, u16, u16)>) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn set_wait_mode(&mut self, mode: ir::WaitMode) -> Result<(), Error> {
+        self.input_ctx.any_key = matches!(mode, ir::WaitMode::AnyKey);
+        Ok(())
+    }
+
+    fn set_window(&mut self, _: ir::SetWindowOption) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn text_width(&mut self, text: &str) -> Result<u16, Error> {
+        Ok((self.measure_text(text)? / self.style.scale).round() as u16)
+    }
+
+    fn use_background(
+        &mut self,
+        option: ir::BackgroundTransparency,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Error> {
+        self.style.background_transparency = option;
+        self.style.background_rgb = (r, g, b);
+        Ok(())
+    }
+
+    fn use_brush(&mut self, option: ir::BrushType, r: u16, g: u16, b: u16) -> Result<(), Error> {
+        self.style.brush_type = option;
+        self.style.brush_rgb = (r, g, b);
+        Ok(())
+    }
+
+    fn use_caption(&mut self, text: &str) -> Result<(), Error> {
+        self.post(&["caption".into(), text.into()])
+    }
+
+    /// Metric coordinates assume a resolution of 96 DPI
+    fn use_coordinates(&mut self, option: ir::Coordinates) -> Result<(), Error> {
+        self.style.scale = match option {
+            ir::Coordinates::Pixel => 1.,
+            ir::Coordinates::Metric => 96. / 25.4,
+        };
+        Ok(())
+    }
+
+    fn use_font(
+        &mut self,
+        name: &str,
+        width: u16,
+        height: u16,
+        bold: ir::FontWeight,
+        italic: ir::FontSlant,
+        underline: ir::FontUnderline,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Error> {
+        self.style.font_family = font_family(name);
+        self.style.font_weight = match bold {
+            ir::FontWeight::Bold => 700,
+            ir::FontWeight::NoBold => 400,
+            ir::FontWeight::Weight(weight) => weight,
+        };
+        self.style.font_italic = matches!(italic, ir::FontSlant::Italic);
+        self.style.text_underline = underline;
+        self.style.text_rgb = (r, g, b);
+        self.style.text_width = if width == 0 {
+            None
+        } else {
+            Some(self.scaled(width))
+        };
+        self.style.text_size = if height == 0 {
+            None
+        } else {
+            Some(self.font_size_for_height(self.scaled(height))?)
+        };
+        Ok(())
+    }
+
+    fn use_pen(
+        &mut self,
+        option: ir::PenType,
+        width: u16,
+        r: u16,
+        g: u16,
+        b: u16,
+    ) -> Result<(), Error> {
+        self.style.pen_type = option;
+        // Pen width follows the coordinate system, like the coordinates themselves
+        self.style.pen_width = f64::from(width) * self.style.scale;
+        self.style.pen_rgb = (r, g, b);
+        Ok(())
+    }
+
+    /// An indefinite wait blocks until the page sends input handled by the program, or the timer
+    /// ticks.
+    fn wait_input(&mut self, milliseconds: Option<u16>) -> Result<Option<vm::Input<'a>>, Error> {
+        self.show()?;
+        if let Some(milliseconds) = milliseconds {
+            clock::Clock::sleep(
+                &mut self.clock,
+                time::Duration::from_millis(milliseconds.into()),
+            );
+            return Ok(None);
+        }
+
+        self.receive_events()?;
+        self.input_ctx.clear_queue();
+        let scale = self.style.scale;
+        loop {
+            let written = self.receive_events()?;
+            self.check_timer();
+            if let Some(input) = self.input_ctx.process_queue(scale) {
+                return Ok(Some(input));
+            }
+            let timeout = match &self.timer {
+                Some(timer) => {
+                    let now = clock::Clock::elapsed(&self.clock);
+                    timer.next_tick().saturating_sub(now).as_secs_f64() * 1000.
+                }
+                None => f64::INFINITY,
+            };
+            // Wakes once the page writes another event, or the timer is due
+            Atomics::wait_with_timeout(&self.events, 0, written, timeout)?;
+        }
+    }
+
+    fn wait_paused(&mut self) -> Result<bool, Error> {
+        Ok(true)
+    }
+}
@@ -17,7 +17,7 @@ use thiserror::Error;
 use crate::{cfg, ir};
 
 impl ir::LogicalOperator {
-    fn cmp(&self, i1: u16, i2: u16) -> bool {
+    pub fn cmp(&self, i1: u16, i2: u16) -> bool {
         match self {
             ir::LogicalOperator::Equal => i1 == i2,
             ir::LogicalOperator::Less => i1 < i2,
@@ -30,13 +30,21 @@ impl ir::LogicalOperator {
 }
 
 impl ir::MathOperator {
-    fn eval(&self, i1: u16, i2: u16) -> Option<u16> {
-        (match self {
-            ir::MathOperator::Add => u16::checked_add,
-            ir::MathOperator::Subtract => u16::checked_sub,
-            ir::MathOperator::Multiply => u16::checked_mul,
-            ir::MathOperator::Divide => u16::checked_div,
-        })(i1, i2)
+    /// `signed` compares operands of `Max` and `Min` as signed 16-bit integers
+    fn eval(&self, i1: u16, i2: u16, signed: bool) -> Option<u16> {
+        let less = if signed {
+            (i1 as i16) < (i2 as i16)
+        } else {
+            i1 < i2
+        };
+        match self {
+            ir::MathOperator::Add => i1.checked_add(i2),
+            ir::MathOperator::Subtract => i1.checked_sub(i2),
+            ir::MathOperator::Multiply => i1.checked_mul(i2),
+            ir::MathOperator::Divide => i1.checked_div(i2),
+            ir::MathOperator::Max => Some(if less { i2 } else { i1 }),
+            ir::MathOperator::Min => Some(if less { i1 } else { i2 }),
+        }
     }
 }
 
@@ -53,6 +61,7 @@ pub struct MouseRegion<'a> {
     pub x2: u16,
     pub y2: u16,
     pub callbacks: &'a ir::MouseCallbacks<'a>,
+    pub cursor: Option<ir::CursorShape>,
 }
 
 pub enum Input<'a> {
@@ -63,10 +72,17 @@ pub enum Input<'a> {
         x: u16,
         y: u16,
     },
+    Timer(ir::Identifier<'a>),
+    /// Continues with the next command
+    Continue,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub trait VMSys<'a> {
-    fn beep(&mut self) -> Result<(), Box<dyn std::error::Error>>;
+    /// Error returned by the system, reported by the VM as a `SystemError`
+    type Error: Into<Box<dyn std::error::Error>>;
+
+    fn beep(&mut self) -> Result<(), Self::Error>;
     fn draw_arc(
         &mut self,
         x1: u16,
@@ -77,14 +93,9 @@ pub trait VMSys<'a> {
         y3: u16,
         x4: u16,
         y4: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_background(&mut self) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_bitmap(
-        &mut self,
-        x: u16,
-        y: u16,
-        filename: &str,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
+    fn draw_background(&mut self) -> Result<(), Self::Error>;
+    fn draw_bitmap(&mut self, x: u16, y: u16, filename: &str) -> Result<(), Self::Error>;
     fn draw_chord(
         &mut self,
         x1: u16,
@@ -95,30 +106,11 @@ pub trait VMSys<'a> {
         y3: u16,
         x4: u16,
         y4: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_ellipse(
-        &mut self,
-        x1: u16,
-        y1: u16,
-        x2: u16,
-        y2: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_flood(
-        &mut self,
-        x: u16,
-        y: u16,
-        r: u16,
-        g: u16,
-        b: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_line(
-        &mut self,
-        x1: u16,
-        y1: u16,
-        x2: u16,
-        y2: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_number(&mut self, x: u16, y: u16, n: u16) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
+    fn draw_ellipse(&mut self, x1: u16, y1: u16, x2: u16, y2: u16) -> Result<(), Self::Error>;
+    fn draw_flood(&mut self, x: u16, y: u16, r: u16, g: u16, b: u16) -> Result<(), Self::Error>;
+    fn draw_line(&mut self, x1: u16, y1: u16, x2: u16, y2: u16) -> Result<(), Self::Error>;
+    fn draw_number(&mut self, x: u16, y: u16, n: u16) -> Result<(), Self::Error>;
     fn draw_pie(
         &mut self,
         x1: u16,
@@ -129,14 +121,8 @@ pub trait VMSys<'a> {
         y3: u16,
         x4: u16,
         y4: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_rectangle(
-        &mut self,
-        x1: u16,
-        y1: u16,
-        x2: u16,
-        y2: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
+    fn draw_rectangle(&mut self, x1: u16, y1: u16, x2: u16, y2: u16) -> Result<(), Self::Error>;
     fn draw_round_rectangle(
         &mut self,
         x1: u16,
@@ -145,7 +131,7 @@ pub trait VMSys<'a> {
         y2: u16,
         x3: u16,
         y3: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
     fn draw_sized_bitmap(
         &mut self,
         x1: u16,
@@ -153,8 +139,18 @@ pub trait VMSys<'a> {
         x2: u16,
         y2: u16,
         filename: &str,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn draw_text(&mut self, x: u16, y: u16, text: &str) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
+    fn draw_text(&mut self, x: u16, y: u16, text: &str) -> Result<(), Self::Error>;
+    /// Called once the program has ended
+    fn end(&mut self) -> Result<(), Self::Error>;
+    /// Value of the environment variable `name`, or an empty string if it is unset
+    fn get_env(&mut self, name: &str) -> Result<String, Self::Error>;
+    /// Milliseconds since the program started, wrapping around every 65536
+    fn get_ticks(&mut self) -> Result<u16, Self::Error>;
+    /// Local time as the hour, minute, and second
+    fn get_time(&mut self) -> Result<(u16, u16, u16), Self::Error>;
+    fn locate(&mut self, x: u16, y: u16) -> Result<(), Self::Error>;
+    fn message_beep(&mut self, icon: ir::MessageBoxIcon) -> Result<(), Self::Error>;
     fn message_box(
         &mut self,
         typ: ir::MessageBoxType,
@@ -162,37 +158,74 @@ pub trait VMSys<'a> {
         icon: ir::MessageBoxIcon,
         text: &str,
         caption: &str,
-    ) -> Result<u16, Box<dyn std::error::Error>>;
-    fn run(&mut self, command: &str) -> Result<(), Box<dyn std::error::Error>>;
-    fn set_keyboard(
+    ) -> Result<u16, Self::Error>;
+    /// Sets the handler of clicks outside every mouse region, or removes it if `None`
+    fn on_click(
         &mut self,
-        params: HashMap<Key, ir::Identifier<'a>>,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn set_menu(&mut self, menu: &[ir::MenuCategory<'a>])
-        -> Result<(), Box<dyn std::error::Error>>;
-    fn set_mouse(&mut self, regions: &[MouseRegion<'a>]) -> Result<(), Box<dyn std::error::Error>>;
-    fn set_wait_mode(&mut self, mode: ir::WaitMode) -> Result<(), Box<dyn std::error::Error>>;
-    fn set_window(&mut self, option: ir::SetWindowOption)
-        -> Result<(), Box<dyn std::error::Error>>;
+        callbacks: Option<&'a ir::MouseCallbacks<'a>>,
+    ) -> Result<(), Self::Error>;
+    /// Appends `text` as a line to the output pane
+    fn output_ln(&mut self, text: &str) -> Result<(), Self::Error>;
+    fn poll_input(&mut self) -> Result<Option<Input<'a>>, Self::Error>;
+    fn print(&mut self, text: &str) -> Result<(), Self::Error>;
+    /// Closes the progress bar
+    fn progress_end(&mut self) -> Result<(), Self::Error>;
+    /// Fills the progress bar to `value` out of the maximum passed to `progress_start`
+    fn progress_set(&mut self, value: u16) -> Result<(), Self::Error>;
+    /// Opens a progress bar, replacing any previous one
+    fn progress_start(&mut self, max: u16) -> Result<(), Self::Error>;
+    fn restore_state(&mut self) -> Result<(), Self::Error>;
+    fn run(&mut self, command: &str) -> Result<(), Self::Error>;
+    fn save_state(&mut self) -> Result<(), Self::Error>;
+    /// Sets the size of the canvas, or makes it follow the window's size if either dimension is 0
+    fn set_canvas_size(&mut self, width: u16, height: u16) -> Result<(), Self::Error>;
+    /// Sets the shape of the mouse cursor over the canvas
+    fn set_cursor(&mut self, shape: ir::CursorShape) -> Result<(), Self::Error>;
+    fn set_keyboard(&mut self, params: HashMap<Key, ir::Identifier<'a>>)
+        -> Result<(), Self::Error>;
+    fn set_menu(&mut self, menu: &[ir::MenuCategory<'a>]) -> Result<(), Self::Error>;
+    /// Checks or unchecks the checkable menu items jumping to `label`
+    fn set_menu_check(&mut self, label: ir::Identifier<'a>, on: bool) -> Result<(), Self::Error>;
+    /// Enables or grays out the menu items jumping to `label`
+    fn set_menu_enabled(
+        &mut self,
+        label: ir::Identifier<'a>,
+        enabled: bool,
+    ) -> Result<(), Self::Error>;
+    fn set_mouse(&mut self, regions: &[MouseRegion<'a>]) -> Result<(), Self::Error>;
+    /// Draws numbers in `draw_number` in hexadecimal if `hex`, otherwise in decimal
+    fn set_number_base(&mut self, hex: bool) -> Result<(), Self::Error>;
+    /// Pads numbers drawn by `draw_number` to at least `width` characters with `pad`
+    fn set_number_format(&mut self, width: u16, pad: char) -> Result<(), Self::Error>;
+    fn set_pen_style(&mut self, cap: ir::LineCap, join: ir::LineJoin) -> Result<(), Self::Error>;
+    /// Shows `text` in the status bar, or hides it if `text` is empty
+    fn set_status(&mut self, text: &str) -> Result<(), Self::Error>;
+    fn set_timer(
+        &mut self,
+        milliseconds: u16,
+        label: Option<ir::Identifier<'a>>,
+    ) -> Result<(), Self::Error>;
+    /// Makes pixels of the color `rgb` transparent in subsequently drawn bitmaps, or none if `None`
+    fn set_transparent_color(&mut self, rgb: Option<(u16, u16, u16)>) -> Result<(), Self::Error>;
+    fn set_wait_mode(&mut self, mode: ir::WaitMode) -> Result<(), Self::Error>;
+    fn set_window(&mut self, option: ir::SetWindowOption) -> Result<(), Self::Error>;
+    fn text_width(&mut self, text: &str) -> Result<u16, Self::Error>;
     fn use_background(
         &mut self,
         option: ir::BackgroundTransparency,
         r: u16,
         g: u16,
         b: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
     fn use_brush(
         &mut self,
         option: ir::BrushType,
         r: u16,
         g: u16,
         b: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn use_caption(&mut self, text: &str) -> Result<(), Box<dyn std::error::Error>>;
-    fn use_coordinates(
-        &mut self,
-        option: ir::Coordinates,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
+    fn use_caption(&mut self, text: &str) -> Result<(), Self::Error>;
+    fn use_coordinates(&mut self, option: ir::Coordinates) -> Result<(), Self::Error>;
     fn use_font(
         &mut self,
         name: &str,
@@ -204,7 +237,7 @@ pub trait VMSys<'a> {
         r: u16,
         g: u16,
         b: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
     fn use_pen(
         &mut self,
         option: ir::PenType,
@@ -212,11 +245,10 @@ pub trait VMSys<'a> {
         r: u16,
         g: u16,
         b: u16,
-    ) -> Result<(), Box<dyn std::error::Error>>;
-    fn wait_input(
-        &mut self,
-        milliseconds: Option<u16>,
-    ) -> Result<Option<Input<'a>>, Box<dyn std::error::Error>>;
+    ) -> Result<(), Self::Error>;
+    fn wait_input(&mut self, milliseconds: Option<u16>) -> Result<Option<Input<'a>>, Self::Error>;
+    /// Blocks while execution is paused, returning `false` if the program should end
+    fn wait_paused(&mut self) -> Result<bool, Self::Error>;
 }
 
 #[allow(clippy::enum_variant_names)]
@@ -232,8 +264,26 @@ pub enum Error {
     NonexistentLabelError,
     #[error("Number of integer variables exceeds 500")]
     ExcessVariablesError,
+    #[error("Number of string variables exceeds 200")]
+    ExcessStringsError,
+    #[error("Color component exceeds 255")]
+    ColorRangeError,
+    #[error("'{}' is not a number between 0 and 65535", .0)]
+    NumberFormatError(String),
+    #[error("Number base {} is not 10 or 16", .0)]
+    NumberBaseError(u16),
+    #[error("{}:{}: Variable '{}' is used before being set", .0, .1, .2)]
+    UninitializedVariableError(usize, usize, String),
+    #[error("{}:{}: Failed to draw bitmap '{}': {}", .0, .1, .2, .3)]
+    BitmapError(usize, usize, String, Box<dyn std::error::Error>),
     #[error("System Error: {}", .0)]
-    SystemError(#[from] Box<dyn std::error::Error>),
+    SystemError(Box<dyn std::error::Error>),
+}
+
+macro_rules! sys_call {
+    ($e:expr) => {
+        ($e).map_err(|e| Error::SystemError(e.into()))?
+    };
 }
 
 macro_rules! incr_ip {
@@ -249,45 +299,206 @@ macro_rules! get_integers {
     };
 }
 
-pub struct VM<'a> {
+macro_rules! get_colors {
+    ($self:ident, $( $name:ident ),*) => {
+        $(let $name = $self.get_color($name)?;)*
+    };
+}
+
+/// Value of an argument, resolved from a literal or variable
+#[derive(Debug, Clone)]
+pub enum Value {
+    Integer(u16),
+    Str(String),
+}
+
+/// Command executed by the VM, as passed to the step hook
+pub struct Step<'a, 'b> {
+    pub ip: usize,
+    pub line: usize,
+    pub col: usize,
+    pub command: &'a ir::Command<'a>,
+    /// Values of the arguments read by the command, in the order it read them
+    pub args: &'b [Value],
+}
+
+type StepHook<'a> = Box<dyn FnMut(&Step<'a, '_>) + 'a>;
+
+pub struct VM<'a, S: VMSys<'a>> {
     program: &'a ir::Program<'a>,
     config: &'a cfg::Config,
     ip: usize,
     vars: HashMap<ir::Identifier<'a>, u16>,
+    vars_str: HashMap<ir::Identifier<'a>, String>,
     call_stack: Vec<usize>,
-    ctx: &'a mut dyn VMSys<'a>,
+    exit_code: u16,
+    ctx: &'a mut S,
+    step_hook: Option<StepHook<'a>>,
+    /// Arguments read by the current command, collected only if there is a step hook
+    args: Vec<Value>,
 }
 
-impl<'a> VM<'a> {
-    pub fn new(
-        program: &'a ir::Program<'a>,
-        config: &'a cfg::Config,
-        ctx: &'a mut dyn VMSys<'a>,
-    ) -> Self {
+impl<'a, S: VMSys<'a>> VM<'a, S> {
+    pub fn new(program: &'a ir::Program<'a>, config: &'a cfg::Config, ctx: &'a mut S) -> Self {
         VM {
             program,
             config,
             ip: 0,
             vars: HashMap::new(),
+            vars_str: HashMap::new(),
             call_stack: Vec::new(),
+            exit_code: 0,
             ctx,
+            step_hook: None,
+            args: Vec::new(),
         }
     }
 
+    /// Calls `hook` after each command is executed
+    pub fn set_step_hook(&mut self, hook: impl FnMut(&Step<'a, '_>) + 'a) {
+        self.step_hook = Some(Box::new(hook));
+    }
+
     fn get_integer(&mut self, i: ir::Integer<'a>) -> Result<u16, Error> {
-        Ok(match i {
+        let val = match i {
             ir::Integer::Literal(val) => val,
             ir::Integer::Variable(ident) => {
                 if let Some(&val) = self.vars.get(&ident) {
                     val
+                } else if self.config.pedantic {
+                    let (line, col) = self.program.locations[self.ip];
+                    return Err(Error::UninitializedVariableError(
+                        line,
+                        col,
+                        ident.0.to_string(),
+                    ));
                 } else {
                     self.set_variable(ident, 0)?;
                     0
                 }
             }
+        };
+        if self.step_hook.is_some() {
+            self.args.push(Value::Integer(val));
+        }
+        Ok(val)
+    }
+
+    fn get_str(&mut self, s: ir::Str<'a>) -> Result<String, Error> {
+        let val = match s {
+            ir::Str::Literal(val) => val.to_string(),
+            ir::Str::Variable(ident) => {
+                if let Some(val) = self.vars_str.get(&ident) {
+                    val.clone()
+                } else if self.config.pedantic {
+                    let (line, col) = self.program.locations[self.ip];
+                    return Err(Error::UninitializedVariableError(
+                        line,
+                        col,
+                        ident.0.to_string(),
+                    ));
+                } else {
+                    self.set_str_variable(ident, String::new())?;
+                    String::new()
+                }
+            }
+        };
+        if self.step_hook.is_some() {
+            self.args.push(Value::Str(val.clone()));
+        }
+        Ok(val)
+    }
+
+    /// Replaces `{name}` placeholders in `text` with the value of the variable `name` if
+    /// interpolation is enabled, where `{{` and `}}` stand for literal braces
+    fn interpolate(&self, text: String) -> Result<String, Error> {
+        if !self.config.interpolate {
+            return Ok(text);
+        }
+        let mut result = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+        while let Some(i) = rest.find(['{', '}']) {
+            result.push_str(&rest[..i]);
+            let tail = &rest[i..];
+            if tail.starts_with("{{") || tail.starts_with("}}") {
+                result.push_str(&tail[..1]);
+                rest = &tail[2..];
+            } else if let Some((name, after)) =
+                tail.strip_prefix('{').and_then(|tail| tail.split_once('}'))
+            {
+                result.push_str(&self.placeholder_value(name)?);
+                rest = after;
+            } else {
+                // Unmatched braces are kept as they are
+                result.push_str(&tail[..1]);
+                rest = &tail[1..];
+            }
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    fn placeholder_value(&self, name: &str) -> Result<String, Error> {
+        let val = if name.ends_with('$') {
+            self.vars_str.get(&ir::Identifier(name)).cloned()
+        } else {
+            self.vars.get(&ir::Identifier(name)).map(u16::to_string)
+        };
+        match val {
+            Some(val) => Ok(val),
+            None if self.config.pedantic => {
+                let (line, col) = self.program.locations[self.ip];
+                Err(Error::UninitializedVariableError(
+                    line,
+                    col,
+                    name.to_string(),
+                ))
+            }
+            None if name.ends_with('$') => Ok(String::new()),
+            None => Ok("0".to_string()),
+        }
+    }
+
+    fn get_value(&mut self, val: &ir::SetValue<'a>) -> Result<u16, Error> {
+        Ok(match val {
+            ir::SetValue::Value(i) => self.get_integer(*i)?,
+            ir::SetValue::Expression { i1, op, i2 } => {
+                let (i1, i2) = (self.get_value(i1)?, self.get_value(i2)?);
+                op.eval(i1, i2, self.config.signed_coords)
+                    .ok_or_else(|| Error::MathOperationError)?
+            }
+            ir::SetValue::Abs(val) => {
+                let val = self.get_value(val)?;
+                if self.config.signed_coords {
+                    (val as i16)
+                        .checked_abs()
+                        .ok_or_else(|| Error::MathOperationError)? as u16
+                } else {
+                    val
+                }
+            }
+        })
+    }
+
+    /// Attaches the location and filename to an error of a bitmap command
+    fn bitmap_result(&self, result: Result<(), S::Error>, filename: String) -> Result<(), Error> {
+        result.map_err(|e| {
+            let (line, col) = self.program.locations[self.ip];
+            Error::BitmapError(line, col, filename, e.into())
         })
     }
 
+    fn get_color(&mut self, i: ir::Integer<'a>) -> Result<u16, Error> {
+        let val = self.get_integer(i)?;
+        if val <= 255 {
+            Ok(val)
+        } else if self.config.pedantic {
+            Err(Error::ColorRangeError)
+        } else {
+            Ok(255)
+        }
+    }
+
     fn set_variable(&mut self, ident: ir::Identifier<'a>, val: u16) -> Result<(), Error> {
         if self.config.pedantic && self.vars.len() >= 500 {
             Err(Error::ExcessVariablesError)
@@ -297,11 +508,21 @@ impl<'a> VM<'a> {
         }
     }
 
+    fn set_str_variable(&mut self, ident: ir::Identifier<'a>, val: String) -> Result<(), Error> {
+        if self.config.pedantic && self.vars_str.len() >= 200 && !self.vars_str.contains_key(&ident)
+        {
+            Err(Error::ExcessStringsError)
+        } else {
+            self.vars_str.insert(ident, val);
+            Ok(())
+        }
+    }
+
     fn goto_label(&mut self, label: ir::Identifier<'_>) -> Result<(), Error> {
         self.ip = *(self
             .program
             .labels
-            .get(&label)
+            .get(&label.0.to_lowercase())
             .ok_or_else(|| Error::NonexistentLabelError)?);
         Ok(())
     }
@@ -309,7 +530,10 @@ impl<'a> VM<'a> {
     pub fn step(&mut self) -> Result<bool, Error> {
         let cmd = &self.program.commands[self.ip];
         match *cmd {
-            ir::Command::Beep => incr_ip!(self, self.ctx.beep()?),
+            ir::Command::Beep => incr_ip!(self, sys_call!(self.ctx.beep())),
+            ir::Command::ClearTransparentColor => {
+                incr_ip!(self, sys_call!(self.ctx.set_transparent_color(None)))
+            }
             ir::Command::DrawArc {
                 x1,
                 y1,
@@ -321,13 +545,15 @@ impl<'a> VM<'a> {
                 y4,
             } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2, x3, y3, x4, y4);
-                self.ctx.draw_arc(x1, y1, x2, y2, x3, y3, x4, y4)?
+                sys_call!(self.ctx.draw_arc(x1, y1, x2, y2, x3, y3, x4, y4))
             }),
-            ir::Command::DrawBackground => incr_ip!(self, self.ctx.draw_background()?),
+            ir::Command::DrawBackground => incr_ip!(self, sys_call!(self.ctx.draw_background())),
             ir::Command::DrawBitmap { x, y, filename } => incr_ip!(self, {
                 let x = self.get_integer(x)?;
                 let y = self.get_integer(y)?;
-                self.ctx.draw_bitmap(x, y, filename)?
+                let filename = self.get_str(filename)?;
+                let result = self.ctx.draw_bitmap(x, y, &filename);
+                self.bitmap_result(result, filename)?
             }),
             ir::Command::DrawChord {
                 x1,
@@ -340,23 +566,24 @@ impl<'a> VM<'a> {
                 y4,
             } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2, x3, y3, x4, y4);
-                self.ctx.draw_chord(x1, y1, x2, y2, x3, y3, x4, y4)?
+                sys_call!(self.ctx.draw_chord(x1, y1, x2, y2, x3, y3, x4, y4))
             }),
             ir::Command::DrawEllipse { x1, y1, x2, y2 } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2);
-                self.ctx.draw_ellipse(x1, y1, x2, y2)?
+                sys_call!(self.ctx.draw_ellipse(x1, y1, x2, y2))
             }),
             ir::Command::DrawFlood { x, y, r, g, b } => incr_ip!(self, {
-                get_integers!(self, x, y, r, g, b);
-                self.ctx.draw_flood(x, y, r, g, b)?
+                get_integers!(self, x, y);
+                get_colors!(self, r, g, b);
+                sys_call!(self.ctx.draw_flood(x, y, r, g, b))
             }),
             ir::Command::DrawLine { x1, y1, x2, y2 } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2);
-                self.ctx.draw_line(x1, y1, x2, y2)?
+                sys_call!(self.ctx.draw_line(x1, y1, x2, y2))
             }),
             ir::Command::DrawNumber { x, y, n } => incr_ip!(self, {
                 get_integers!(self, x, y, n);
-                self.ctx.draw_number(x, y, n)?
+                sys_call!(self.ctx.draw_number(x, y, n))
             }),
             ir::Command::DrawPie {
                 x1,
@@ -369,11 +596,11 @@ impl<'a> VM<'a> {
                 y4,
             } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2, x3, y3, x4, y4);
-                self.ctx.draw_pie(x1, y1, x2, y2, x3, y3, x4, y4)?
+                sys_call!(self.ctx.draw_pie(x1, y1, x2, y2, x3, y3, x4, y4))
             }),
             ir::Command::DrawRectangle { x1, y1, x2, y2 } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2);
-                self.ctx.draw_rectangle(x1, y1, x2, y2)?
+                sys_call!(self.ctx.draw_rectangle(x1, y1, x2, y2))
             }),
             ir::Command::DrawRoundRectangle {
                 x1,
@@ -384,7 +611,7 @@ impl<'a> VM<'a> {
                 y3,
             } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2, x3, y3);
-                self.ctx.draw_round_rectangle(x1, y1, x2, y2, x3, y3)?
+                sys_call!(self.ctx.draw_round_rectangle(x1, y1, x2, y2, x3, y3))
             }),
             ir::Command::DrawSizedBitmap {
                 x1,
@@ -394,13 +621,40 @@ impl<'a> VM<'a> {
                 filename,
             } => incr_ip!(self, {
                 get_integers!(self, x1, y1, x2, y2);
-                self.ctx.draw_sized_bitmap(x1, y1, x2, y2, filename)?
+                let filename = self.get_str(filename)?;
+                let result = self.ctx.draw_sized_bitmap(x1, y1, x2, y2, &filename);
+                self.bitmap_result(result, filename)?
             }),
             ir::Command::DrawText { x, y, text } => incr_ip!(self, {
                 get_integers!(self, x, y);
-                self.ctx.draw_text(x, y, text)?
+                let text = self.get_str(text)?;
+                let text = self.interpolate(text)?;
+                sys_call!(self.ctx.draw_text(x, y, &text))
             }),
             ir::Command::End => return Ok(false),
+            ir::Command::Exit { code } => {
+                self.exit_code = self.get_integer(code)?;
+                return Ok(false);
+            }
+            ir::Command::GetEnv { name, result } => incr_ip!(self, {
+                let name = self.get_str(name)?;
+                let value = sys_call!(self.ctx.get_env(&name));
+                self.set_str_variable(result, value)?
+            }),
+            ir::Command::GetTicks { result } => incr_ip!(self, {
+                let ticks = sys_call!(self.ctx.get_ticks());
+                self.set_variable(result, ticks)?
+            }),
+            ir::Command::GetTime {
+                hour,
+                minute,
+                second,
+            } => incr_ip!(self, {
+                let (hour_val, minute_val, second_val) = sys_call!(self.ctx.get_time());
+                self.set_variable(hour, hour_val)?;
+                self.set_variable(minute, minute_val)?;
+                self.set_variable(second, second_val)?
+            }),
             ir::Command::Gosub(ident) => {
                 self.call_stack.push(self.ip + 1);
                 self.goto_label(ident)?
@@ -413,17 +667,24 @@ impl<'a> VM<'a> {
             }
             ir::Command::Goto(ident) => self.goto_label(ident)?,
             ir::Command::If {
-                i1,
+                ref i1,
                 op,
-                i2,
+                ref i2,
                 goto_false,
             } => {
-                self.ip = if op.cmp(self.get_integer(i1)?, self.get_integer(i2)?) {
+                self.ip = if op.cmp(self.get_value(i1)?, self.get_value(i2)?) {
                     self.ip + 1
                 } else {
                     goto_false
                 }
             }
+            ir::Command::Locate { x, y } => incr_ip!(self, {
+                get_integers!(self, x, y);
+                sys_call!(self.ctx.locate(x, y))
+            }),
+            ir::Command::MessageBeep(icon) => {
+                incr_ip!(self, sys_call!(self.ctx.message_beep(icon)))
+            }
             ir::Command::MessageBox {
                 typ,
                 default_button,
@@ -433,21 +694,54 @@ impl<'a> VM<'a> {
                 button_pushed,
             } => {
                 get_integers!(self, default_button);
+                let text = self.get_str(text)?;
+                let text = self.interpolate(text)?;
+                let caption = self.get_str(caption)?;
                 let button_pushed_val =
-                    self.ctx
-                        .message_box(typ, default_button, icon, text, caption)?;
+                    sys_call!(self
+                        .ctx
+                        .message_box(typ, default_button, icon, &text, &caption));
                 incr_ip!(self, self.set_variable(button_pushed, button_pushed_val)?);
             }
-            ir::Command::Run(command) => incr_ip!(self, self.ctx.run(command)?),
-            ir::Command::Set { var, val } => incr_ip!(self, {
-                let ident = match val {
-                    ir::SetValue::Value(i) => self.get_integer(i)?,
-                    ir::SetValue::Expression { i1, op, i2 } => op
-                        .eval(self.get_integer(i1)?, self.get_integer(i2)?)
-                        .ok_or_else(|| Error::MathOperationError)?,
-                };
-                self.set_variable(var, ident)?
+            ir::Command::OnClick(ref callbacks) => {
+                incr_ip!(self, sys_call!(self.ctx.on_click(callbacks.as_ref())))
+            }
+            ir::Command::OutputLn(text) => incr_ip!(self, {
+                let text = self.get_str(text)?;
+                sys_call!(self.ctx.output_ln(&text))
+            }),
+            ir::Command::PollInput => {
+                let input = sys_call!(self.ctx.poll_input());
+                return self.dispatch_input(input);
+            }
+            ir::Command::Print(text) => incr_ip!(self, {
+                let text = self.get_str(text)?;
+                sys_call!(self.ctx.print(&text))
+            }),
+            ir::Command::ProgressEnd => incr_ip!(self, sys_call!(self.ctx.progress_end())),
+            ir::Command::ProgressSet { value } => incr_ip!(self, {
+                get_integers!(self, value);
+                sys_call!(self.ctx.progress_set(value))
+            }),
+            ir::Command::ProgressStart { max } => incr_ip!(self, {
+                get_integers!(self, max);
+                sys_call!(self.ctx.progress_start(max))
+            }),
+            ir::Command::RestoreState => incr_ip!(self, sys_call!(self.ctx.restore_state())),
+            ir::Command::Run(command) => incr_ip!(self, {
+                let command = self.get_str(command)?;
+                sys_call!(self.ctx.run(&command))
+            }),
+            ir::Command::SaveState => incr_ip!(self, sys_call!(self.ctx.save_state())),
+            ir::Command::Set { var, ref val } => incr_ip!(self, {
+                let val = self.get_value(val)?;
+                self.set_variable(var, val)?
             }),
+            ir::Command::SetCanvasSize { width, height } => incr_ip!(self, {
+                get_integers!(self, width, height);
+                sys_call!(self.ctx.set_canvas_size(width, height))
+            }),
+            ir::Command::SetCursor(shape) => incr_ip!(self, sys_call!(self.ctx.set_cursor(shape))),
             ir::Command::SetKeyboard(ref hashmap) => incr_ip!(self, {
                 let params = hashmap
                     .iter()
@@ -466,9 +760,17 @@ impl<'a> VM<'a> {
                         ))
                     })
                     .collect::<Result<HashMap<_, _>, Error>>()?;
-                self.ctx.set_keyboard(params)?
+                sys_call!(self.ctx.set_keyboard(params))
+            }),
+            ir::Command::SetMenu(ref menu) => incr_ip!(self, sys_call!(self.ctx.set_menu(menu))),
+            ir::Command::SetMenuCheck { label, on } => incr_ip!(self, {
+                get_integers!(self, on);
+                sys_call!(self.ctx.set_menu_check(label, on != 0))
+            }),
+            ir::Command::SetMenuEnabled { label, enabled } => incr_ip!(self, {
+                get_integers!(self, enabled);
+                sys_call!(self.ctx.set_menu_enabled(label, enabled != 0))
             }),
-            ir::Command::SetMenu(ref menu) => incr_ip!(self, self.ctx.set_menu(menu)?),
             ir::Command::SetMouse(ref params) => incr_ip!(self, {
                 let params = &params
                     .iter()
@@ -479,24 +781,92 @@ impl<'a> VM<'a> {
                             x2: self.get_integer(param.x2)?,
                             y2: self.get_integer(param.y2)?,
                             callbacks: &param.callbacks,
+                            cursor: param.cursor,
                         })
                     })
                     .collect::<Result<Vec<_>, Error>>()?;
-                self.ctx.set_mouse(params)?
+                sys_call!(self.ctx.set_mouse(params))
+            }),
+            ir::Command::SetNumberBase(base) => incr_ip!(self, {
+                get_integers!(self, base);
+                let hex = match base {
+                    10 => false,
+                    16 => true,
+                    _ => return Err(Error::NumberBaseError(base)),
+                };
+                sys_call!(self.ctx.set_number_base(hex))
+            }),
+            ir::Command::SetNumberFormat { width, pad } => incr_ip!(self, {
+                get_integers!(self, width);
+                let pad = self.get_str(pad)?.chars().next().unwrap_or(' ');
+                sys_call!(self.ctx.set_number_format(width, pad))
+            }),
+            ir::Command::SetPenStyle { cap, join } => {
+                incr_ip!(self, sys_call!(self.ctx.set_pen_style(cap, join)));
+            }
+            ir::Command::SetStatus(text) => incr_ip!(self, {
+                let text = self.get_str(text)?;
+                sys_call!(self.ctx.set_status(&text))
+            }),
+            ir::Command::SetStr { var, val } => incr_ip!(self, {
+                let val = self.get_str(val)?;
+                self.set_str_variable(var, val)?
+            }),
+            ir::Command::SetTimer {
+                milliseconds,
+                label,
+            } => incr_ip!(self, {
+                get_integers!(self, milliseconds);
+                sys_call!(self.ctx.set_timer(milliseconds, label))
+            }),
+            ir::Command::SetTransparentColor { r, g, b } => incr_ip!(self, {
+                get_colors!(self, r, g, b);
+                sys_call!(self.ctx.set_transparent_color(Some((r, g, b))))
+            }),
+            ir::Command::SetWaitMode(mode) => {
+                incr_ip!(self, sys_call!(self.ctx.set_wait_mode(mode)))
+            }
+            ir::Command::SetWindow(option) => {
+                incr_ip!(self, sys_call!(self.ctx.set_window(option)))
+            }
+            ir::Command::Str { value, result } => incr_ip!(self, {
+                let value = self.get_integer(value)?;
+                self.set_str_variable(result, value.to_string())?
+            }),
+            ir::Command::StrLen { text, result } => incr_ip!(self, {
+                let len = self.get_str(text)?.chars().count();
+                self.set_variable(result, len.try_into().unwrap_or(u16::MAX))?
+            }),
+            ir::Command::SubStr {
+                text,
+                start,
+                count,
+                result,
+            } => incr_ip!(self, {
+                get_integers!(self, start, count);
+                let text = self.get_str(text)?;
+                let substr = text.chars().skip(start.into()).take(count.into()).collect();
+                self.set_str_variable(result, substr)?
+            }),
+            ir::Command::TextWidth { text, result } => incr_ip!(self, {
+                let text = self.get_str(text)?;
+                let width = sys_call!(self.ctx.text_width(&text));
+                self.set_variable(result, width)?
             }),
-            ir::Command::SetWaitMode(mode) => incr_ip!(self, self.ctx.set_wait_mode(mode)?),
-            ir::Command::SetWindow(option) => incr_ip!(self, self.ctx.set_window(option)?),
             ir::Command::UseBackground { option, r, g, b } => incr_ip!(self, {
-                get_integers!(self, r, g, b);
-                self.ctx.use_background(option, r, g, b)?
+                get_colors!(self, r, g, b);
+                sys_call!(self.ctx.use_background(option, r, g, b))
             }),
             ir::Command::UseBrush { option, r, g, b } => incr_ip!(self, {
-                get_integers!(self, r, g, b);
-                self.ctx.use_brush(option, r, g, b)?
+                get_colors!(self, r, g, b);
+                sys_call!(self.ctx.use_brush(option, r, g, b))
+            }),
+            ir::Command::UseCaption(text) => incr_ip!(self, {
+                let text = self.get_str(text)?;
+                sys_call!(self.ctx.use_caption(&text))
             }),
-            ir::Command::UseCaption(text) => incr_ip!(self, self.ctx.use_caption(text)?),
             ir::Command::UseCoordinates(coordinates) => {
-                incr_ip!(self, self.ctx.use_coordinates(coordinates)?);
+                incr_ip!(self, sys_call!(self.ctx.use_coordinates(coordinates)));
             }
             ir::Command::UseFont {
                 name,
@@ -509,9 +879,11 @@ impl<'a> VM<'a> {
                 g,
                 b,
             } => incr_ip!(self, {
-                get_integers!(self, width, height, r, g, b);
-                self.ctx
-                    .use_font(name, width, height, bold, italic, underline, r, g, b)?
+                get_integers!(self, width, height);
+                get_colors!(self, r, g, b);
+                sys_call!(self
+                    .ctx
+                    .use_font(name, width, height, bold, italic, underline, r, g, b))
             }),
             ir::Command::UsePen {
                 option,
@@ -520,8 +892,18 @@ impl<'a> VM<'a> {
                 g,
                 b,
             } => incr_ip!(self, {
-                get_integers!(self, width, r, g, b);
-                self.ctx.use_pen(option, width, r, g, b)?
+                get_integers!(self, width);
+                get_colors!(self, r, g, b);
+                sys_call!(self.ctx.use_pen(option, width, r, g, b))
+            }),
+            ir::Command::Val { text, result } => incr_ip!(self, {
+                let text = self.get_str(text)?;
+                let val = match text.trim().parse() {
+                    Ok(val) => val,
+                    Err(_) if self.config.pedantic => return Err(Error::NumberFormatError(text)),
+                    Err(_) => 0,
+                };
+                self.set_variable(result, val)?
             }),
             ir::Command::WaitInput(milliseconds) => {
                 let milliseconds = if let Some(i) = milliseconds {
@@ -529,32 +911,706 @@ impl<'a> VM<'a> {
                 } else {
                     None
                 };
-                if let Some(input) = self.ctx.wait_input(milliseconds)? {
-                    match input {
-                        Input::End => return Ok(false),
-                        Input::Goto(label) => self.goto_label(label)?,
-                        Input::Mouse { callbacks, x, y } => {
-                            self.set_variable(callbacks.x, x)?;
-                            self.set_variable(callbacks.y, y)?;
-                            self.goto_label(callbacks.label)?;
-                        }
-                    };
-                } else {
-                    self.ip += 1;
-                }
+                let input = sys_call!(self.ctx.wait_input(milliseconds));
+                return self.dispatch_input(input);
             }
         };
         Ok(true)
     }
 
-    pub fn run(&'a mut self) -> Result<(), Error> {
+    /// Jumps to the handler of an input, or to the next command if there is none.
+    fn dispatch_input(&mut self, input: Option<Input<'a>>) -> Result<bool, Error> {
+        if let Some(input) = input {
+            match input {
+                Input::End => return Ok(false),
+                Input::Continue => self.ip += 1,
+                Input::Goto(label) | Input::Timer(label) => self.goto_label(label)?,
+                Input::Mouse { callbacks, x, y } => {
+                    self.set_variable(callbacks.x, x)?;
+                    self.set_variable(callbacks.y, y)?;
+                    self.goto_label(callbacks.label)?;
+                }
+            };
+        } else {
+            self.ip += 1;
+        }
+        Ok(true)
+    }
+
+    /// Runs the program to completion, returning its exit code
+    pub fn run(&mut self) -> Result<u16, Error> {
         loop {
+            if !sys_call!(self.ctx.wait_paused()) {
+                break;
+            }
+
+            let ip = self.ip;
             let step_result = self.step()?;
+            if let Some(hook) = &mut self.step_hook {
+                let (line, col) = self.program.locations[ip];
+                hook(&Step {
+                    ip,
+                    line,
+                    col,
+                    command: &self.program.commands[ip],
+                    args: &self.args,
+                });
+                self.args.clear();
+            }
 
             if !step_result {
                 break;
             }
         }
-        Ok(())
+        sys_call!(self.ctx.end());
+        Ok(self.exit_code)
+    }
+
+    /// String variables sorted by name
+    pub fn str_variables(&self) -> Vec<(&'a str, &str)> {
+        let mut vars: Vec<_> = self
+            .vars_str
+            .iter()
+            .map(|(ident, val)| (ident.0, val.as_str()))
+            .collect();
+        vars.sort_unstable();
+        vars
+    }
+
+    /// Integer variables sorted by name
+    pub fn variables(&self) -> Vec<(&'a str, u16)> {
+        let mut vars: Vec<_> = self
+            .vars
+            .iter()
+            .map(|(ident, &val)| (ident.0, val))
+            .collect();
+        vars.sort_unstable();
+        vars
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc, time::Duration};
+
+    use super::*;
+    use crate::clock::{self, Clock};
+
+    /// Backend without any output, recording the calls checked by tests. State checked once the
+    /// program ends is shared, as the VM holds the backend until then.
+    #[derive(Default)]
+    struct TestSys<'a> {
+        /// Colors passed to `use_pen`
+        pens: Rc<RefCell<Vec<(u16, u16, u16)>>>,
+        /// Commands passed to `run`, and captions to `use_caption`
+        commands: Rc<RefCell<Vec<String>>>,
+        captions: Rc<RefCell<Vec<String>>>,
+        clock: Rc<RefCell<clock::FakeClock>>,
+        timer: Option<(clock::Timer, ir::Identifier<'a>)>,
+    }
+
+    impl<'a> VMSys<'a> for TestSys<'a> {
+        type Error = Box<dyn std::error::Error>;
+
+        fn beep(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_arc(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_background(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        /// Bitmaps never load, as if their files were missing
+        fn draw_bitmap(&mut self, _: u16, _: u16, filename: &str) -> Result<(), Self::Error> {
+            Err(format!("Failed to open '{}'", filename).into())
+        }
+
+        fn draw_chord(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_ellipse(&mut self, _: u16, _: u16, _: u16, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_flood(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_line(&mut self, _: u16, _: u16, _: u16, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_number(&mut self, _: u16, _: u16, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_pie(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_rectangle(&mut self, _: u16, _: u16, _: u16, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_round_rectangle(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn draw_sized_bitmap(
+            &mut self,
+            _: u16,
+            _: u16,
+            _: u16,
+            _: u16,
+            filename: &str,
+        ) -> Result<(), Self::Error> {
+            Err(format!("Failed to open '{}'", filename).into())
+        }
+
+        fn draw_text(&mut self, _: u16, _: u16, _: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn get_env(&mut self, _: &str) -> Result<String, Self::Error> {
+            Ok(Default::default())
+        }
+
+        fn get_ticks(&mut self) -> Result<u16, Self::Error> {
+            Ok(clock::ticks(&*self.clock.borrow()))
+        }
+
+        fn get_time(&mut self) -> Result<(u16, u16, u16), Self::Error> {
+            Ok(Default::default())
+        }
+
+        fn locate(&mut self, _: u16, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn message_beep(&mut self, _: ir::MessageBoxIcon) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn message_box(
+            &mut self,
+            _: ir::MessageBoxType,
+            _: u16,
+            _: ir::MessageBoxIcon,
+            _: &str,
+            _: &str,
+        ) -> Result<u16, Self::Error> {
+            Ok(Default::default())
+        }
+
+        fn on_click(&mut self, _: Option<&'a ir::MouseCallbacks<'a>>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn output_ln(&mut self, _: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn poll_input(&mut self) -> Result<Option<Input<'a>>, Self::Error> {
+            Ok(match &mut self.timer {
+                Some((timer, label)) => timer
+                    .tick(&*self.clock.borrow())
+                    .then_some(Input::Timer(*label)),
+                None => None,
+            })
+        }
+
+        fn print(&mut self, _: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn progress_end(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn progress_set(&mut self, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn progress_start(&mut self, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn restore_state(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn run(&mut self, command: &str) -> Result<(), Self::Error> {
+            self.commands.borrow_mut().push(command.to_string());
+            Ok(())
+        }
+
+        fn save_state(&mut self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_canvas_size(&mut self, _: u16, _: u16) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_cursor(&mut self, _: ir::CursorShape) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_keyboard(&mut self, _: HashMap<Key, ir::Identifier<'a>>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_menu(&mut self, _: &[ir::MenuCategory<'a>]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_menu_check(&mut self, _: ir::Identifier<'a>, _: bool) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_menu_enabled(&mut self, _: ir::Identifier<'a>, _: bool) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_mouse(&mut self, _: &[MouseRegion<'a>]) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_number_base(&mut self, _: bool) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_number_format(&mut self, _: u16, _: char) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_pen_style(&mut self, _: ir::LineCap, _: ir::LineJoin) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_status(&mut self, _: &str) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_timer(
+            &mut self,
+            milliseconds: u16,
+            label: Option<ir::Identifier<'a>>,
+        ) -> Result<(), Self::Error> {
+            self.timer = match label {
+                Some(label) if milliseconds > 0 => {
+                    let interval = Duration::from_millis(milliseconds.into());
+                    Some((clock::Timer::new(interval, &*self.clock.borrow()), label))
+                }
+                _ => None,
+            };
+            Ok(())
+        }
+
+        fn set_transparent_color(&mut self, _: Option<(u16, u16, u16)>) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_wait_mode(&mut self, _: ir::WaitMode) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn set_window(&mut self, _: ir::SetWindowOption) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn text_width(&mut self, _: &str) -> Result<u16, Self::Error> {
+            Ok(Default::default())
+        }
+
+        fn use_background(
+            &mut self,
+            _: ir::BackgroundTransparency,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn use_brush(
+            &mut self,
+            _: ir::BrushType,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn use_caption(&mut self, text: &str) -> Result<(), Self::Error> {
+            self.captions.borrow_mut().push(text.to_string());
+            Ok(())
+        }
+
+        fn use_coordinates(&mut self, _: ir::Coordinates) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn use_font(
+            &mut self,
+            _: &str,
+            _: u16,
+            _: u16,
+            _: ir::FontWeight,
+            _: ir::FontSlant,
+            _: ir::FontUnderline,
+            _: u16,
+            _: u16,
+            _: u16,
+        ) -> Result<(), Self::Error> {
+            Ok(())
+        }
+
+        fn use_pen(
+            &mut self,
+            _: ir::PenType,
+            _: u16,
+            r: u16,
+            g: u16,
+            b: u16,
+        ) -> Result<(), Self::Error> {
+            self.pens.borrow_mut().push((r, g, b));
+            Ok(())
+        }
+
+        fn wait_input(
+            &mut self,
+            milliseconds: Option<u16>,
+        ) -> Result<Option<Input<'a>>, Self::Error> {
+            if let Some(milliseconds) = milliseconds {
+                let duration = Duration::from_millis(milliseconds.into());
+                self.clock.borrow_mut().sleep(duration);
+            }
+            Ok(None)
+        }
+
+        fn wait_paused(&mut self) -> Result<bool, Self::Error> {
+            Ok(true)
+        }
+    }
+
+    /// Runs `src` to completion, returning its integer variables
+    fn run(src: &str, config: &cfg::Config, sys: TestSys) -> Result<HashMap<String, u16>, Error> {
+        let src = format!("{}\n", src);
+        let (prog, _) = ir::Program::from_src(&src, config).unwrap();
+        let mut sys: TestSys = sys;
+        let mut vm = VM::new(&prog, config, &mut sys);
+        vm.run()?;
+        Ok(vm
+            .variables()
+            .into_iter()
+            .map(|(name, val)| (name.to_string(), val))
+            .collect())
+    }
+
+    #[test]
+    fn color_clamped() {
+        let sys = TestSys::default();
+        let pens = sys.pens.clone();
+        run("UsePen(SOLID,1,300,255,0)", &cfg::Config::default(), sys).unwrap();
+        assert_eq!(*pens.borrow(), [(255, 255, 0)]);
+    }
+
+    #[test]
+    fn run_and_caption_take_string_variables() {
+        let sys = TestSys::default();
+        let (commands, captions) = (sys.commands.clone(), sys.captions.clone());
+        let src =
+            "Set cmd$=\"echo hi\"\nSet title$=\"Game\"\nRun(cmd$)\nUseCaption(title$)\nRun(\"ls\")";
+        run(src, &cfg::Config::default(), sys).unwrap();
+        assert_eq!(*commands.borrow(), ["echo hi", "ls"]);
+        assert_eq!(*captions.borrow(), ["Game"]);
+    }
+
+    #[test]
+    fn color_out_of_range_pedantic() {
+        let config = cfg::Config {
+            pedantic: true,
+            ..Default::default()
+        };
+        let result = run("UsePen(SOLID,1,0,256,0)", &config, TestSys::default());
+        assert!(matches!(result, Err(Error::ColorRangeError)));
+    }
+
+    #[test]
+    fn goto_mixed_case_label() {
+        let src = "Goto START\nSet x=1\nStart:\nSet y=2\nGosub Sub\nEnd\nSUB:\nSet z=3\nReturn";
+        let vars = run(src, &cfg::Config::default(), TestSys::default()).unwrap();
+        assert_eq!(vars.get("x"), None);
+        assert_eq!(vars.get("y"), Some(&2));
+        assert_eq!(vars.get("z"), Some(&3));
+    }
+
+    #[test]
+    fn while_loop() {
+        let src = "Set i=0\nSet n=0\nWhile i<5\nSet i=i+1\nSet n=n+2\nWend";
+        let vars = run(src, &cfg::Config::default(), TestSys::default()).unwrap();
+        assert_eq!(vars.get("i"), Some(&5));
+        assert_eq!(vars.get("n"), Some(&10));
+    }
+
+    #[test]
+    fn while_never_true_skips_body() {
+        let src = "Set i=0\nWhile i>0\nSet x=1\nWend\nSet y=1";
+        let vars = run(src, &cfg::Config::default(), TestSys::default()).unwrap();
+        assert_eq!(vars.get("x"), None);
+        assert_eq!(vars.get("y"), Some(&1));
+    }
+
+    /// Runs a program setting `r` to 1 if `condition` holds for the given `a` and `b`
+    fn condition_holds(condition: &str, a: u16, b: u16) -> bool {
+        let src = format!(
+            "Set a={}\nSet b={}\nSet r=0\nIf {} Then Set r=1",
+            a, b, condition
+        );
+        let vars = run(&src, &cfg::Config::default(), TestSys::default()).unwrap();
+        vars["r"] == 1
+    }
+
+    #[test]
+    fn if_and() {
+        assert!(condition_holds("a>0 And a<10", 5, 0));
+        assert!(!condition_holds("a>0 And a<10", 0, 0));
+        assert!(!condition_holds("a>0 And a<10", 10, 0));
+    }
+
+    #[test]
+    fn if_or() {
+        assert!(condition_holds("a=1 Or b=1", 1, 0));
+        assert!(condition_holds("a=1 Or b=1", 0, 1));
+        assert!(!condition_holds("a=1 Or b=1", 0, 0));
+    }
+
+    #[test]
+    fn if_and_binds_tighter_than_or() {
+        let condition = "a=1 Or a=2 And b=3";
+        assert!(condition_holds(condition, 1, 0));
+        assert!(condition_holds(condition, 2, 3));
+        assert!(!condition_holds(condition, 2, 0));
+        assert!(!condition_holds(condition, 3, 3));
+    }
+
+    #[test]
+    fn while_and_or() {
+        let src = "Set i=0\nSet j=0\nWhile i<10 And j<3 Or i=0\nSet i=i+1\nSet j=j+1\nWend";
+        let vars = run(src, &cfg::Config::default(), TestSys::default()).unwrap();
+        assert_eq!(vars.get("i"), Some(&3));
+    }
+
+    #[test]
+    fn elseif_chain_runs_one_branch() {
+        for (a, branch) in [(1, "r1"), (2, "r2"), (3, "r3"), (4, "r4")] {
+            let src = format!(
+                "Set a={}\nIf a=1 Then Set r1=1 ElseIf a=2 Then Set r2=1 ElseIf a=3 Then Set r3=1 ElseIf 0=0 Then Set r4=1\nSet after=1",
+                a
+            );
+            let vars = run(&src, &cfg::Config::default(), TestSys::default()).unwrap();
+            let ran: Vec<&str> = ["r1", "r2", "r3", "r4"]
+                .into_iter()
+                .filter(|branch| vars.contains_key(*branch))
+                .collect();
+            assert_eq!(ran, [branch]);
+            assert_eq!(vars.get("after"), Some(&1));
+        }
+    }
+
+    #[test]
+    fn bitmap_error_has_location_and_filename() {
+        for command in ["DrawBitmap(0,0,f$)", "DrawSizedBitmap(0,0,10,10,f$)"] {
+            let src = format!("Set f$=\"cars.bmp\"\nSet x=1\n  {}", command);
+            let result = run(&src, &cfg::Config::default(), TestSys::default());
+            match &result {
+                Err(Error::BitmapError(3, 3, filename, _)) => assert_eq!(filename, "cars.bmp"),
+                _ => panic!("expected BitmapError at 3:3, got {:?}", result.err()),
+            }
+            assert_eq!(
+                result.unwrap_err().to_string(),
+                "3:3: Failed to draw bitmap 'cars.bmp': Failed to open 'cars.bmp'"
+            );
+        }
+    }
+
+    /// Runs a program setting `r` to `expression`
+    fn eval(expression: &str, config: &cfg::Config) -> u16 {
+        let src = format!("Set a=5\nSet r={}", expression);
+        run(&src, config, TestSys::default()).unwrap()["r"]
+    }
+
+    #[test]
+    fn min_max_clamp() {
+        let config = cfg::Config::default();
+        assert_eq!(eval("Max(a,3)", &config), 5);
+        assert_eq!(eval("Min(a,3)", &config), 3);
+        assert_eq!(eval("Min(a+1,Max(2,9))", &config), 6);
+        assert_eq!(eval("Clamp(a,0,4)", &config), 4);
+        assert_eq!(eval("Clamp(a,6,9)", &config), 6);
+        assert_eq!(eval("Clamp(a,0,9)", &config), 5);
+    }
+
+    #[test]
+    fn min_max_abs_signed() {
+        let unsigned = cfg::Config::default();
+        let signed = cfg::Config {
+            signed_coords: true,
+            ..Default::default()
+        };
+        // -5 is stored as 65531, so it is only the smaller value when signed
+        assert_eq!(eval("Min(-5,a)", &unsigned), 5);
+        assert_eq!(eval("Min(-5,a)", &signed), (-5i16) as u16);
+        assert_eq!(eval("Max(-5,a)", &signed), 5);
+        assert_eq!(eval("Abs(-5)", &unsigned), (-5i16) as u16);
+        assert_eq!(eval("Abs(-5)", &signed), 5);
+        assert_eq!(eval("Abs(a)", &signed), 5);
+    }
+
+    #[test]
+    fn abs_overflow() {
+        let config = cfg::Config {
+            signed_coords: true,
+            ..Default::default()
+        };
+        let result = run("Set r=Abs(-32768)", &config, TestSys::default());
+        assert!(matches!(result, Err(Error::MathOperationError)));
+    }
+
+    /// Runs a FOR loop counting its passes in `n`, returning `n` and the loop variable
+    fn for_loop(range: &str) -> (u16, u16) {
+        let src = format!("Set n=0\nFor i={}\nSet n=n+1\nNext", range);
+        let vars = run(&src, &cfg::Config::default(), TestSys::default()).unwrap();
+        (vars["n"], vars["i"])
+    }
+
+    #[test]
+    fn for_loop_steps() {
+        assert_eq!(for_loop("1 To 10"), (10, 10));
+        assert_eq!(for_loop("0 To 9 Step 3"), (4, 9));
+        assert_eq!(for_loop("0 To 10 Step 3"), (4, 9));
+        assert_eq!(for_loop("0 To 1 Step 2"), (1, 0));
+        assert_eq!(for_loop("10 To 0 Step -3"), (4, 1));
+        assert_eq!(for_loop("5 To 0 Step -5"), (2, 0));
+        assert_eq!(for_loop("2 To 3"), (2, 3));
+        assert_eq!(for_loop("3 To 2"), (0, 3));
+    }
+
+    #[test]
+    fn for_loop_range_limits() {
+        assert_eq!(for_loop("65534 To 65535"), (2, 65535));
+        assert_eq!(for_loop("0 To 65535 Step 2"), (32768, 65534));
+        assert_eq!(for_loop("65535 To 65530 Step -4"), (2, 65531));
+        assert_eq!(for_loop("65535 To 0 Step -4096"), (16, 4095));
+        assert_eq!(for_loop("1 To 0 Step -2"), (1, 1));
+    }
+
+    #[test]
+    fn get_ticks_follows_clock() {
+        let src = "GetTicks(t1)\nGetTicks(t2)\nGetTicks(t3)\n";
+        let config = cfg::Config::default();
+        let (prog, _) = ir::Program::from_src(src, &config).unwrap();
+        let mut sys = TestSys::default();
+        let clock = sys.clock.clone();
+        let mut vm = VM::new(&prog, &config, &mut sys);
+
+        clock.borrow_mut().advance(Duration::from_millis(1234));
+        vm.step().unwrap();
+        clock.borrow_mut().advance(Duration::from_millis(4321));
+        vm.step().unwrap();
+        // Ticks wrap around every 65536 milliseconds
+        clock.borrow_mut().advance(Duration::from_millis(65536));
+        vm.step().unwrap();
+        assert_eq!(vm.variables(), [("t1", 1234), ("t2", 5555), ("t3", 5555)]);
+    }
+
+    #[test]
+    fn timer_ticks_on_clock() {
+        let src = "SetTimer(100,tick)\nSet n=0\nloop:\nPollInput\nSet n=n+1\nGoto loop\ntick:\nGetTicks(t)\nEnd\n";
+        let config = cfg::Config::default();
+        let (prog, _) = ir::Program::from_src(src, &config).unwrap();
+        let mut sys = TestSys::default();
+        let clock = sys.clock.clone();
+        let mut vm = VM::new(&prog, &config, &mut sys);
+
+        // The timer does not tick while the clock stands still
+        for _ in 0..32 {
+            assert!(vm.step().unwrap());
+        }
+        assert_eq!(vm.variables(), [("n", 10)]);
+
+        clock.borrow_mut().advance(Duration::from_millis(99));
+        for _ in 0..3 {
+            vm.step().unwrap();
+        }
+        assert_eq!(vm.variables(), [("n", 11)]);
+
+        clock.borrow_mut().advance(Duration::from_millis(1));
+        vm.run().unwrap();
+        assert_eq!(vm.variables(), [("n", 11), ("t", 100)]);
+    }
+
+    #[test]
+    fn timer_ticks_during_timed_waits() {
+        let src = "SetTimer(100,tick)\nSet n=0\nloop:\nWaitInput(30)\nPollInput\nSet n=n+1\nGoto loop\ntick:\nGetTicks(t)";
+        let vars = run(src, &cfg::Config::default(), TestSys::default()).unwrap();
+        assert_eq!(vars["n"], 3);
+        assert_eq!(vars["t"], 120);
     }
 }
@@ -0,0 +1,109 @@
+use std::{
+    io::Write,
+    process::{Command, Output, Stdio},
+};
+
+/// Runs the interpreter on `src`, read from stdin
+fn run(src: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_oriel"))
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(src.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn indented_label_error_keeps_stdout_clean() {
+    let output = run("  label:\nEnd\n");
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not at line start"));
+}
+
+#[test]
+fn missing_source_keeps_stdout_clean() {
+    let output = Command::new(env!("CARGO_BIN_EXE_oriel")).output().unwrap();
+    assert!(output.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Provide a source file."));
+}
+
+/// Runs `src`, read from stdin, on the terminal backend with a fake clock, feeding it the events
+/// in `replay` through a file called `name`
+#[cfg(feature = "gtk")]
+fn run_replay(name: &str, src: &str, replay: &str) -> Output {
+    let path = std::env::temp_dir().join(format!("oriel-{}-{}.txt", std::process::id(), name));
+    std::fs::write(&path, replay).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_oriel"))
+        .args(["--backend", "terminal", "--fake-clock", "--replay"])
+        .arg(&path)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(src.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+    output
+}
+
+#[cfg(feature = "gtk")]
+#[test]
+fn timer_ticks_while_replaying() {
+    let output = run_replay(
+        "timer",
+        "Set n=0\nSetTimer(100,tick)\nSetKeyboard(\"e\",end_it)\nloop:\nWaitInput()\ntick:\nSet n=n+1\nGoto loop\nend_it:\nExit(n)\n",
+        "250 char e\n",
+    );
+    // Ticks at 100ms and 200ms come before the key at 250ms
+    assert_eq!(
+        output.status.code(),
+        Some(2),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[cfg(feature = "gtk")]
+#[test]
+fn disabled_menu_item_is_ignored_until_enabled() {
+    let output = run_replay(
+        "menu",
+        "Set n=0
+SetMenu(\"&File\",IGNORE,\"&Go\",go,ENDPOPUP)
+SetKeyboard(\"e\",enable)
+SetMenuEnabled(go,0)
+loop:
+WaitInput()
+enable:
+SetMenuEnabled(go,1)
+Set n=10
+Goto loop
+go:
+Set n=n+1
+Exit(n)
+",
+        "10 menu 0\n10 char e\n10 menu 0\n",
+    );
+    assert_eq!(
+        output.status.code(),
+        Some(11),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
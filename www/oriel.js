@@ -0,0 +1,105 @@
+// Layout of the buffer of input events shared with the worker, as in src/sys_wasm.rs
+const EVENT_SLOTS = 64;
+const EVENT_LEN = 4;
+const EVENT_KEY = 1;
+const EVENT_CLICK = 2;
+
+const canvas = document.getElementById("canvas");
+const ctx = canvas.getContext("2d");
+const statusBar = document.getElementById("status");
+const progress = document.getElementById("progress");
+const output = document.getElementById("output");
+
+let worker = null;
+let events = null;
+
+// Writes an event into the next slot, then wakes the worker if it is waiting for one
+function send(...fields) {
+    if (!events) {
+        return;
+    }
+    const start = 1 + ((Atomics.load(events, 0) >>> 0) % EVENT_SLOTS) * EVENT_LEN;
+    events.fill(0, start, start + EVENT_LEN);
+    events.set(fields, start);
+    Atomics.add(events, 0, 1);
+    Atomics.notify(events, 0);
+}
+
+function print(text) {
+    output.textContent += text + "\n";
+}
+
+const handlers = {
+    frame(image) {
+        if (canvas.width !== image.width || canvas.height !== image.height) {
+            canvas.width = image.width;
+            canvas.height = image.height;
+        }
+        ctx.putImageData(image, 0, 0);
+    },
+    caption(text) {
+        document.title = text;
+    },
+    status(text) {
+        statusBar.textContent = text;
+        statusBar.hidden = text === "";
+    },
+    output: print,
+    warning(text) {
+        print(`Warning: ${text}`);
+    },
+    message(caption, text) {
+        print(`[${caption}] ${text}`);
+    },
+    cursor(cursor) {
+        canvas.style.cursor = cursor;
+    },
+    progress(percent) {
+        progress.hidden = percent === undefined;
+        progress.value = percent ?? 0;
+    },
+    exit(code) {
+        print(`Exited with code ${code}`);
+        events = null;
+    },
+    error(text) {
+        print(`Error: ${text}`);
+        events = null;
+    },
+};
+
+// Stops any running program, then runs `src` in a new worker
+function run(src) {
+    worker?.terminate();
+    output.textContent = "";
+    handlers.status("");
+    handlers.progress();
+    canvas.style.cursor = "";
+
+    events = new Int32Array(new SharedArrayBuffer(4 * (1 + EVENT_SLOTS * EVENT_LEN)));
+    worker = new Worker("worker.js");
+    worker.onmessage = ({ data: [kind, ...values] }) => handlers[kind](...values);
+    worker.postMessage({ src, events });
+    canvas.focus();
+}
+
+canvas.addEventListener("mousedown", (event) => {
+    // The canvas may be scaled by CSS, while the program sees its pixels
+    const rect = canvas.getBoundingClientRect();
+    send(
+        EVENT_CLICK,
+        Math.floor((event.clientX - rect.left) * canvas.width / rect.width),
+        Math.floor((event.clientY - rect.top) * canvas.height / rect.height),
+    );
+});
+
+// Key codes of the browser match the virtual keys of Windows for the keys Oriel knows
+canvas.addEventListener("keydown", (event) => {
+    const chr = [...event.key].length === 1 ? event.key.codePointAt(0) : 0;
+    send(EVENT_KEY, event.keyCode, chr, event.ctrlKey ? 1 : 0);
+    event.preventDefault();
+});
+
+document.getElementById("run").addEventListener("click", () => {
+    run(document.getElementById("source").value);
+});
@@ -0,0 +1,11 @@
+// Runs a program sent by the page, blocking this worker until it ends
+importScripts("pkg/oriel.js");
+
+onmessage = async ({ data: { src, events } }) => {
+    await wasm_bindgen({ module_or_path: "pkg/oriel_bg.wasm" });
+    try {
+        postMessage(["exit", wasm_bindgen.run(src, events)]);
+    } catch (e) {
+        postMessage(["error", String(e)]);
+    }
+};